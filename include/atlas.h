@@ -0,0 +1,129 @@
+/*
+ * C interface to atlas, for fingerprinting audio and matching it against a database
+ * from other programs. Link against the atlas shared library built by `cargo build
+ * --release` (target/release/libatlas.so, .dylib or .dll).
+ *
+ * Ownership: every object and array atlas hands out belongs to the caller, who frees
+ * it with the matching _free function. Passing null to a _free function does nothing.
+ * Strings inside results belong to the results and are freed with them.
+ *
+ * Errors: functions returning an AtlasStatus write their outputs only when they return
+ * ATLAS_OK. Otherwise atlas_last_error() describes what went wrong.
+ *
+ * Threads: handles may be moved between threads but must not be used by two at once.
+ */
+
+#ifndef ATLAS_H
+#define ATLAS_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef enum AtlasStatus {
+    ATLAS_OK = 0,
+    ATLAS_NULL_ARGUMENT = 1,    /* a required pointer was null */
+    ATLAS_INVALID_ARGUMENT = 2, /* parameters, sample counts or strings that can't be used */
+    ATLAS_FAILED = 3,           /* the database couldn't be opened or read */
+    ATLAS_PANIC = 4,            /* a bug in atlas. The handle should no longer be used. */
+} AtlasStatus;
+
+/* Kinds of database, as given to `atlas --storage` */
+enum {
+    ATLAS_STORAGE_SQLITE = 0,
+    ATLAS_STORAGE_PACKED = 1,
+    ATLAS_STORAGE_KV = 2,
+    ATLAS_STORAGE_INVERTED = 3,
+};
+
+/* Tracks and the recordings matched against them must be analysed with the same
+ * parameters. Start from atlas_analysis_params_default. */
+typedef struct AtlasAnalysisParams {
+    float window_length;       /* in seconds */
+    size_t kernel_size;        /* used for maximum filter */
+    float magnitude_threshold; /* used for maximum filter */
+    float target_zone_delay_sec;
+    float target_zone_height_hz;
+    float target_zone_width_sec;
+} AtlasAnalysisParams;
+
+typedef struct AtlasMatchOptions {
+    uint32_t offset_bin_width;  /* in windows */
+    uint32_t offset_neighbours; /* bins either side summed into each bin's score */
+    size_t top;                 /* number of ranked results to report */
+    double min_confidence;      /* required 1 - p-value of the best track's score */
+} AtlasMatchOptions;
+
+/* A hash of a recording and the window its first peak is in */
+typedef struct AtlasHash {
+    uint32_t hash;
+    uint32_t time;
+} AtlasHash;
+
+typedef struct AtlasMatchResult {
+    uint32_t track_id;
+    char *title;      /* NUL-terminated UTF-8, owned by the results */
+    float offset_sec; /* where the recording starts in the track, negative if the track starts within it */
+    uint32_t score;   /* number of hashes agreeing on the offset */
+    double confidence;
+    bool accepted;    /* whether this result was decided to be the match */
+} AtlasMatchResult;
+
+typedef struct AtlasFingerprinter AtlasFingerprinter;
+typedef struct AtlasMatcher AtlasMatcher;
+
+/* Describes the most recent failure on this thread, or is null if there hasn't been
+ * one. The string belongs to atlas and lasts until the next failure on this thread. */
+const char *atlas_last_error(void);
+
+AtlasStatus atlas_analysis_params_default(AtlasAnalysisParams *out);
+AtlasStatus atlas_match_options_default(AtlasMatchOptions *out);
+
+/* Creates a fingerprinter for audio with `channels` interleaved channels at
+ * `sample_rate`, of which the first is fingerprinted */
+AtlasStatus atlas_fingerprinter_new(const AtlasAnalysisParams *params, uint32_t sample_rate,
+                                    uint32_t channels, AtlasFingerprinter **out);
+void atlas_fingerprinter_free(AtlasFingerprinter *fingerprinter);
+
+/* Add `frames` frames of interleaved samples, so `frames * channels` samples. Buffers
+ * may be any length. Float samples are in [-1, 1]. */
+AtlasStatus atlas_fingerprinter_push_f32(AtlasFingerprinter *fingerprinter,
+                                         const float *samples, size_t frames);
+AtlasStatus atlas_fingerprinter_push_i16(AtlasFingerprinter *fingerprinter,
+                                         const int16_t *samples, size_t frames);
+
+/* Marks the end of the audio, so peaks in its last windows are found. No audio can be
+ * pushed afterwards. */
+AtlasStatus atlas_fingerprinter_finish(AtlasFingerprinter *fingerprinter);
+
+/* The hashes of the audio pushed so far, ordered by time then hash. Until the
+ * fingerprinter is finished, peaks in the last windows are still to be found. `*hashes`
+ * is null when there are none. Free with atlas_hashes_free. */
+AtlasStatus atlas_fingerprinter_hashes(const AtlasFingerprinter *fingerprinter,
+                                       AtlasHash **hashes, size_t *len);
+void atlas_hashes_free(AtlasHash *hashes, size_t len);
+
+/* Opens a database of one of the ATLAS_STORAGE_ kinds, whose tracks were fingerprinted
+ * with `params`, failing with ATLAS_INVALID_ARGUMENT if it records others or there is
+ * no database at `path`. `options` may be null for the defaults. */
+AtlasStatus atlas_matcher_open(const char *path, uint32_t kind,
+                               const AtlasAnalysisParams *params,
+                               const AtlasMatchOptions *options, AtlasMatcher **out);
+void atlas_matcher_free(AtlasMatcher *matcher);
+
+/* Ranks the tracks the hashes of a recording could be from, best first, as `atlas
+ * match` does. `*results` holds at most `options.top` results and is null when there
+ * are none. Free with atlas_match_results_free. */
+AtlasStatus atlas_matcher_match(AtlasMatcher *matcher, const AtlasHash *hashes, size_t len,
+                                AtlasMatchResult **results, size_t *count);
+void atlas_match_results_free(AtlasMatchResult *results, size_t count);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* ATLAS_H */
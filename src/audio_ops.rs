@@ -1,53 +1,117 @@
 // Spectrogram plotting code adapted from https://github.com/rfilmyer/plotters-spectrogram/blob/339a2e832136ef343963b334910e41c8aaa8be58/src/main.rs
-use std::path::Path;
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Seek, Write},
+    path::Path,
+};
 
 use anyhow::Context;
 use hound::WavReader;
-use ndarray::{Array, Array2, Axis};
+use ndarray::{Array2, Axis};
 use rustfft::{num_complex::Complex, FftPlanner};
 
 pub fn read_wav_to_fft(filename: &Path, window_length: f32) -> Result<Array2<f32>, anyhow::Error> {
-    println!("Reading wav file");
-    let mut wav = WavReader::open(filename).context("Could not open file for reading.")?;
+    let (samples, sample_rate) = read_wav_mono(filename)?;
+
+    samples_to_fft(&samples, sample_rate, window_length)
+}
+
+/// Reads the first channel of a 16 bit wav file as f32 for fft. Returns the samples and sample rate.
+pub fn read_wav_mono(filename: &Path) -> Result<(Vec<f32>, u32), anyhow::Error> {
+    let file = File::open(filename).context("Could not open file for reading.")?;
+    decode_wav_mono(BufReader::new(file))
+}
+
+/// Reads the first channel of 16 bit wav data, as [`read_wav_mono`] reads a file
+pub fn decode_wav_mono(reader: impl Read) -> Result<(Vec<f32>, u32), anyhow::Error> {
+    let mut wav = WavReader::new(reader).context("Could not read the data as wav.")?;
     let wav_spec = wav.spec();
     let sample_rate = wav_spec.sample_rate;
     let channels = wav_spec.channels.into();
+    if channels == 0 {
+        anyhow::bail!("File has no channels.");
+    }
     let samples = wav
         .samples()
         .step_by(channels)
-        .collect::<Result<Vec<i16>, _>>()
+        .map(|sample| sample.map(|i: i16| i as f32))
+        .collect::<Result<Vec<f32>, _>>()
         .context("Could not interpret file as 16 bit samples.")?;
 
-    let window_size: usize = (sample_rate as f32 * window_length) as usize;
+    Ok((samples, sample_rate))
+}
+
+/// Length of a wav file in seconds, read from its header
+pub fn wav_duration_sec(filename: &Path) -> Result<f64, anyhow::Error> {
+    let wav = WavReader::open(filename).context("Could not open file for reading.")?;
+    Ok(wav.duration() as f64 / wav.spec().sample_rate as f64)
+}
+
+/// Writes mono samples scaled like 16 bit samples to a 16 bit wav file, saturating any beyond that range
+pub fn write_wav_mono(
+    filename: &Path,
+    samples: &[f32],
+    sample_rate: u32,
+) -> Result<(), anyhow::Error> {
+    let file = File::create(filename).context("Could not open file for writing.")?;
+    encode_wav_mono(BufWriter::new(file), samples, sample_rate)
+}
+
+/// Writes mono samples as 16 bit wav data, as [`write_wav_mono`] writes a file
+pub fn encode_wav_mono(
+    writer: impl Write + Seek,
+    samples: &[f32],
+    sample_rate: u32,
+) -> Result<(), anyhow::Error> {
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut writer = hound::WavWriter::new(writer, spec).context("Could not write wav header.")?;
+    for &sample in samples {
+        writer.write_sample(sample.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16)?;
+    }
+    writer.finalize()?;
+
+    Ok(())
+}
+
+/// Number of samples in each fft window
+pub fn window_size(sample_rate: u32, window_length: f32) -> usize {
+    (sample_rate as f32 * window_length) as usize
+}
+
+/// Computes the spectrogram of mono samples using consecutive windows of window_length.
+/// Samples past the last whole window are ignored, so feeding whole windows at a time
+/// gives the same rows as processing all the samples at once.
+pub fn samples_to_fft(
+    samples: &[f32],
+    sample_rate: u32,
+    window_length: f32,
+) -> Result<Array2<f32>, anyhow::Error> {
+    let window_size = window_size(sample_rate, window_length);
+    if window_size == 0 {
+        anyhow::bail!("Window length is shorter than one sample.");
+    }
     const WINDOW_OVERLAP: f64 = 0.0;
     let skip_size: usize = (window_size as f64 * (1f64 - WINDOW_OVERLAP)) as usize;
 
-    println!("Creating windows {window_size} samples long from a timeline {num_samples} samples long, for a total of {num_windows} windows.",
-        window_size = window_size, num_samples = samples.len(), num_windows = (samples.len() / skip_size) - 1,
-    );
-    let channel_count = wav.spec().channels;
-    let mut channel_description = format!("{} channel", channel_count);
-    if channel_count > 1 {
-        channel_description.push_str("s");
-    }
-    println!(
-        "Sample rate is {sample_rate} Hz. Bit depth is {}. {}",
-        wav.spec().bits_per_sample,
-        channel_description,
-    );
-
-    // Convert to an ndarray. f32 for fft.
-    let samples_array = Array::from(samples.clone());
+    // Convert to an ndarray
+    let samples_array = ndarray::ArrayView1::from(samples);
     let windows = samples_array
         .windows(ndarray::Dim(window_size))
         .into_iter()
         .step_by(skip_size)
         .collect::<Vec<_>>();
+    if windows.is_empty() {
+        return Ok(Array2::zeros((0, window_size / 2 + 1)));
+    }
     let windows = ndarray::stack(Axis(0), &windows)?;
-    let mut windows = windows.map(|i| Complex::from(*i as f32));
+    let mut windows = windows.map(|i| Complex::from(*i));
 
     // Prepare fft
-    println!("Performing fft");
     let mut planner = FftPlanner::new();
     let fft = planner.plan_fft_forward(window_size);
 
@@ -66,3 +130,117 @@ pub fn read_wav_to_fft(filename: &Path, window_length: f32) -> Result<Array2<f32
 
     Ok(windows)
 }
+
+/// Encoding of audio read from a stream
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PcmFormat {
+    /// wav with a header giving the sample rate and channels
+    Wav,
+    /// raw signed 16 bit little endian samples
+    S16le,
+    /// raw 32 bit float little endian samples in [-1, 1]
+    F32le,
+}
+
+/// Reads the first channel of a stream of interleaved samples in chunks, scaled to
+/// the range of 16 bit samples like read_wav_to_fft
+pub struct PcmReader {
+    source: PcmSource,
+    channels: usize,
+    sample_rate: u32,
+}
+
+enum PcmSource {
+    Wav(WavReader<Box<dyn Read>>),
+    S16le(Box<dyn Read>),
+    F32le(Box<dyn Read>),
+}
+
+impl PcmReader {
+    /// Creates a reader. For raw formats the sample rate and channel count must be given,
+    /// for wav they are read from the header.
+    pub fn new(
+        reader: Box<dyn Read>,
+        format: PcmFormat,
+        sample_rate: u32,
+        channels: usize,
+    ) -> Result<Self, anyhow::Error> {
+        let pcm_reader = match format {
+            PcmFormat::Wav => {
+                let wav = WavReader::new(reader).context("Could not read wav header.")?;
+                let spec = wav.spec();
+                if spec.sample_format != hound::SampleFormat::Int || spec.bits_per_sample != 16 {
+                    anyhow::bail!("Only 16 bit wav streams are supported.");
+                }
+                PcmReader {
+                    channels: spec.channels.into(),
+                    sample_rate: spec.sample_rate,
+                    source: PcmSource::Wav(wav),
+                }
+            }
+            PcmFormat::S16le => PcmReader {
+                source: PcmSource::S16le(reader),
+                channels,
+                sample_rate,
+            },
+            PcmFormat::F32le => PcmReader {
+                source: PcmSource::F32le(reader),
+                channels,
+                sample_rate,
+            },
+        };
+        if pcm_reader.channels == 0 || pcm_reader.sample_rate == 0 {
+            anyhow::bail!("Sample rate and channel count must be positive.");
+        }
+
+        Ok(pcm_reader)
+    }
+
+    /// Reads a wav file in chunks
+    pub fn open_wav(path: &Path) -> Result<Self, anyhow::Error> {
+        let file = File::open(path).context("Could not open file for reading.")?;
+        Self::new(Box::new(BufReader::new(file)), PcmFormat::Wav, 0, 0)
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Reads up to `frames` frames, blocking until they arrive.
+    /// Returns fewer only at the end of the stream.
+    pub fn read_chunk(&mut self, frames: usize) -> Result<Vec<f32>, anyhow::Error> {
+        let channels = self.channels;
+        match &mut self.source {
+            PcmSource::Wav(wav) => wav
+                .samples::<i16>()
+                .take(frames * channels)
+                .step_by(channels)
+                .map(|sample| Ok(sample? as f32))
+                .collect(),
+            PcmSource::S16le(reader) => {
+                let bytes = read_up_to(reader, frames * channels * 2)?;
+                Ok(bytes
+                    .chunks_exact(2 * channels)
+                    .map(|frame| i16::from_le_bytes([frame[0], frame[1]]) as f32)
+                    .collect())
+            }
+            PcmSource::F32le(reader) => {
+                let bytes = read_up_to(reader, frames * channels * 4)?;
+                Ok(bytes
+                    .chunks_exact(4 * channels)
+                    .map(|frame| {
+                        f32::from_le_bytes([frame[0], frame[1], frame[2], frame[3]])
+                            * i16::MAX as f32
+                    })
+                    .collect())
+            }
+        }
+    }
+}
+
+/// Reads until `len` bytes have been read or the stream ends
+fn read_up_to(reader: &mut impl Read, len: usize) -> Result<Vec<u8>, anyhow::Error> {
+    let mut bytes = Vec::with_capacity(len);
+    reader.take(len as u64).read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
@@ -0,0 +1,259 @@
+//! A daemon answering requests on a Unix domain socket, for integrations on the same
+//! machine.
+//!
+//! Requests and responses are JSON objects, one per line. A request names its command
+//! and may carry an `id`, which is echoed in the response:
+//!
+//! - `{"id":1,"command":"match_file","path":"clip.wav"}` ranks the tracks a wav or
+//!   fingerprint file could be, answering with the match report of `match --format json`.
+//!   An optional `query` names the recording in the report.
+//! - `{"id":2,"command":"match_fingerprint","fingerprints":[[hash,time],...]}` does the
+//!   same for hashes made elsewhere, with times in windows as in exports
+//! - `{"id":3,"command":"add_file","path":"track.wav","title":"track"}` adds a wav or
+//!   fingerprint file as a track, replacing any with the same title. The title defaults
+//!   to that in a fingerprint file, or the file name.
+//! - `{"id":4,"command":"remove_track","title":"track"}`
+//! - `{"id":5,"command":"stats"}`
+//!
+//! and are answered with `{"id":1,"result":{...}}` or `{"id":1,"error":"..."}`.
+//! Paths are read by the daemon, so relative paths are relative to where it was started.
+//!
+//! Each connection is served by its own thread, one request at a time, through a
+//! [`Service`] as the HTTP server's requests are: matches run concurrently on a pool of
+//! readers, while adds and removals take turns with a single writer.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    hash::PairRecord,
+    service::{Service, Upload},
+    Storage,
+};
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum Command {
+    MatchFile {
+        path: PathBuf,
+        query: Option<String>,
+    },
+    MatchFingerprint {
+        fingerprints: Vec<(u32, u32)>, // (hash, time in windows)
+        query: Option<String>,
+    },
+    AddFile {
+        path: PathBuf,
+        title: Option<String>,
+    },
+    RemoveTrack {
+        title: String,
+    },
+    Stats,
+}
+
+#[derive(Serialize, Debug)]
+struct Response {
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Response {
+    fn new(id: Value, result: Result<Value, anyhow::Error>) -> Self {
+        match result {
+            Ok(result) => Response {
+                id,
+                result: Some(result),
+                error: None,
+            },
+            Err(err) => Response {
+                id,
+                result: None,
+                error: Some(format!("{:#}", err)),
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct Removed {
+    title: String,
+    removed: bool,
+}
+
+/// A daemon listening on a socket, run until stopped with a [`ShutdownHandle`]
+pub struct Daemon {
+    listener: UnixListener,
+    path: PathBuf,
+    stopping: Arc<AtomicBool>,
+}
+
+/// Stops a [`Daemon`] from another thread
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    path: PathBuf,
+    stopping: Arc<AtomicBool>,
+}
+
+impl Daemon {
+    /// Listens at `path`, replacing a socket left behind by a daemon that has stopped
+    pub fn bind(path: &Path) -> Result<Self, anyhow::Error> {
+        if path.exists() {
+            if UnixStream::connect(path).is_ok() {
+                anyhow::bail!("Another daemon is listening on {}.", path.display());
+            }
+            fs::remove_file(path)
+                .with_context(|| format!("Could not remove the old socket {}.", path.display()))?;
+        }
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("Could not listen on {}.", path.display()))?;
+        Ok(Daemon {
+            listener,
+            path: path.to_path_buf(),
+            stopping: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            path: self.path.clone(),
+            stopping: self.stopping.clone(),
+        }
+    }
+
+    /// Serves connections until shut down, returning once those open have closed.
+    /// Connections that fail, and failures to accept them, are passed to `on_error`.
+    pub fn run<S: Storage>(&self, service: &Service<S>, on_error: impl Fn(anyhow::Error) + Sync) {
+        thread::scope(|scope| {
+            for stream in self.listener.incoming() {
+                if self.stopping.load(Ordering::Acquire) {
+                    return;
+                }
+                match stream {
+                    Ok(stream) => {
+                        let on_error = &on_error;
+                        scope.spawn(move || {
+                            if let Err(err) = serve_connection(stream, service) {
+                                on_error(err.context("Connection failed."));
+                            }
+                        });
+                    }
+                    Err(err) => {
+                        on_error(anyhow::Error::from(err).context("Could not accept a connection."))
+                    }
+                }
+            }
+        });
+    }
+}
+
+impl ShutdownHandle {
+    /// Has the daemon stop taking connections. [`Daemon::run`] returns once those
+    /// already taken are closed.
+    pub fn shutdown(&self) {
+        self.stopping.store(true, Ordering::Release);
+        // wakes the daemon waiting for a connection
+        let _ = UnixStream::connect(&self.path);
+    }
+}
+
+fn serve_connection<S: Storage>(
+    stream: UnixStream,
+    service: &Service<S>,
+) -> Result<(), anyhow::Error> {
+    let lines = BufReader::new(stream.try_clone()?).lines();
+    let mut writer = stream;
+    for line in lines {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = respond(service, &line);
+        serde_json::to_writer(&mut writer, &response)?;
+        writer.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// Answers a request, echoing its id whenever it can be read
+fn respond<S: Storage>(service: &Service<S>, line: &str) -> Response {
+    let request = match serde_json::from_str::<Value>(line) {
+        Ok(request) => request,
+        Err(err) => {
+            let err = anyhow::Error::from(err).context("The request is invalid.");
+            return Response::new(Value::Null, Err(err));
+        }
+    };
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    let result = serde_json::from_value::<Command>(request)
+        .context("The request is invalid.")
+        .and_then(|command| handle(service, command));
+    Response::new(id, result)
+}
+
+fn handle<S: Storage>(service: &Service<S>, command: Command) -> Result<Value, anyhow::Error> {
+    let result = match command {
+        Command::MatchFile { path, query } => {
+            let upload = read_upload(service, &path)?;
+            let query = query.unwrap_or_else(|| path.to_string_lossy().to_string());
+            serde_json::to_value(service.match_upload(&query, &upload)?)?
+        }
+        Command::MatchFingerprint {
+            fingerprints,
+            query,
+        } => {
+            let hashes: HashMap<u32, PairRecord> = fingerprints
+                .into_iter()
+                .map(|(hash, time_a)| (hash, PairRecord { hash, time_a }))
+                .collect();
+            let upload = Upload {
+                title: None,
+                hashes,
+            };
+            let query = query.unwrap_or_else(|| "fingerprints".to_string());
+            serde_json::to_value(service.match_upload(&query, &upload)?)?
+        }
+        Command::AddFile { path, title } => {
+            let upload = read_upload(service, &path)?;
+            // the audio is recorded so matches can be explained, but fingerprint files can't be
+            let audio = upload.title.is_none().then_some(path.as_path());
+            let title = title
+                .or_else(|| upload.title.clone())
+                .or_else(|| {
+                    path.file_stem()
+                        .map(|stem| stem.to_string_lossy().to_string())
+                })
+                .context("Give the track a title.")?;
+            serde_json::to_value(service.add_upload(&title, audio, &upload)?)?
+        }
+        Command::RemoveTrack { title } => {
+            let removed = service.remove_track(&title)?;
+            serde_json::to_value(Removed { title, removed })?
+        }
+        Command::Stats => serde_json::to_value(service.stats()?)?,
+    };
+    Ok(result)
+}
+
+/// Reads a wav or fingerprint file and fingerprints it
+fn read_upload<S: Storage>(service: &Service<S>, path: &Path) -> Result<Upload, anyhow::Error> {
+    let bytes = fs::read(path).with_context(|| format!("Could not read {}.", path.display()))?;
+    Upload::parse(&bytes, service.fingerprinter())
+}
@@ -0,0 +1,444 @@
+use std::{
+    f32::consts::PI,
+    fs,
+    io::BufWriter,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use rand::Rng;
+use serde::Serialize;
+
+use crate::audio_ops;
+
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NoiseKind {
+    None,
+    White,
+    Pink,
+}
+
+/// Ranges the degradation of each clip is drawn from
+#[derive(Debug, Clone, Copy)]
+pub struct DegradationRanges {
+    pub noise: NoiseKind,
+    pub snr_db: f32,
+    pub clip_level: Option<f32>, // fraction of full scale samples are clipped to
+    pub lowpass_hz: Option<f32>, // cutoff of a lowpass filter
+    pub highpass_hz: Option<f32>, // cutoff of a highpass filter
+    pub max_gain_db: f32,        // gain is drawn from [-max_gain_db, max_gain_db]
+    pub max_speed_change: f32,   // speed is drawn from [1 - max_speed_change, 1 + max_speed_change]
+}
+
+/// Degradation applied to one clip
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Degradation {
+    pub noise: NoiseKind,
+    pub snr_db: f32,
+    pub clip_level: Option<f32>,
+    pub lowpass_hz: Option<f32>,
+    pub highpass_hz: Option<f32>,
+    pub gain_db: f32,
+    pub speed: f32, // playback rate, above 1 is faster
+}
+
+impl DegradationRanges {
+    pub fn sample(&self, rng: &mut impl Rng) -> Degradation {
+        let symmetric = |rng: &mut dyn rand::RngCore, max: f32| {
+            if max > 0. {
+                rng.gen_range(-max..=max)
+            } else {
+                0.
+            }
+        };
+        Degradation {
+            noise: self.noise,
+            snr_db: self.snr_db,
+            clip_level: self.clip_level,
+            lowpass_hz: self.lowpass_hz,
+            highpass_hz: self.highpass_hz,
+            gain_db: symmetric(rng, self.max_gain_db),
+            speed: 1. + symmetric(rng, self.max_speed_change),
+        }
+    }
+}
+
+/// Applies the degradation to samples scaled like 16 bit samples, in the order a
+/// recording would pick them up: playback speed, the channel's filtering, gain, then
+/// background noise and finally clipping at the input.
+pub fn degrade(
+    samples: &[f32],
+    sample_rate: u32,
+    degradation: &Degradation,
+    rng: &mut impl Rng,
+) -> Vec<f32> {
+    let mut samples = change_speed(samples, degradation.speed);
+    if let Some(cutoff) = degradation.highpass_hz {
+        Biquad::highpass(cutoff, sample_rate).process(&mut samples);
+    }
+    if let Some(cutoff) = degradation.lowpass_hz {
+        Biquad::lowpass(cutoff, sample_rate).process(&mut samples);
+    }
+
+    let gain = 10f32.powf(degradation.gain_db / 20.);
+    samples.iter_mut().for_each(|sample| *sample *= gain);
+
+    if degradation.noise != NoiseKind::None {
+        add_noise(&mut samples, degradation.noise, degradation.snr_db, rng);
+    }
+    if let Some(clip_level) = degradation.clip_level {
+        let limit = clip_level * i16::MAX as f32;
+        samples
+            .iter_mut()
+            .for_each(|sample| *sample = sample.clamp(-limit, limit));
+    }
+
+    samples
+}
+
+/// Resamples by linear interpolation so the audio plays `speed` times faster
+fn change_speed(samples: &[f32], speed: f32) -> Vec<f32> {
+    if (speed - 1.).abs() < f32::EPSILON || samples.len() < 2 {
+        return samples.to_vec();
+    }
+
+    let output_len = ((samples.len() - 1) as f32 / speed) as usize + 1;
+    (0..output_len)
+        .map(|i| {
+            let position = i as f32 * speed;
+            let index = position as usize;
+            let fraction = position - index as f32;
+            let next = samples[(index + 1).min(samples.len() - 1)];
+            samples[index] * (1. - fraction) + next * fraction
+        })
+        .collect()
+}
+
+/// Adds noise scaled to give the signal to noise ratio
+fn add_noise(samples: &mut [f32], kind: NoiseKind, snr_db: f32, rng: &mut impl Rng) {
+    let mut noise: Vec<f32> = (0..samples.len()).map(|_| gaussian(rng)).collect();
+    if kind == NoiseKind::Pink {
+        pink_filter(&mut noise);
+    }
+
+    let power = |values: &[f32]| {
+        values.iter().map(|value| value * value).sum::<f32>() / values.len().max(1) as f32
+    };
+    let signal_power = power(samples);
+    let noise_power = power(&noise);
+    if noise_power == 0. {
+        return;
+    }
+    let scale = (signal_power / noise_power / 10f32.powf(snr_db / 10.)).sqrt();
+    for (sample, noise) in samples.iter_mut().zip(noise) {
+        *sample += noise * scale;
+    }
+}
+
+/// Standard normal sample using the Box-Muller transform
+fn gaussian(rng: &mut impl Rng) -> f32 {
+    let uniform: f32 = 1. - rng.gen::<f32>(); // in (0, 1] so the log is finite
+    (-2. * uniform.ln()).sqrt() * (2. * PI * rng.gen::<f32>()).cos()
+}
+
+/// Turns white noise into pink noise, falling at 3dB per octave, using Paul Kellet's filter
+fn pink_filter(noise: &mut [f32]) {
+    let mut b = [0f32; 7];
+    for sample in noise.iter_mut() {
+        let white = *sample;
+        b[0] = 0.99886 * b[0] + white * 0.0555179;
+        b[1] = 0.99332 * b[1] + white * 0.0750759;
+        b[2] = 0.96900 * b[2] + white * 0.153852;
+        b[3] = 0.86650 * b[3] + white * 0.3104856;
+        b[4] = 0.55000 * b[4] + white * 0.5329522;
+        b[5] = -0.7616 * b[5] - white * 0.0168980;
+        *sample = b[..6].iter().sum::<f32>() + b[6] + white * 0.5362;
+        b[6] = white * 0.115926;
+    }
+}
+
+/// Second order filter with coefficients from the Audio EQ Cookbook
+struct Biquad {
+    b: [f32; 3],
+    a: [f32; 2],
+}
+
+impl Biquad {
+    const Q: f32 = std::f32::consts::FRAC_1_SQRT_2; // Butterworth
+
+    fn lowpass(cutoff_hz: f32, sample_rate: u32) -> Self {
+        let (cos, alpha) = Self::angle(cutoff_hz, sample_rate);
+        Self::normalised([(1. - cos) / 2., 1. - cos, (1. - cos) / 2.], cos, alpha)
+    }
+
+    fn highpass(cutoff_hz: f32, sample_rate: u32) -> Self {
+        let (cos, alpha) = Self::angle(cutoff_hz, sample_rate);
+        Self::normalised([(1. + cos) / 2., -(1. + cos), (1. + cos) / 2.], cos, alpha)
+    }
+
+    fn angle(cutoff_hz: f32, sample_rate: u32) -> (f32, f32) {
+        // keep the cutoff below nyquist so the filter stays stable
+        let cutoff_hz = cutoff_hz.clamp(1., sample_rate as f32 * 0.49);
+        let omega = 2. * PI * cutoff_hz / sample_rate as f32;
+        (omega.cos(), omega.sin() / (2. * Self::Q))
+    }
+
+    fn normalised(b: [f32; 3], cos: f32, alpha: f32) -> Self {
+        let a0 = 1. + alpha;
+        Biquad {
+            b: [b[0] / a0, b[1] / a0, b[2] / a0],
+            a: [-2. * cos / a0, (1. - alpha) / a0],
+        }
+    }
+
+    fn process(&self, samples: &mut [f32]) {
+        let (mut x1, mut x2, mut y1, mut y2) = (0., 0., 0., 0.);
+        for sample in samples.iter_mut() {
+            let x = *sample;
+            let y =
+                self.b[0] * x + self.b[1] * x1 + self.b[2] * x2 - self.a[0] * y1 - self.a[1] * y2;
+            x2 = x1;
+            x1 = x;
+            y2 = y1;
+            y1 = y;
+            *sample = y;
+        }
+    }
+}
+
+/// Label of a generated clip, in the format read by eval with the degradation appended
+#[derive(Serialize)]
+pub struct ClipLabel {
+    pub query: String, // clip file name, relative to the labels file
+    pub expected_track: String,
+    pub expected_offset: f32, // where the clip starts in the track, in seconds
+    pub noise: NoiseKind,
+    pub snr_db: f32,
+    pub clip_level: Option<f32>,
+    pub lowpass_hz: Option<f32>,
+    pub highpass_hz: Option<f32>,
+    pub gain_db: f32,
+    pub speed: f32,
+}
+
+impl ClipLabel {
+    pub fn new(
+        query: String,
+        expected_track: String,
+        expected_offset: f32,
+        degradation: &Degradation,
+    ) -> Self {
+        ClipLabel {
+            query,
+            expected_track,
+            expected_offset,
+            noise: degradation.noise,
+            snr_db: degradation.snr_db,
+            clip_level: degradation.clip_level,
+            lowpass_hz: degradation.lowpass_hz,
+            highpass_hz: degradation.highpass_hz,
+            gain_db: degradation.gain_db,
+            speed: degradation.speed,
+        }
+    }
+}
+
+/// Writes `clips_per_track` randomly placed, degraded excerpts of `clip_length` seconds
+/// of each track to the clip directory, with a labels csv that eval can read.
+/// Fails rather than overwrite clips already there. Each track is passed to `on_track`
+/// before its clips are written.
+pub fn write_clips(
+    tracks: &[PathBuf],
+    clip_dir: &Path,
+    clip_length: f32,
+    clips_per_track: usize,
+    ranges: &DegradationRanges,
+    rng: &mut impl Rng,
+    mut on_track: impl FnMut(&Path),
+) -> Result<(), anyhow::Error> {
+    fs::create_dir_all(clip_dir)?;
+    let mut labels = csv::Writer::from_writer(create_new(&clip_dir.join("labels.csv"))?);
+    for track in tracks {
+        on_track(track);
+        let title = track
+            .file_stem()
+            .context("Please provide a file not a directory.")?
+            .to_string_lossy()
+            .to_string();
+        let (samples, sample_rate) = audio_ops::read_wav_mono(track)?;
+        let clip_samples = ((clip_length * sample_rate as f32) as usize).min(samples.len());
+
+        for clip_number in 1..=clips_per_track {
+            let start = rng.gen_range(0..=samples.len() - clip_samples);
+            let offset_sec = start as f32 / sample_rate as f32;
+            let degradation = ranges.sample(rng);
+            let clip = degrade(
+                &samples[start..start + clip_samples],
+                sample_rate,
+                &degradation,
+                rng,
+            );
+
+            // named so eval can also label the clip from its name alone, and numbered as
+            // clips of short tracks all start at the same offset
+            let clip_name = format!("{}@{:.2}#{}.wav", title, offset_sec, clip_number);
+            let clip_file = create_new(&clip_dir.join(&clip_name))?;
+            audio_ops::encode_wav_mono(clip_file, &clip, sample_rate)?;
+            labels.serialize(ClipLabel::new(
+                clip_name,
+                title.clone(),
+                offset_sec,
+                &degradation,
+            ))?;
+        }
+    }
+    labels.flush()?;
+
+    Ok(())
+}
+
+/// Creates a file for writing, failing if there already is one
+fn create_new(path: &Path) -> Result<BufWriter<fs::File>, anyhow::Error> {
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .with_context(|| format!("Could not create {}, or it already exists.", path.display()))?;
+    Ok(BufWriter::new(file))
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 8000;
+
+    fn sine(frequency: f32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|n| 10000. * (2. * PI * frequency * n as f32 / SAMPLE_RATE as f32).sin())
+            .collect()
+    }
+
+    fn power(samples: &[f32]) -> f32 {
+        samples.iter().map(|sample| sample * sample).sum::<f32>() / samples.len() as f32
+    }
+
+    fn only(change: impl FnOnce(&mut Degradation)) -> Degradation {
+        let mut degradation = Degradation {
+            noise: NoiseKind::None,
+            snr_db: 0.,
+            clip_level: None,
+            lowpass_hz: None,
+            highpass_hz: None,
+            gain_db: 0.,
+            speed: 1.,
+        };
+        change(&mut degradation);
+        degradation
+    }
+
+    #[test]
+    fn noise_is_added_at_the_signal_to_noise_ratio() {
+        let clean = sine(440., SAMPLE_RATE as usize * 2);
+        for noise in [NoiseKind::White, NoiseKind::Pink] {
+            let degradation = only(|degradation| {
+                degradation.noise = noise;
+                degradation.snr_db = 10.;
+            });
+            let noisy = degrade(
+                &clean,
+                SAMPLE_RATE,
+                &degradation,
+                &mut StdRng::seed_from_u64(1),
+            );
+            let added: Vec<f32> = noisy.iter().zip(&clean).map(|(a, b)| a - b).collect();
+            let snr_db = 10. * (power(&clean) / power(&added)).log10();
+            assert!((snr_db - 10.).abs() < 0.1, "{:?} at {} dB", noise, snr_db);
+        }
+    }
+
+    #[test]
+    fn filters_attenuate_beyond_their_cutoff() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let len = SAMPLE_RATE as usize;
+        // the filters' ramp-up is left out of the comparison
+        let gain = |frequency: f32, degradation: &Degradation, rng: &mut StdRng| {
+            let clean = sine(frequency, len);
+            let filtered = degrade(&clean, SAMPLE_RATE, degradation, rng);
+            (power(&filtered[len / 2..]) / power(&clean[len / 2..])).sqrt()
+        };
+
+        let lowpass = only(|degradation| degradation.lowpass_hz = Some(500.));
+        assert!(gain(100., &lowpass, &mut rng) > 0.95);
+        assert!(gain(3000., &lowpass, &mut rng) < 0.05);
+
+        let highpass = only(|degradation| degradation.highpass_hz = Some(1000.));
+        assert!(gain(3000., &highpass, &mut rng) > 0.95);
+        assert!(gain(100., &highpass, &mut rng) < 0.05);
+    }
+
+    #[test]
+    fn speed_changes_scale_the_length() {
+        let samples = sine(440., 8001);
+        assert_eq!(change_speed(&samples, 1.).len(), 8001);
+        assert_eq!(change_speed(&samples, 2.).len(), 4001);
+        assert_eq!(change_speed(&samples, 0.5).len(), 16001);
+        assert_eq!(change_speed(&samples, 1.25).len(), 6401);
+        assert_eq!(change_speed(&[1.], 2.), vec![1.]);
+    }
+
+    #[test]
+    fn samples_are_clipped_at_the_clip_level() {
+        let clean = sine(440., 800);
+        let degradation = only(|degradation| degradation.clip_level = Some(0.25));
+        let clipped = degrade(
+            &clean,
+            SAMPLE_RATE,
+            &degradation,
+            &mut StdRng::seed_from_u64(1),
+        );
+        let limit = 0.25 * i16::MAX as f32;
+        for (clipped, clean) in clipped.iter().zip(&clean) {
+            assert_eq!(*clipped, clean.clamp(-limit, limit));
+        }
+        assert!(clipped.contains(&limit));
+    }
+
+    #[test]
+    fn clips_at_the_same_offset_get_their_own_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let tracks = vec![dir.path().join("short.wav")];
+        audio_ops::write_wav_mono(&tracks[0], &sine(440., 800), SAMPLE_RATE).unwrap();
+        let clip_dir = dir.path().join("clips");
+        let ranges = DegradationRanges {
+            noise: NoiseKind::None,
+            snr_db: 10.,
+            clip_level: None,
+            lowpass_hz: None,
+            highpass_hz: None,
+            max_gain_db: 0.,
+            max_speed_change: 0.,
+        };
+        let mut rng = StdRng::seed_from_u64(1);
+        // longer than the track, so every clip starts at 0
+        write_clips(&tracks, &clip_dir, 1., 3, &ranges, &mut rng, |_| {}).unwrap();
+
+        let labels = crate::eval::read_labels(&clip_dir.join("labels.csv")).unwrap();
+        assert_eq!(labels.len(), 3);
+        for label in &labels {
+            assert!(label.path.is_file(), "{}", label.path.display());
+            let named = crate::eval::label_from_name(&label.path);
+            assert_eq!(named.expected_track.as_deref(), Some("short"));
+            assert_eq!(named.expected_offset_sec, Some(0.));
+        }
+        assert_eq!(fs::read_dir(&clip_dir).unwrap().count(), 4);
+
+        // clips and labels already written are never overwritten
+        assert!(write_clips(&tracks, &clip_dir, 1., 1, &ranges, &mut rng, |_| {}).is_err());
+        let labels = crate::eval::read_labels(&clip_dir.join("labels.csv")).unwrap();
+        assert_eq!(labels.len(), 3);
+    }
+}
@@ -0,0 +1,333 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    input::{self, MatchedFile},
+    plots::PlotOptions,
+    report::MatchReport,
+    storage::Storage,
+    Fingerprinter, MatchOptions, Matcher,
+};
+
+/// A query with the track it should match, or none if it isn't in the database
+#[derive(Debug, Clone)]
+pub struct LabelledQuery {
+    pub path: PathBuf,
+    pub expected_track: Option<String>,
+    pub expected_offset_sec: Option<f32>, // where the query starts in the track
+}
+
+#[derive(Deserialize)]
+struct LabelRow {
+    query: PathBuf,
+    expected_track: Option<String>,
+    expected_offset: Option<f32>,
+}
+
+/// Reads labels from a csv with columns query, expected_track and expected_offset.
+/// Query paths are relative to the csv. An empty expected_track marks a query that
+/// shouldn't match anything.
+pub fn read_labels(labels_path: &Path) -> Result<Vec<LabelledQuery>, anyhow::Error> {
+    let base_dir = labels_path.parent().unwrap_or_else(|| Path::new(""));
+    let mut reader = csv::Reader::from_path(labels_path).context("Could not open labels file.")?;
+    reader
+        .deserialize::<LabelRow>()
+        .map(|row| {
+            let row = row.context("Could not parse labels file.")?;
+            Ok(LabelledQuery {
+                path: base_dir.join(row.query),
+                expected_track: row.expected_track.filter(|track| !track.is_empty()),
+                expected_offset_sec: row.expected_offset,
+            })
+        })
+        .collect()
+}
+
+/// Labels a query from its file name, `<track title>@<offset seconds>.wav`, optionally
+/// numbered as `<track title>@<offset seconds>#<n>.wav` to tell apart clips at the same
+/// offset. Without an `@` the whole name is the track title and the offset is unknown.
+pub fn label_from_name(path: &Path) -> LabelledQuery {
+    let stem = path
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+    let (expected_track, expected_offset_sec) = match stem.rsplit_once('@') {
+        Some((title, offset)) => match offset
+            .split_once('#')
+            .map_or(offset, |(offset, _)| offset)
+            .parse::<f32>()
+        {
+            Ok(offset) => (title.to_string(), Some(offset)),
+            Err(_) => (stem.clone(), None),
+        },
+        None => (stem.clone(), None),
+    };
+
+    LabelledQuery {
+        path: path.to_path_buf(),
+        expected_track: Some(expected_track),
+        expected_offset_sec,
+    }
+}
+
+/// Labels from the labels csv if one is given, otherwise from the names of the queries,
+/// a file, directory or glob pattern. The queries are ignored if there is a labels csv.
+pub fn find_labels(
+    labels_path: Option<&Path>,
+    queries: Option<&Path>,
+) -> Result<Vec<LabelledQuery>, anyhow::Error> {
+    match (labels_path, queries) {
+        (Some(labels_path), _) => read_labels(labels_path),
+        (None, Some(queries)) => Ok(input::input_files(queries)?
+            .iter()
+            .map(|path| label_from_name(path))
+            .collect()),
+        (None, None) => {
+            anyhow::bail!("Please specify a labels csv or queries named after their tracks.")
+        }
+    }
+}
+
+/// Time spent in each stage of matching, summed over queries
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StageTimings {
+    pub decode: Duration, // reading the file and computing the spectrogram
+    pub max_filter: Duration,
+    pub peaks: Duration,
+    pub fingerprint: Duration,
+    pub lookup: Duration, // database lookup and scoring
+}
+
+/// Mean milliseconds per query spent in each stage
+#[derive(Serialize, Debug, Clone)]
+pub struct StageTimingsSummary {
+    pub decode_ms: f64,
+    pub max_filter_ms: f64,
+    pub peaks_ms: f64,
+    pub fingerprint_ms: f64,
+    pub lookup_ms: f64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ThresholdStats {
+    pub threshold: f64,
+    pub true_accept_rate: f64, // positive queries whose expected track was accepted
+    pub false_positive_rate: f64, // queries for which a wrong track was accepted
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct EvalSummary {
+    pub queries: usize,
+    pub positives: usize, // queries expected to match a track
+    pub errors: usize,    // queries that couldn't be matched at all
+    pub top_k: usize,
+    pub top1_accuracy: f64,
+    pub topk_accuracy: f64,
+    pub offset_error_mean_sec: Option<f32>, // of correct top-1 results with a known offset
+    pub offset_error_median_sec: Option<f32>,
+    pub offset_error_max_sec: Option<f32>,
+    pub thresholds: Vec<ThresholdStats>,
+    pub mean_timings: StageTimingsSummary,
+}
+
+/// Outcome of matching one labelled query
+pub struct EvalOutcome {
+    pub label: LabelledQuery,
+    pub report: Result<MatchReport, anyhow::Error>,
+}
+
+impl EvalOutcome {
+    /// Whether the expected track is within the first `k` results
+    fn found_within(&self, k: usize) -> bool {
+        match (&self.label.expected_track, &self.report) {
+            (Some(expected), Ok(report)) => report
+                .results
+                .iter()
+                .take(k)
+                .any(|result| &result.title == expected),
+            _ => false,
+        }
+    }
+}
+
+pub fn summarize(
+    outcomes: &[EvalOutcome],
+    top_k: usize,
+    thresholds: &[f64],
+    timings: StageTimings,
+) -> EvalSummary {
+    let queries = outcomes.len();
+    let positives = outcomes
+        .iter()
+        .filter(|outcome| outcome.label.expected_track.is_some())
+        .count();
+    let errors = outcomes
+        .iter()
+        .filter(|outcome| outcome.report.is_err())
+        .count();
+    let rate = |count: usize, total: usize| {
+        if total == 0 {
+            0.
+        } else {
+            count as f64 / total as f64
+        }
+    };
+
+    let top1 = outcomes
+        .iter()
+        .filter(|outcome| outcome.found_within(1))
+        .count();
+    let topk = outcomes
+        .iter()
+        .filter(|outcome| outcome.found_within(top_k))
+        .count();
+
+    let mut offset_errors: Vec<f32> = outcomes
+        .iter()
+        .filter(|outcome| outcome.found_within(1))
+        .filter_map(|outcome| {
+            let expected_offset = outcome.label.expected_offset_sec?;
+            let best = outcome.report.as_ref().ok()?.results.first()?;
+            Some((best.offset_sec - expected_offset).abs())
+        })
+        .collect();
+    offset_errors.sort_by(f32::total_cmp);
+
+    let threshold_stats = thresholds
+        .iter()
+        .map(|&threshold| {
+            let mut true_accepts = 0;
+            let mut false_positives = 0;
+            for outcome in outcomes {
+                let best = match &outcome.report {
+                    Ok(report) => report.results.first(),
+                    Err(_) => None,
+                };
+                if let Some(best) = best.filter(|best| best.confidence >= threshold) {
+                    if outcome.label.expected_track.as_ref() == Some(&best.title) {
+                        true_accepts += 1;
+                    } else {
+                        false_positives += 1;
+                    }
+                }
+            }
+            ThresholdStats {
+                threshold,
+                true_accept_rate: rate(true_accepts, positives),
+                false_positive_rate: rate(false_positives, queries),
+            }
+        })
+        .collect();
+
+    let mean_ms = |duration: Duration| rate(1, queries) * duration.as_secs_f64() * 1000.;
+
+    EvalSummary {
+        queries,
+        positives,
+        errors,
+        top_k,
+        top1_accuracy: rate(top1, positives),
+        topk_accuracy: rate(topk, positives),
+        offset_error_mean_sec: (!offset_errors.is_empty())
+            .then(|| offset_errors.iter().sum::<f32>() / offset_errors.len() as f32),
+        offset_error_median_sec: offset_errors.get(offset_errors.len() / 2).copied(),
+        offset_error_max_sec: offset_errors.last().copied(),
+        thresholds: threshold_stats,
+        mean_timings: StageTimingsSummary {
+            decode_ms: mean_ms(timings.decode),
+            max_filter_ms: mean_ms(timings.max_filter),
+            peaks_ms: mean_ms(timings.peaks),
+            fingerprint_ms: mean_ms(timings.fingerprint),
+            lookup_ms: mean_ms(timings.lookup),
+        },
+    }
+}
+
+/// Matches each labelled query, saving plots if asked to, and summarizes how accurately
+/// they were recognised at each confidence threshold. Each query is passed to
+/// `on_matched` once it has been matched.
+pub fn evaluate(
+    matcher: &mut Matcher<impl Storage>,
+    fingerprinter: &Fingerprinter,
+    labels: Vec<LabelledQuery>,
+    options: &MatchOptions,
+    thresholds: &[f64],
+    plots: Option<&PlotOptions>,
+    mut on_matched: impl FnMut(&Path, &Result<MatchedFile, anyhow::Error>),
+) -> EvalSummary {
+    let mut timings = StageTimings::default();
+    let outcomes: Vec<EvalOutcome> = labels
+        .into_iter()
+        .map(|label| {
+            let matched = input::match_file(
+                matcher,
+                fingerprinter,
+                &label.path,
+                options,
+                plots,
+                &mut timings,
+            );
+            on_matched(&label.path, &matched);
+            EvalOutcome {
+                label,
+                report: matched.map(|matched| matched.report),
+            }
+        })
+        .collect();
+
+    summarize(&outcomes, options.top, thresholds, timings)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn labels_are_read_relative_to_their_csv_without_queries() {
+        let dir = tempfile::tempdir().unwrap();
+        let labels_path = dir.path().join("labels.csv");
+        fs::write(
+            &labels_path,
+            "query,expected_track,expected_offset\nclip.wav,track,1.5\nnoise.wav,,\n",
+        )
+        .unwrap();
+
+        let labels = find_labels(Some(&labels_path), None).unwrap();
+        assert_eq!(labels.len(), 2);
+        assert_eq!(labels[0].path, dir.path().join("clip.wav"));
+        assert_eq!(labels[0].expected_track.as_deref(), Some("track"));
+        assert_eq!(labels[0].expected_offset_sec, Some(1.5));
+        assert_eq!(labels[1].expected_track, None);
+
+        // queries given alongside the csv are not listed
+        let missing = dir.path().join("missing");
+        assert_eq!(
+            find_labels(Some(&labels_path), Some(&missing))
+                .unwrap()
+                .len(),
+            2
+        );
+        assert!(find_labels(None, None).is_err());
+    }
+
+    #[test]
+    fn labels_are_taken_from_query_names() {
+        let label = label_from_name(Path::new("clips/a track@12.50.wav"));
+        assert_eq!(label.expected_track.as_deref(), Some("a track"));
+        assert_eq!(label.expected_offset_sec, Some(12.5));
+        let label = label_from_name(Path::new("clips/a track@0.00#3.wav"));
+        assert_eq!(label.expected_track.as_deref(), Some("a track"));
+        assert_eq!(label.expected_offset_sec, Some(0.));
+        let label = label_from_name(Path::new("clips/track.wav"));
+        assert_eq!(label.expected_track.as_deref(), Some("track"));
+        assert_eq!(label.expected_offset_sec, None);
+    }
+}
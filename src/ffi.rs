@@ -0,0 +1,550 @@
+//! The C interface declared in `include/atlas.h`, for embedding the fingerprinter and
+//! matcher in other programs.
+//!
+//! Every function returning an [`AtlasStatus`] writes its outputs only on success. On
+//! failure [`atlas_last_error`] describes what went wrong. Objects and arrays handed out
+//! belong to the caller, who frees each with the matching `_free` function. Handles may
+//! be moved between threads but not used by two at once.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    ffi::{c_char, CStr, CString},
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    ptr, slice,
+};
+
+use crate::{
+    eval::StageTimings,
+    hash::PairRecord,
+    matching::OffsetBinning,
+    storage::{self, StorageKind},
+    stream::StreamFingerprinter,
+    AnalysisParams, Fingerprinter, Index, MatchOptions, Matcher, Storage,
+};
+
+/// The outcome of a call
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtlasStatus {
+    Ok = 0,
+    NullArgument = 1,    // a required pointer was null
+    InvalidArgument = 2, // parameters, sample counts or strings that can't be used
+    Failed = 3,          // the database couldn't be opened or read
+    Panic = 4,           // a bug in atlas. The handle should no longer be used.
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct AtlasAnalysisParams {
+    pub window_length: f32,       // in seconds
+    pub kernel_size: usize,       // used for maximum filter
+    pub magnitude_threshold: f32, // used for maximum filter
+    pub target_zone_delay_sec: f32,
+    pub target_zone_height_hz: f32,
+    pub target_zone_width_sec: f32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct AtlasMatchOptions {
+    pub offset_bin_width: u32,  // in windows
+    pub offset_neighbours: u32, // bins either side summed into each bin's score
+    pub top: usize,             // number of ranked results to report
+    pub min_confidence: f64,    // required 1 - p-value of the best track's score
+}
+
+/// A hash of a recording and the window its first peak is in
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct AtlasHash {
+    pub hash: u32,
+    pub time: u32,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct AtlasMatchResult {
+    pub track_id: u32,
+    pub title: *mut c_char, // owned by the results
+    pub offset_sec: f32, // where the recording starts in the track, negative if the track starts within it
+    pub score: u32,
+    pub confidence: f64,
+    pub accepted: bool, // whether this result was decided to be the match
+}
+
+/// Fingerprints PCM audio pushed to it in buffers
+pub struct AtlasFingerprinter {
+    stream: StreamFingerprinter,
+    channels: usize,
+    finished: bool,
+}
+
+/// Ranks the tracks of a database a recording's hashes could be from
+pub struct AtlasMatcher {
+    matcher: Matcher<Box<dyn Storage>>,
+    window_length: f32,
+    options: MatchOptions,
+}
+
+impl From<AtlasAnalysisParams> for AnalysisParams {
+    fn from(params: AtlasAnalysisParams) -> Self {
+        AnalysisParams {
+            window_length: params.window_length,
+            kernel_size: params.kernel_size,
+            magnitude_threshold: params.magnitude_threshold,
+            target_zone_delay_sec: params.target_zone_delay_sec,
+            target_zone_height_hz: params.target_zone_height_hz,
+            target_zone_width_sec: params.target_zone_width_sec,
+        }
+    }
+}
+
+impl From<AtlasMatchOptions> for MatchOptions {
+    fn from(options: AtlasMatchOptions) -> Self {
+        MatchOptions {
+            binning: OffsetBinning {
+                bin_width: options.offset_bin_width,
+                neighbours: options.offset_neighbours,
+            },
+            top: options.top,
+            min_confidence: options.min_confidence,
+        }
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// A failed call, with the status to return
+struct Failure {
+    status: AtlasStatus,
+    error: anyhow::Error,
+}
+
+impl Failure {
+    fn null(name: &str) -> Self {
+        Failure {
+            status: AtlasStatus::NullArgument,
+            error: anyhow::anyhow!("{} must not be null.", name),
+        }
+    }
+
+    fn invalid(error: anyhow::Error) -> Self {
+        Failure {
+            status: AtlasStatus::InvalidArgument,
+            error,
+        }
+    }
+}
+
+/// Errors not blamed on the arguments are failures
+impl From<anyhow::Error> for Failure {
+    fn from(error: anyhow::Error) -> Self {
+        Failure {
+            status: AtlasStatus::Failed,
+            error,
+        }
+    }
+}
+
+/// Runs the body of a call, recording why it failed and turning panics into a status
+fn call(body: impl FnOnce() -> Result<(), Failure>) -> AtlasStatus {
+    let failure = match panic::catch_unwind(AssertUnwindSafe(body)) {
+        Ok(Ok(())) => return AtlasStatus::Ok,
+        Ok(Err(failure)) => failure,
+        Err(_) => Failure {
+            status: AtlasStatus::Panic,
+            error: anyhow::anyhow!("atlas panicked."),
+        },
+    };
+    let message = format!("{:#}", failure.error).replace('\0', " ");
+    LAST_ERROR.with(|last| *last.borrow_mut() = CString::new(message).ok());
+    failure.status
+}
+
+/// # Safety
+/// `pointer` must be null or valid for reads of a `T`
+unsafe fn reference<'a, T>(pointer: *const T, name: &str) -> Result<&'a T, Failure> {
+    pointer.as_ref().ok_or_else(|| Failure::null(name))
+}
+
+/// # Safety
+/// `pointer` must be null or valid for writes of a `T`, with no other references to it
+unsafe fn mutable<'a, T>(pointer: *mut T, name: &str) -> Result<&'a mut T, Failure> {
+    pointer.as_mut().ok_or_else(|| Failure::null(name))
+}
+
+/// # Safety
+/// `pointer` must be valid for reads of `len` `T`s, or null if `len` is 0
+unsafe fn elements<'a, T>(pointer: *const T, len: usize, name: &str) -> Result<&'a [T], Failure> {
+    if len == 0 {
+        return Ok(&[]);
+    }
+    if pointer.is_null() {
+        return Err(Failure::null(name));
+    }
+    Ok(slice::from_raw_parts(pointer, len))
+}
+
+/// Hands an array to the caller, who frees it with its length
+fn into_raw_array<T>(elements: Vec<T>) -> (*mut T, usize) {
+    let len = elements.len();
+    if len == 0 {
+        return (ptr::null_mut(), 0);
+    }
+    (Box::into_raw(elements.into_boxed_slice()).cast(), len)
+}
+
+/// # Safety
+/// `pointer` and `len` must have come from [`into_raw_array`], or `pointer` be null
+unsafe fn from_raw_array<T>(pointer: *mut T, len: usize) -> Option<Box<[T]>> {
+    (!pointer.is_null()).then(|| Box::from_raw(ptr::slice_from_raw_parts_mut(pointer, len)))
+}
+
+/// Describes the most recent failure on this thread, or is null if there hasn't been
+/// one. The string belongs to atlas and lasts until the next failure on this thread.
+#[no_mangle]
+pub extern "C" fn atlas_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
+
+/// # Safety
+/// `out` must be null or valid for writes
+#[no_mangle]
+pub unsafe extern "C" fn atlas_analysis_params_default(
+    out: *mut AtlasAnalysisParams,
+) -> AtlasStatus {
+    call(|| {
+        let params = AnalysisParams::default();
+        *mutable(out, "out")? = AtlasAnalysisParams {
+            window_length: params.window_length,
+            kernel_size: params.kernel_size,
+            magnitude_threshold: params.magnitude_threshold,
+            target_zone_delay_sec: params.target_zone_delay_sec,
+            target_zone_height_hz: params.target_zone_height_hz,
+            target_zone_width_sec: params.target_zone_width_sec,
+        };
+        Ok(())
+    })
+}
+
+/// # Safety
+/// `out` must be null or valid for writes
+#[no_mangle]
+pub unsafe extern "C" fn atlas_match_options_default(out: *mut AtlasMatchOptions) -> AtlasStatus {
+    call(|| {
+        let options = MatchOptions::default();
+        *mutable(out, "out")? = AtlasMatchOptions {
+            offset_bin_width: options.binning.bin_width,
+            offset_neighbours: options.binning.neighbours,
+            top: options.top,
+            min_confidence: options.min_confidence,
+        };
+        Ok(())
+    })
+}
+
+/// Creates a fingerprinter for audio with `channels` interleaved channels at
+/// `sample_rate`, of which the first is fingerprinted
+///
+/// # Safety
+/// `params` must be null or point to parameters, `out` be null or valid for writes
+#[no_mangle]
+pub unsafe extern "C" fn atlas_fingerprinter_new(
+    params: *const AtlasAnalysisParams,
+    sample_rate: u32,
+    channels: u32,
+    out: *mut *mut AtlasFingerprinter,
+) -> AtlasStatus {
+    call(|| {
+        let params = *reference(params, "params")?;
+        let out = mutable(out, "out")?;
+        if sample_rate == 0 || channels == 0 {
+            return Err(Failure::invalid(anyhow::anyhow!(
+                "Sample rate and channel count must be positive."
+            )));
+        }
+        let params = Fingerprinter::new(params.into())
+            .map_err(Failure::invalid)?
+            .params();
+        let stream = StreamFingerprinter::new(params, sample_rate).map_err(Failure::invalid)?;
+        *out = Box::into_raw(Box::new(AtlasFingerprinter {
+            stream,
+            channels: channels as usize,
+            finished: false,
+        }));
+        Ok(())
+    })
+}
+
+/// # Safety
+/// `fingerprinter` must be null or have come from [`atlas_fingerprinter_new`] and not
+/// been freed
+#[no_mangle]
+pub unsafe extern "C" fn atlas_fingerprinter_free(fingerprinter: *mut AtlasFingerprinter) {
+    if !fingerprinter.is_null() {
+        drop(Box::from_raw(fingerprinter));
+    }
+}
+
+/// Adds frames of the first channel, scaled like 16 bit samples
+fn push_frames(
+    fingerprinter: &mut AtlasFingerprinter,
+    samples: impl Iterator<Item = f32>,
+) -> Result<(), Failure> {
+    if fingerprinter.finished {
+        return Err(Failure::invalid(anyhow::anyhow!(
+            "No audio can be pushed once the fingerprinter is finished."
+        )));
+    }
+    let first_channel: Vec<f32> = samples.step_by(fingerprinter.channels).collect();
+    fingerprinter
+        .stream
+        .push_samples(&first_channel)
+        .map_err(Failure::from)
+}
+
+/// Adds `frames` frames of interleaved samples in [-1, 1]
+///
+/// # Safety
+/// `fingerprinter` must be null or a live fingerprinter, `samples` be valid for reads of
+/// `frames` times its channel count floats
+#[no_mangle]
+pub unsafe extern "C" fn atlas_fingerprinter_push_f32(
+    fingerprinter: *mut AtlasFingerprinter,
+    samples: *const f32,
+    frames: usize,
+) -> AtlasStatus {
+    call(|| {
+        let fingerprinter = mutable(fingerprinter, "fingerprinter")?;
+        let len = frames
+            .checked_mul(fingerprinter.channels)
+            .ok_or_else(|| Failure::invalid(anyhow::anyhow!("Too many frames to push at once.")))?;
+        let samples = elements(samples, len, "samples")?;
+        push_frames(
+            fingerprinter,
+            samples.iter().map(|&sample| sample * i16::MAX as f32),
+        )
+    })
+}
+
+/// Adds `frames` frames of interleaved 16 bit samples
+///
+/// # Safety
+/// `fingerprinter` must be null or a live fingerprinter, `samples` be valid for reads of
+/// `frames` times its channel count samples
+#[no_mangle]
+pub unsafe extern "C" fn atlas_fingerprinter_push_i16(
+    fingerprinter: *mut AtlasFingerprinter,
+    samples: *const i16,
+    frames: usize,
+) -> AtlasStatus {
+    call(|| {
+        let fingerprinter = mutable(fingerprinter, "fingerprinter")?;
+        let len = frames
+            .checked_mul(fingerprinter.channels)
+            .ok_or_else(|| Failure::invalid(anyhow::anyhow!("Too many frames to push at once.")))?;
+        let samples = elements(samples, len, "samples")?;
+        push_frames(fingerprinter, samples.iter().map(|&sample| sample as f32))
+    })
+}
+
+/// Marks the end of the audio, so peaks in its last windows are found. No audio can be
+/// pushed afterwards.
+///
+/// # Safety
+/// `fingerprinter` must be null or a live fingerprinter
+#[no_mangle]
+pub unsafe extern "C" fn atlas_fingerprinter_finish(
+    fingerprinter: *mut AtlasFingerprinter,
+) -> AtlasStatus {
+    call(|| {
+        let fingerprinter = mutable(fingerprinter, "fingerprinter")?;
+        if !fingerprinter.finished {
+            fingerprinter.stream.finish();
+            fingerprinter.finished = true;
+        }
+        Ok(())
+    })
+}
+
+/// The hashes of the audio pushed so far, ordered by time then hash. Until the
+/// fingerprinter is finished, peaks in the last windows are still to be found. The
+/// array is null when there are no hashes and is freed with [`atlas_hashes_free`].
+///
+/// # Safety
+/// `fingerprinter` must be null or a live fingerprinter, `hashes` and `len` be null or
+/// valid for writes
+#[no_mangle]
+pub unsafe extern "C" fn atlas_fingerprinter_hashes(
+    fingerprinter: *const AtlasFingerprinter,
+    hashes: *mut *mut AtlasHash,
+    len: *mut usize,
+) -> AtlasStatus {
+    call(|| {
+        let fingerprinter = reference(fingerprinter, "fingerprinter")?;
+        let hashes = mutable(hashes, "hashes")?;
+        let len = mutable(len, "len")?;
+        let mut found: Vec<AtlasHash> = fingerprinter
+            .stream
+            .fingerprint_since(0)
+            .into_values()
+            .map(|record| AtlasHash {
+                hash: record.hash,
+                time: record.time_a,
+            })
+            .collect();
+        found.sort_unstable_by_key(|hash| (hash.time, hash.hash));
+        (*hashes, *len) = into_raw_array(found);
+        Ok(())
+    })
+}
+
+/// # Safety
+/// `hashes` must be null or have come with `len` from [`atlas_fingerprinter_hashes`]
+/// and not been freed
+#[no_mangle]
+pub unsafe extern "C" fn atlas_hashes_free(hashes: *mut AtlasHash, len: usize) {
+    drop(from_raw_array(hashes, len));
+}
+
+/// Opens a database of the given kind: 0 SQLite, 1 packed, 2 key-value or 3 inverted
+/// index. Tracks must have been fingerprinted with `params`, which fails as an invalid
+/// argument if the database records others, as does a path with no database. `options` may be null for the defaults.
+///
+/// # Safety
+/// `path` must be null or a NUL-terminated string, `params` null or point to parameters,
+/// `options` null or point to options and `out` be null or valid for writes
+#[no_mangle]
+pub unsafe extern "C" fn atlas_matcher_open(
+    path: *const c_char,
+    kind: u32,
+    params: *const AtlasAnalysisParams,
+    options: *const AtlasMatchOptions,
+    out: *mut *mut AtlasMatcher,
+) -> AtlasStatus {
+    call(|| {
+        if path.is_null() {
+            return Err(Failure::null("path"));
+        }
+        let path = CStr::from_ptr(path)
+            .to_str()
+            .map_err(|err| Failure::invalid(err.into()))?;
+        // the storages would create a database that doesn't exist, and find nothing in it
+        if !Path::new(path).exists() {
+            return Err(Failure::invalid(anyhow::anyhow!(
+                "There is no database at {}.",
+                path
+            )));
+        }
+        let params = Fingerprinter::new((*reference(params, "params")?).into())
+            .map_err(Failure::invalid)?
+            .params();
+        let options = options
+            .as_ref()
+            .map_or_else(MatchOptions::default, |&options| options.into());
+        let out = mutable(out, "out")?;
+        let kind = match kind {
+            0 => StorageKind::Sqlite,
+            1 => StorageKind::Packed,
+            2 => StorageKind::Kv,
+            3 => StorageKind::Inverted,
+            _ => {
+                return Err(Failure::invalid(anyhow::anyhow!(
+                    "Unknown storage kind {}.",
+                    kind
+                )))
+            }
+        };
+
+        let index = Index::new(storage::open_catalogue(kind, &[PathBuf::from(path)])?);
+        index.check_params(params).map_err(Failure::invalid)?;
+        *out = Box::into_raw(Box::new(AtlasMatcher {
+            matcher: Matcher::new(index)?,
+            window_length: params.window_length,
+            options,
+        }));
+        Ok(())
+    })
+}
+
+/// # Safety
+/// `matcher` must be null or have come from [`atlas_matcher_open`] and not been freed
+#[no_mangle]
+pub unsafe extern "C" fn atlas_matcher_free(matcher: *mut AtlasMatcher) {
+    if !matcher.is_null() {
+        drop(Box::from_raw(matcher));
+    }
+}
+
+/// Ranks the tracks the hashes of a recording could be from, best first. The array of
+/// at most `options.top` results is null when there are none and is freed with
+/// [`atlas_match_results_free`].
+///
+/// # Safety
+/// `matcher` must be null or a live matcher, `hashes` be valid for reads of `len`
+/// hashes, and `results` and `count` be null or valid for writes
+#[no_mangle]
+pub unsafe extern "C" fn atlas_matcher_match(
+    matcher: *mut AtlasMatcher,
+    hashes: *const AtlasHash,
+    len: usize,
+    results: *mut *mut AtlasMatchResult,
+    count: *mut usize,
+) -> AtlasStatus {
+    call(|| {
+        let matcher = mutable(matcher, "matcher")?;
+        let hashes: HashMap<u32, PairRecord> = elements(hashes, len, "hashes")?
+            .iter()
+            .map(|&AtlasHash { hash, time }| (hash, PairRecord { hash, time_a: time }))
+            .collect();
+        let results = mutable(results, "results")?;
+        let count = mutable(count, "count")?;
+
+        let (_, report) = matcher.matcher.match_hashes(
+            "buffer",
+            &hashes,
+            matcher.window_length,
+            &matcher.options,
+            &mut StageTimings::default(),
+        )?;
+        let ranked = report
+            .results
+            .into_iter()
+            .map(|result| AtlasMatchResult {
+                track_id: result.track_id,
+                // titles are stored as text, so only hold a NUL if it was added with one
+                title: CString::new(result.title.replace('\0', " "))
+                    .unwrap_or_default()
+                    .into_raw(),
+                offset_sec: result.offset_sec,
+                score: result.score,
+                confidence: result.confidence,
+                accepted: result.accepted,
+            })
+            .collect();
+        (*results, *count) = into_raw_array(ranked);
+        Ok(())
+    })
+}
+
+/// # Safety
+/// `results` must be null or have come with `count` from [`atlas_matcher_match`] and
+/// not been freed
+#[no_mangle]
+pub unsafe extern "C" fn atlas_match_results_free(results: *mut AtlasMatchResult, count: usize) {
+    if let Some(results) = from_raw_array(results, count) {
+        for result in results.iter() {
+            if !result.title.is_null() {
+                drop(CString::from_raw(result.title));
+            }
+        }
+    }
+}
@@ -0,0 +1,302 @@
+//! Fingerprints saved to a file, so audio can be fingerprinted in one place and added or
+//! matched in another without moving the audio.
+//!
+//! A fingerprint file is little-endian binary:
+//! - the magic bytes `ATLASFP\0` and a u32 version, currently 1
+//! - the analysis parameters: window length (f32, seconds), kernel size (u32), magnitude
+//!   threshold (f32), target zone delay (f32, seconds), height (f32, Hz) and width (f32,
+//!   seconds)
+//! - the number of spectrogram windows analysed (u32)
+//! - the title, as a u32 byte length and UTF-8
+//! - the number of peak pairs (u32), then each pair as four varints: the window of its
+//!   first peak less that of the previous pair's, the frequency bins of the two peaks and
+//!   the windows between them
+//!
+//! Pairs rather than hashes are stored so that hashes are always computed by the host
+//! that matches them.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{Read, Write},
+    path::Path,
+};
+
+use anyhow::Context;
+
+use crate::{
+    fingerprinter::{Analysis, Fingerprinter},
+    hash::{self, PairRecord, PeakLocationPair},
+    varint::{read_varint, write_varint},
+    AnalysisParams,
+};
+
+pub const EXTENSION: &str = "afp";
+const MAGIC: &[u8; 8] = b"ATLASFP\0";
+const VERSION: u32 = 1;
+
+/// The peak pairs of a recording and how they were found
+#[derive(Debug, Clone)]
+pub struct FingerprintFile {
+    pub params: AnalysisParams,
+    pub title: String,
+    pub windows: u32,                 // analysed, for the duration of the recording
+    pub pairs: Vec<PeakLocationPair>, // ordered by the window of the first peak
+}
+
+impl FingerprintFile {
+    /// The fingerprints of an analysed recording
+    pub fn new(fingerprinter: &Fingerprinter, title: &str, analysis: &Analysis) -> Self {
+        FingerprintFile {
+            params: fingerprinter.params(),
+            title: title.to_string(),
+            windows: analysis.windows.nrows() as u32,
+            pairs: fingerprinter.find_pairs(&analysis.peaks),
+        }
+    }
+
+    /// The hashes of the pairs, as fingerprinting the recording would give
+    pub fn hashes(&self) -> HashMap<u32, PairRecord> {
+        hash::hash_pairs(&self.pairs)
+    }
+
+    /// Fails unless the pairs were found with `params`, as hashes found with different
+    /// parameters can't be matched against each other
+    pub fn check_params(&self, params: AnalysisParams) -> Result<(), anyhow::Error> {
+        if self.params != params {
+            anyhow::bail!(
+                "{} was fingerprinted with {:?}, not {:?}.",
+                self.title,
+                self.params,
+                params
+            );
+        }
+        Ok(())
+    }
+
+    /// Writes the file to `path`, failing rather than replacing a file already there
+    pub fn write(&self, path: &Path) -> Result<(), anyhow::Error> {
+        let bytes = self.to_bytes()?;
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .with_context(|| format!("Could not create {}.", path.display()))?;
+        file.write_all(&bytes)
+            .with_context(|| format!("Could not write {}.", path.display()))
+    }
+
+    /// The contents of the file
+    pub fn to_bytes(&self) -> Result<Vec<u8>, anyhow::Error> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(VERSION.to_le_bytes());
+        bytes.extend(self.params.window_length.to_le_bytes());
+        bytes.extend((self.params.kernel_size as u32).to_le_bytes());
+        bytes.extend(self.params.magnitude_threshold.to_le_bytes());
+        bytes.extend(self.params.target_zone_delay_sec.to_le_bytes());
+        bytes.extend(self.params.target_zone_height_hz.to_le_bytes());
+        bytes.extend(self.params.target_zone_width_sec.to_le_bytes());
+        bytes.extend(self.windows.to_le_bytes());
+        bytes.extend((self.title.len() as u32).to_le_bytes());
+        bytes.extend(self.title.as_bytes());
+
+        bytes.extend((self.pairs.len() as u32).to_le_bytes());
+        let mut previous_window = 0;
+        for &((window_a, freq_a), (window_b, freq_b)) in &self.pairs {
+            let encoded = [
+                window_a.checked_sub(previous_window),
+                Some(freq_a),
+                Some(freq_b),
+                window_b.checked_sub(window_a),
+            ];
+            for value in encoded {
+                let value = value
+                    .and_then(|value| u32::try_from(value).ok())
+                    .context("Peak pairs must be ordered by time and fit in 32 bits.")?;
+                write_varint(&mut bytes, value);
+            }
+            previous_window = window_a;
+        }
+        Ok(bytes)
+    }
+
+    pub fn read(path: &Path) -> Result<Self, anyhow::Error> {
+        let bytes =
+            fs::read(path).with_context(|| format!("Could not read {}.", path.display()))?;
+        Self::from_bytes(&bytes)
+            .with_context(|| format!("{} is not a valid fingerprint file.", path.display()))
+    }
+
+    /// Reads the contents of a fingerprint file
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, anyhow::Error> {
+        const TRUNCATED: &str = "The file is truncated.";
+        if bytes.get(..MAGIC.len()) != Some(MAGIC) {
+            anyhow::bail!("The file doesn't start with the fingerprint file magic bytes.");
+        }
+        let mut reader = &bytes[MAGIC.len()..];
+        let mut read_u32 = || -> Result<u32, anyhow::Error> {
+            let mut word = [0; 4];
+            reader.read_exact(&mut word).context(TRUNCATED)?;
+            Ok(u32::from_le_bytes(word))
+        };
+
+        let version = read_u32()?;
+        if version != VERSION {
+            anyhow::bail!("Version {} fingerprint files aren't supported.", version);
+        }
+        let params = AnalysisParams {
+            window_length: f32::from_bits(read_u32()?),
+            kernel_size: read_u32()? as usize,
+            magnitude_threshold: f32::from_bits(read_u32()?),
+            target_zone_delay_sec: f32::from_bits(read_u32()?),
+            target_zone_height_hz: f32::from_bits(read_u32()?),
+            target_zone_width_sec: f32::from_bits(read_u32()?),
+        };
+        let windows = read_u32()?;
+        let title_len = read_u32()? as usize;
+
+        // fixed fields: magic, version, six parameters, windows and title length
+        let title_start = MAGIC.len() + 4 * 9;
+        let title_end = title_start.checked_add(title_len).context(TRUNCATED)?;
+        let title = bytes.get(title_start..title_end).context(TRUNCATED)?;
+        let title = String::from_utf8(title.to_vec()).context("The title isn't UTF-8.")?;
+
+        let count = bytes.get(title_end..title_end + 4).context(TRUNCATED)?;
+        let count = u32::from_le_bytes([count[0], count[1], count[2], count[3]]) as usize;
+        let mut position = title_end + 4;
+        let mut pairs = Vec::with_capacity(count.min(bytes.len()));
+        let mut previous_window = 0usize;
+        for _ in 0..count {
+            let mut next = || read_varint(bytes, &mut position).context(TRUNCATED);
+            let window_a = previous_window
+                .checked_add(next()? as usize)
+                .context("A window is out of range.")?;
+            let freq_a = next()? as usize;
+            let freq_b = next()? as usize;
+            let window_b = window_a
+                .checked_add(next()? as usize)
+                .context("A window is out of range.")?;
+            pairs.push(((window_a, freq_a), (window_b, freq_b)));
+            previous_window = window_a;
+        }
+        if position != bytes.len() {
+            anyhow::bail!("The file has data after its last peak pair.");
+        }
+
+        Ok(FingerprintFile {
+            params,
+            title,
+            windows,
+            pairs,
+        })
+    }
+}
+
+/// Whether the file at `path` starts like a fingerprint file. Unreadable files aren't.
+pub fn is_fingerprint_file(path: &Path) -> bool {
+    let mut magic = [0; MAGIC.len()];
+    fs::File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .is_ok()
+        && is_fingerprint_data(&magic)
+}
+
+/// Whether the data starts like a fingerprint file
+pub fn is_fingerprint_data(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fingerprints() -> FingerprintFile {
+        FingerprintFile {
+            params: AnalysisParams::default(),
+            title: "01. Intro – live".to_string(),
+            windows: 1200,
+            pairs: vec![
+                ((0, 3), (2, 5)),
+                ((0, 3), (30, 1)),
+                ((7, 200), (8, 190)),
+                ((1_000_000, 0), (1_000_040, 511)),
+            ],
+        }
+    }
+
+    #[test]
+    fn files_round_trip() {
+        let original = fingerprints();
+        let bytes = original.to_bytes().unwrap();
+        assert!(is_fingerprint_data(&bytes));
+        let read = FingerprintFile::from_bytes(&bytes).unwrap();
+        assert_eq!(read.params, original.params);
+        assert_eq!(read.title, original.title);
+        assert_eq!(read.windows, original.windows);
+        assert_eq!(read.pairs, original.pairs);
+        assert_eq!(read.hashes().len(), original.hashes().len());
+    }
+
+    #[test]
+    fn files_are_written_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(format!("track.{}", EXTENSION));
+        fingerprints().write(&path).unwrap();
+        assert!(is_fingerprint_file(&path));
+        assert_eq!(FingerprintFile::read(&path).unwrap().pairs.len(), 4);
+        assert!(fingerprints().write(&path).is_err());
+    }
+
+    #[test]
+    fn pairs_out_of_order_are_not_written() {
+        let mut unordered = fingerprints();
+        unordered.pairs.reverse();
+        assert!(unordered.to_bytes().is_err());
+    }
+
+    #[test]
+    fn other_files_and_versions_are_rejected() {
+        let bytes = fingerprints().to_bytes().unwrap();
+        let mut wav = bytes.clone();
+        wav[..4].copy_from_slice(b"RIFF");
+        assert!(!is_fingerprint_data(&wav));
+        assert!(FingerprintFile::from_bytes(&wav).is_err());
+
+        let mut newer = bytes;
+        newer[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(FingerprintFile::from_bytes(&newer).is_err());
+    }
+
+    #[test]
+    fn truncated_and_extended_files_are_rejected() {
+        let bytes = fingerprints().to_bytes().unwrap();
+        for len in 0..bytes.len() {
+            assert!(
+                FingerprintFile::from_bytes(&bytes[..len]).is_err(),
+                "{} bytes",
+                len
+            );
+        }
+        let mut extended = bytes;
+        extended.push(0);
+        assert!(FingerprintFile::from_bytes(&extended).is_err());
+    }
+
+    #[test]
+    fn corrupt_titles_and_counts_are_rejected() {
+        let bytes = fingerprints().to_bytes().unwrap();
+        let title_len_at = MAGIC.len() + 4 * 8;
+        let mut long_title = bytes.clone();
+        long_title[title_len_at..title_len_at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(FingerprintFile::from_bytes(&long_title).is_err());
+
+        let mut not_utf8 = bytes.clone();
+        not_utf8[title_len_at + 4] = 0xff;
+        assert!(FingerprintFile::from_bytes(&not_utf8).is_err());
+
+        let count_at = title_len_at + 4 + fingerprints().title.len();
+        let mut many_pairs = bytes;
+        many_pairs[count_at..count_at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(FingerprintFile::from_bytes(&many_pairs).is_err());
+    }
+}
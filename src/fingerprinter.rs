@@ -0,0 +1,133 @@
+use std::{collections::HashMap, path::Path, time::Instant};
+
+use ndarray::Array2;
+
+use crate::{
+    audio_ops,
+    eval::StageTimings,
+    hash::{self, PairRecord, PeakLocationPair},
+    image_ops, AnalysisParams,
+};
+
+/// Turns audio into the hashes of pairs of peaks in its spectrogram
+#[derive(Debug, Clone, Copy)]
+pub struct Fingerprinter {
+    params: AnalysisParams,
+}
+
+/// The result of each stage of fingerprinting a recording
+#[derive(Debug, Clone)]
+pub struct Analysis {
+    pub windows: Array2<f32>,       // spectrogram, a row per window
+    pub filtered: Array2<f32>,      // spectrogram after the maximum filter
+    pub peaks: Vec<(usize, usize)>, // (window, frequency bin), ordered by window
+    pub hashes: HashMap<u32, PairRecord>,
+}
+
+impl Fingerprinter {
+    /// Fails if the parameters don't describe a usable analysis
+    pub fn new(params: AnalysisParams) -> Result<Self, anyhow::Error> {
+        if !(params.window_length.is_finite() && params.window_length > 0.) {
+            anyhow::bail!("Window length must be a positive number of seconds.");
+        }
+        let target_zone = [
+            params.target_zone_delay_sec,
+            params.target_zone_height_hz,
+            params.target_zone_width_sec,
+        ];
+        if !target_zone
+            .iter()
+            .all(|value| value.is_finite() && *value >= 0.)
+        {
+            anyhow::bail!("Target zone dimensions must not be negative.");
+        }
+        if params.magnitude_threshold.is_nan() {
+            anyhow::bail!("Magnitude threshold must be a number.");
+        }
+
+        Ok(Fingerprinter { params })
+    }
+
+    pub fn params(&self) -> AnalysisParams {
+        self.params
+    }
+
+    /// Analyses a wav file, adding the time spent in each stage to `timings`
+    pub fn analyse_file(
+        &self,
+        path: &Path,
+        timings: &mut StageTimings,
+    ) -> Result<Analysis, anyhow::Error> {
+        let start = Instant::now();
+        let windows = audio_ops::read_wav_to_fft(path, self.params.window_length)?;
+        timings.decode += start.elapsed();
+
+        Ok(self.analyse_spectrogram(windows, timings))
+    }
+
+    /// Analyses mono samples scaled like 16 bit samples
+    pub fn analyse_samples(
+        &self,
+        samples: &[f32],
+        sample_rate: u32,
+        timings: &mut StageTimings,
+    ) -> Result<Analysis, anyhow::Error> {
+        let start = Instant::now();
+        let windows = audio_ops::samples_to_fft(samples, sample_rate, self.params.window_length)?;
+        timings.decode += start.elapsed();
+
+        Ok(self.analyse_spectrogram(windows, timings))
+    }
+
+    fn analyse_spectrogram(&self, windows: Array2<f32>, timings: &mut StageTimings) -> Analysis {
+        let start = Instant::now();
+        let filtered = image_ops::max_filter(&windows, self.params.kernel_size);
+        timings.max_filter += start.elapsed();
+
+        let start = Instant::now();
+        let peaks = self.find_peaks(&windows, &filtered);
+        timings.peaks += start.elapsed();
+
+        let start = Instant::now();
+        let hashes = self.fingerprint(&peaks);
+        timings.fingerprint += start.elapsed();
+
+        Analysis {
+            windows,
+            filtered,
+            peaks,
+            hashes,
+        }
+    }
+
+    /// Finds local maxima of the spectrogram louder than the magnitude threshold,
+    /// ordered by time
+    pub fn find_peaks(&self, windows: &Array2<f32>, filtered: &Array2<f32>) -> Vec<(usize, usize)> {
+        image_ops::find_equal(windows, filtered)
+            .into_iter()
+            .filter(|&loc| windows[loc] > self.params.magnitude_threshold)
+            .collect()
+    }
+
+    /// Pairs of peaks that are hashed, the second of each in the target zone of the first
+    pub fn find_pairs(&self, peaks: &[(usize, usize)]) -> Vec<PeakLocationPair> {
+        hash::find_pairs(
+            peaks,
+            self.params.window_length,
+            self.params.target_zone_delay_sec,
+            self.params.target_zone_height_hz,
+            self.params.target_zone_width_sec,
+        )
+    }
+
+    /// Hashes the pairs of peaks, keyed by hash
+    pub fn fingerprint(&self, peaks: &[(usize, usize)]) -> HashMap<u32, PairRecord> {
+        hash::fingerprint(
+            peaks,
+            self.params.window_length,
+            self.params.target_zone_delay_sec,
+            self.params.target_zone_height_hz,
+            self.params.target_zone_width_sec,
+        )
+    }
+}
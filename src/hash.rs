@@ -31,19 +31,23 @@ pub fn calculate_hash<T: Hash>(t: &T) -> u32 {
     s.finish32()
 }
 
-pub fn fingerprint(
+/// Locations of two paired peaks, (time, frequency bin) each
+pub type PeakLocationPair = ((usize, usize), (usize, usize));
+
+/// Pairs of peaks where the second is in the target zone of the first
+pub fn find_pairs(
     peak_locations: &[(usize, usize)],
     window_length: f32,
     target_zone_delay_sec: f32,
     target_zone_height_hz: f32,
     target_zone_width_sec: f32,
-) -> HashMap<u32, PairRecord> {
+) -> Vec<PeakLocationPair> {
     let frequency_resolution = 1. / window_length;
 
     // Find pairs of peaks where one peak is in the target zone of the other
     let mut close_pairs = vec![];
-    for (i, loc_a) in peak_locations.iter().enumerate() {
-        for loc_b in peak_locations[i + 1..].iter() {
+    for (i, &loc_a) in peak_locations.iter().enumerate() {
+        for &loc_b in peak_locations[i + 1..].iter() {
             // dbg!(loc_b.0, loc_a.0, target_zone_delay_sec / window_length, target_zone_width_sec / window_length);
             if loc_b.0 - loc_a.0
                 > (target_zone_delay_sec / window_length + target_zone_width_sec / window_length)
@@ -64,11 +68,31 @@ pub fn fingerprint(
             }
         }
     }
+    close_pairs
+}
+
+pub fn fingerprint(
+    peak_locations: &[(usize, usize)],
+    window_length: f32,
+    target_zone_delay_sec: f32,
+    target_zone_height_hz: f32,
+    target_zone_width_sec: f32,
+) -> HashMap<u32, PairRecord> {
+    let close_pairs = find_pairs(
+        peak_locations,
+        window_length,
+        target_zone_delay_sec,
+        target_zone_height_hz,
+        target_zone_width_sec,
+    );
+
+    hash_pairs(&close_pairs)
+}
 
-    println!("Calculating {} hashes", close_pairs.len());
-    // Calculate hashes to create pair records
+/// Hashes pairs of peaks, keyed by hash. Of pairs with the same hash, the last is kept.
+pub fn hash_pairs(pairs: &[PeakLocationPair]) -> HashMap<u32, PairRecord> {
     let mut records = HashMap::new();
-    for (&loc_a, &loc_b) in close_pairs {
+    for &(loc_a, loc_b) in pairs {
         let pair = pair_from_locations(loc_a, loc_b);
         let hash = calculate_hash(&pair);
         let record = PairRecord {
@@ -0,0 +1,270 @@
+//! A local HTTP server for a [`Service`].
+//!
+//! - `POST /match?query=<name>` with wav audio or a fingerprint file as the body ranks the
+//!   tracks it could be, answering with the JSON match report of the match command
+//! - `POST /tracks?title=<title>` adds the body as a track, replacing any with the same
+//!   title. The title may be left out for fingerprint files, which carry their own.
+//! - `DELETE /tracks?title=<title>` removes a track
+//! - `GET /stats` reports the size of the catalogue and its lookup speed
+//!
+//! Errors are answered with a 4xx or 5xx status and `{"error": "..."}`.
+
+use std::{
+    io::Read,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+use serde::Serialize;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::{
+    service::{Service, Upload},
+    Storage,
+};
+
+/// Largest body accepted, enough for several minutes of CD quality stereo audio
+pub const MAX_UPLOAD_BYTES: u64 = 256 * 1024 * 1024;
+
+/// A failed request, with the status to answer it with
+struct HttpError {
+    status: u16,
+    error: anyhow::Error,
+}
+
+impl HttpError {
+    fn bad_request(error: anyhow::Error) -> Self {
+        HttpError { status: 400, error }
+    }
+
+    fn not_found(message: String) -> Self {
+        HttpError {
+            status: 404,
+            error: anyhow::anyhow!(message),
+        }
+    }
+}
+
+/// Errors not blamed on the request are the server's
+impl From<anyhow::Error> for HttpError {
+    fn from(error: anyhow::Error) -> Self {
+        HttpError { status: 500, error }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+#[derive(Serialize)]
+struct Removed {
+    title: String,
+    removed: bool,
+}
+
+/// A server listening for requests, run until stopped with a [`ShutdownHandle`]
+pub struct HttpServer {
+    server: Arc<Server>,
+    stopping: Arc<AtomicBool>,
+    workers: usize,
+}
+
+/// Stops an [`HttpServer`] from another thread
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    server: Arc<Server>,
+    stopping: Arc<AtomicBool>,
+    workers: usize,
+}
+
+impl HttpServer {
+    /// Listens on `address`, e.g. `127.0.0.1:8080` or port 0 to pick a free port, to
+    /// serve with `workers` threads
+    pub fn bind(address: &str, workers: usize) -> Result<Self, anyhow::Error> {
+        let server = Server::http(address)
+            .map_err(|err| anyhow::anyhow!("Could not listen on {}: {}", address, err))?;
+        Ok(HttpServer {
+            server: Arc::new(server),
+            stopping: Arc::new(AtomicBool::new(false)),
+            workers: workers.max(1),
+        })
+    }
+
+    /// The address listened on, if it is an IP address
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            server: self.server.clone(),
+            stopping: self.stopping.clone(),
+            workers: self.workers,
+        }
+    }
+
+    /// Serves requests until shut down, finishing those already being served. Requests
+    /// that fail, and failures to receive or answer them, are passed to `on_error`.
+    pub fn run<S: Storage>(&self, service: &Service<S>, on_error: impl Fn(anyhow::Error) + Sync) {
+        thread::scope(|scope| {
+            for _ in 0..self.workers {
+                scope.spawn(|| loop {
+                    match self.server.recv() {
+                        Ok(request) => respond(service, request, &on_error),
+                        Err(_) if self.stopping.load(Ordering::Acquire) => return,
+                        Err(err) => on_error(
+                            anyhow::Error::from(err).context("Could not receive a request."),
+                        ),
+                    }
+                });
+            }
+        });
+    }
+}
+
+impl ShutdownHandle {
+    /// Has the server stop taking requests. [`HttpServer::run`] returns once those
+    /// already taken are answered.
+    pub fn shutdown(&self) {
+        self.stopping.store(true, Ordering::Release);
+        // each wakes one worker waiting for a request
+        for _ in 0..self.workers {
+            self.server.unblock();
+        }
+    }
+}
+
+fn respond<S: Storage>(
+    service: &Service<S>,
+    mut request: Request,
+    on_error: impl Fn(anyhow::Error),
+) {
+    let method = request.method().clone();
+    let url = request.url().to_string();
+    let (status, body) = match route(service, &mut request) {
+        Ok(body) => (200, body),
+        Err(err) => {
+            let body = ErrorBody {
+                error: format!("{:#}", err.error),
+            };
+            on_error(err.error.context(format!("{} {}", method, url)));
+            (err.status, serde_json::to_string(&body).unwrap_or_default())
+        }
+    };
+    let header = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+        .expect("The content type header is valid.");
+    let response = Response::from_string(body)
+        .with_status_code(status)
+        .with_header(header);
+    if let Err(err) = request.respond(response) {
+        on_error(
+            anyhow::Error::from(err).context(format!("{} {}: could not respond", method, url)),
+        );
+    }
+}
+
+/// Handles a request, answering with a JSON body
+fn route<S: Storage>(service: &Service<S>, request: &mut Request) -> Result<String, HttpError> {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let body = match (request.method(), path) {
+        (Method::Post, "/match") => {
+            let upload = read_upload(service, request)?;
+            let name = query_param(query, "query")
+                .or_else(|| upload.title.clone())
+                .unwrap_or_else(|| "upload".to_string());
+            serde_json::to_string(&service.match_upload(&name, &upload)?)
+        }
+        (Method::Post, "/tracks") => {
+            let upload = read_upload(service, request)?;
+            let title = query_param(query, "title")
+                .or_else(|| upload.title.clone())
+                .ok_or_else(|| {
+                    HttpError::bad_request(anyhow::anyhow!("Give the track a title."))
+                })?;
+            serde_json::to_string(&service.add_upload(&title, None, &upload)?)
+        }
+        (Method::Delete, "/tracks") => {
+            let title = query_param(query, "title").ok_or_else(|| {
+                HttpError::bad_request(anyhow::anyhow!("Give the title of the track to remove."))
+            })?;
+            if !service.remove_track(&title)? {
+                return Err(HttpError::not_found(format!("No track titled {}.", title)));
+            }
+            serde_json::to_string(&Removed {
+                title,
+                removed: true,
+            })
+        }
+        (Method::Get, "/stats") => serde_json::to_string(&service.stats()?),
+        _ => {
+            return Err(HttpError::not_found(format!(
+                "No endpoint {} {}.",
+                request.method(),
+                path
+            )))
+        }
+    };
+    Ok(body.map_err(anyhow::Error::from)?)
+}
+
+/// Reads the body of a request and fingerprints it
+fn read_upload<S: Storage>(
+    service: &Service<S>,
+    request: &mut Request,
+) -> Result<Upload, HttpError> {
+    let mut bytes = vec![];
+    request
+        .as_reader()
+        .take(MAX_UPLOAD_BYTES + 1)
+        .read_to_end(&mut bytes)
+        .map_err(|err| HttpError::bad_request(err.into()))?;
+    if bytes.len() as u64 > MAX_UPLOAD_BYTES {
+        return Err(HttpError {
+            status: 413,
+            error: anyhow::anyhow!("Uploads are limited to {} bytes.", MAX_UPLOAD_BYTES),
+        });
+    }
+    Upload::parse(&bytes, service.fingerprinter()).map_err(HttpError::bad_request)
+}
+
+/// The percent-decoded value of a parameter of a query string
+fn query_param(query: &str, name: &str) -> Option<String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| percent_decode(value))
+}
+
+/// Decodes `%xx` escapes and `+` for spaces, keeping malformed escapes as they are
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut position = 0;
+    while position < bytes.len() {
+        match bytes[position] {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let escape = bytes
+                    .get(position + 1..position + 3)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                if let Some(byte) = escape {
+                    decoded.push(byte);
+                    position += 2;
+                } else {
+                    decoded.push(b'%');
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        position += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
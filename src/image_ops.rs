@@ -1,61 +1,202 @@
 use std::path::PathBuf;
 
+use anyhow::Context;
 use ndarray::{s, Array, Array2, ArrayView2};
-use ndarray_stats::QuantileExt;
 use plotters::prelude::*;
 
-pub fn save_png(windows: &Array2<f32>, output_path: PathBuf) {
-    let height = windows.ncols();
-    let width = windows.nrows();
+use crate::{hash::PeakLocationPair, matching::MatchExplanation};
 
-    println!("Generating a {} wide x {} high image", width, height);
+/// Image formats plots can be saved in
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlotFormat {
+    Png,
+    Svg,
+}
 
-    let image_dimensions: (u32, u32) = (width as u32, height as u32);
-    let root_drawing_area = BitMapBackend::new(
-        &output_path,
-        image_dimensions, // width x height. Worth it if we ever want to resize the graph.
-    )
-    .into_drawing_area();
+impl PlotFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            PlotFormat::Png => "png",
+            PlotFormat::Svg => "svg",
+        }
+    }
+}
 
-    let spectrogram_cells = root_drawing_area.split_evenly((height, width));
+/// Plots that can be saved while adding or matching
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlotKind {
+    Spectrogram, // spectrogram of the audio
+    Filtered,    // spectrogram after the maximum filter
+    Peaks,       // peak locations
+    Pairs,       // peak locations with lines between the pairs that are hashed
+    Match,       // matching hashes and offset histogram of each candidate
+    Compare,     // sample peaks against the aligned peaks of each candidate
+}
 
-    let window_size = (height - 1) * 2;
-    let windows_scaled = windows.map(|i| i.abs() / (window_size as f32));
-    let highest_spectral_density = windows_scaled.max_skipnan();
+/// Binds `$root` to the root drawing area of a new image at `$path` in `$format` and
+/// runs `$draw`, which is compiled once for each backend
+macro_rules! with_root {
+    ($format:expr, $path:expr, $size:expr, |$root:ident| $draw:block) => {
+        match $format {
+            PlotFormat::Png => {
+                let $root = BitMapBackend::new($path, $size).into_drawing_area();
+                $draw
+            }
+            PlotFormat::Svg => {
+                let $root = SVGBackend::new($path, $size).into_drawing_area();
+                $draw
+            }
+        }
+    };
+}
 
-    // transpose and flip around to prepare for graphing
-    let windows_flipped = windows_scaled.slice(ndarray::s![.., ..; -1]); // flips the
-    let windows_flipped = windows_flipped.t();
+/// Largest image to render a spectrogram at. Larger spectrograms are downsampled,
+/// keeping the loudest cell of each block so peaks stay visible.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PlotSize {
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+}
 
-    // Finally add a color scale
-    let color_scale = colorous::PLASMA;
+/// Saves an image of the spectrogram with time and frequency axes and a colour bar.
+/// Pixels are written straight into a buffer, which is drawn onto the chart in one go.
+pub fn plot_spectrogram(
+    windows: &Array2<f32>,
+    window_length: f32,
+    size: PlotSize,
+    format: PlotFormat,
+    output_path: PathBuf,
+) -> Result<(), anyhow::Error> {
+    let height = windows.ncols();
+    let width = windows.nrows();
+    if width == 0 || height == 0 {
+        anyhow::bail!("Spectrogram is empty.");
+    }
+    let plot_width = size
+        .max_width
+        .map_or(width, |max| width.min(max.max(1) as usize));
+    let plot_height = size
+        .max_height
+        .map_or(height, |max| height.min(max.max(1) as usize));
 
-    for (cell, spectral_density) in spectrogram_cells.iter().zip(windows_flipped.iter()) {
+    // a single frequency bin comes from a window of one sample
+    let window_size = ((height - 1) * 2).max(1);
+    let highest_spectral_density = windows
+        .iter()
+        .map(|i| i.abs() / window_size as f32)
+        .fold(0f32, f32::max);
+    // square root scale, saturating at a quarter of the highest density
+    let color_scale = colorous::PLASMA;
+    let color_of = |spectral_density: f32| {
         let spectral_density_scaled =
-            2. * spectral_density.sqrt() / highest_spectral_density.sqrt();
-        let color = color_scale.eval_continuous(spectral_density_scaled as f64);
-        cell.fill(&RGBColor(color.r, color.g, color.b)).unwrap();
+            2. * spectral_density.sqrt() / highest_spectral_density.sqrt().max(f32::EPSILON);
+        color_scale.eval_continuous(spectral_density_scaled.min(1.) as f64)
+    };
+
+    // the loudest cell of each block of windows and frequency bins, highest frequency at the top
+    let mut pixels = vec![0u8; plot_width * plot_height * 3];
+    for y in 0..plot_height {
+        let freq_start = (plot_height - 1 - y) * height / plot_height;
+        let freq_end = ((plot_height - y) * height / plot_height).max(freq_start + 1);
+        for x in 0..plot_width {
+            let time_start = x * width / plot_width;
+            let time_end = ((x + 1) * width / plot_width).max(time_start + 1);
+            let block_max = windows
+                .slice(s![time_start..time_end, freq_start..freq_end])
+                .iter()
+                .fold(0f32, |max, i| max.max(i.abs()));
+            let color = color_of(block_max / window_size as f32);
+            let pixel = (y * plot_width + x) * 3;
+            pixels[pixel..pixel + 3].copy_from_slice(&[color.r, color.g, color.b]);
+        }
     }
 
-    root_drawing_area.present().unwrap();
+    let (margin, x_label_size, y_label_size, color_bar_width) = (10, 40, 70, 110);
+    let image_size = (
+        (plot_width + y_label_size + 2 * margin + color_bar_width) as u32,
+        (plot_height + x_label_size + 2 * margin) as u32,
+    );
+    with_root!(format, &output_path, image_size, |root| {
+        root.fill(&WHITE)?;
+        let (spectrogram_area, color_bar_area) =
+            root.split_horizontally((plot_width + y_label_size + 2 * margin) as u32);
+
+        let duration_sec = width as f32 * window_length;
+        let max_frequency_hz = height as f32 / window_length;
+        let mut spectrogram_ctx = ChartBuilder::on(&spectrogram_area)
+            .margin(margin as u32)
+            .x_label_area_size(x_label_size as u32)
+            .y_label_area_size(y_label_size as u32)
+            .build_cartesian_2d(0f32..duration_sec, 0f32..max_frequency_hz)?;
+        spectrogram_ctx
+            .configure_mesh()
+            .disable_x_mesh()
+            .disable_y_mesh()
+            .x_desc("Time (s)")
+            .y_desc("Frequency (Hz)")
+            .label_style(("sans-serif", 12))
+            .axis_desc_style(("sans-serif", 14))
+            .draw()?;
+        spectrogram_ctx.draw_series(std::iter::once(
+            BitMapElement::with_owned_buffer(
+                (0., max_frequency_hz),
+                (plot_width as u32, plot_height as u32),
+                pixels,
+            )
+            .context("Spectrogram buffer is the wrong size")?,
+        ))?;
+
+        // one stripe per pixel row of the bar, on the density scale used above
+        let saturation = highest_spectral_density / 4.;
+        let mut color_bar_ctx = ChartBuilder::on(&color_bar_area)
+            .margin(margin as u32)
+            .margin_bottom((margin + x_label_size) as u32)
+            .y_label_area_size(y_label_size as u32)
+            .build_cartesian_2d(0f32..1., 0f32..saturation.max(f32::EPSILON))?;
+        color_bar_ctx
+            .configure_mesh()
+            .disable_x_mesh()
+            .disable_y_mesh()
+            .disable_x_axis()
+            .y_desc("Magnitude")
+            .y_label_formatter(&|value| format!("{:.1e}", value))
+            .y_label_style(("sans-serif", 12))
+            .axis_desc_style(("sans-serif", 14))
+            .draw()?;
+        let stripes = plot_height.max(1);
+        color_bar_ctx.draw_series((0..stripes).map(|i| {
+            let low = saturation * i as f32 / stripes as f32;
+            let high = saturation * (i + 1) as f32 / stripes as f32;
+            let color = color_of((low + high) / 2.);
+            Rectangle::new(
+                [(0., low), (1., high)],
+                RGBColor(color.r, color.g, color.b).filled(),
+            )
+        }))?;
+
+        root.present()
+            .with_context(|| format!("Unable to write {}", output_path.display()))?;
+    });
+
+    Ok(())
 }
 
 // returns values, centred on x,y coord
-fn get_square(data: &Array2<f32>, x: usize, y: usize, width: usize) -> Option<ArrayView2<f32>> {
+fn get_square(data: &Array2<f32>, x: usize, y: usize, width: usize) -> Option<ArrayView2<'_, f32>> {
     let rows = data.nrows();
     let cols = data.ncols();
     if x > cols || y > rows {
         return None;
     }
 
-    let min_x = if x < width / 2 { 0 } else { x - width / 2 };
+    let min_x = x.saturating_sub(width / 2);
     let max_x = x + width / 2;
     let max_x = usize::min(max_x, cols - 1);
-    let min_y = if y < width / 2 { 0 } else { y - width / 2 };
+    let min_y = y.saturating_sub(width / 2);
     let max_y = y + width / 2;
     let max_y = usize::min(max_y, rows - 1);
 
-    // println!("x: {}, width/2: {}, rows: {}, cols: {}, {} {} {} {}", x, width/2, rows, cols, min_x, max_x, min_y, max_y);
+    // eprintln!("x: {}, width/2: {}, rows: {}, cols: {}, {} {} {} {}", x, width/2, rows, cols, min_x, max_x, min_y, max_y);
     // dbg!(x, y, width/2, rows, cols, min_x, max_x, min_y, max_y);
 
     Some(data.slice(s![min_y..=max_y, min_x..=max_x]))
@@ -65,17 +206,11 @@ pub fn max_filter(spec: &Array2<f32>, kernel_size: usize) -> Array2<f32> {
     let mut filtered = Array::zeros(spec.raw_dim());
     for x in 0..spec.ncols() {
         for y in 0..spec.nrows() {
-            let square = get_square(spec, x, y, kernel_size).unwrap();
-            let max = square.max();
-            match max {
-                Ok(_) => (),
-                Err(_) => {
-                    dbg!(x, y, kernel_size);
-                }
-            }
-            let pos = filtered.get_mut((y, x)).expect("out of bounds");
-            // let max = spec.get((x, y)).expect("out of bounds");
-            *pos = *max.unwrap();
+            let Some(square) = get_square(spec, x, y, kernel_size) else {
+                continue;
+            };
+            // folding rather than max() so a NaN can't stop the filter
+            filtered[(y, x)] = square.fold(f32::NEG_INFINITY, |max, &value| max.max(value));
         }
     }
     filtered
@@ -84,7 +219,7 @@ pub fn max_filter(spec: &Array2<f32>, kernel_size: usize) -> Array2<f32> {
 pub fn find_equal(array_a: &Array2<f32>, array_b: &Array2<f32>) -> Vec<(usize, usize)> {
     let mut locations = vec![];
     for (loc, elem) in array_a.indexed_iter() {
-        if *array_b.get(loc).unwrap() == *elem {
+        if array_b.get(loc) == Some(elem) {
             locations.push(loc);
         }
     }
@@ -92,57 +227,274 @@ pub fn find_equal(array_a: &Array2<f32>, array_b: &Array2<f32>) -> Vec<(usize, u
     locations
 }
 
+/// Plots the peak locations, with a line between each pair in `pairs`
 pub fn plot_peaks(
     peak_locations: &[(usize, usize)],
+    pairs: &[PeakLocationPair],
     height: usize,
     width: usize,
     window_length: f32,
+    format: PlotFormat,
     output_path: PathBuf,
 ) -> Result<(), anyhow::Error> {
-    println!("Plotting peaks");
-    dbg!(height, width);
-
     let frequency_resolution_hz = (1. / window_length) as usize;
     let image_vertical_scale = 2;
-    let root = BitMapBackend::new(
-        &output_path,
-        (width as u32 + 40, height as u32 / image_vertical_scale + 40),
-    )
-    .into_drawing_area();
-
-    root.fill(&WHITE)?;
-
-    let areas = root.split_by_breakpoints([width as u32 - 40], [40]);
-    dbg!(window_length, ((width as f32) * window_length) as usize);
-
-    let mut scatter_ctx = ChartBuilder::on(&areas[2])
-        .x_label_area_size(40)
-        .y_label_area_size(40)
-        .build_cartesian_2d(
-            0..((width as f32) * window_length) as usize,
-            0..height * frequency_resolution_hz / image_vertical_scale as usize,
-        )?;
-    scatter_ctx
-        .configure_mesh()
-        .disable_x_mesh()
-        .disable_y_mesh()
-        .y_desc("Frequency (Hz)")
-        .x_desc("Time (s)")
-        .draw()?;
-    scatter_ctx.draw_series(peak_locations.iter().map(|(x, y)| {
-        Circle::new(
+    let image_size = (width as u32 + 40, height as u32 / image_vertical_scale + 40);
+    with_root!(format, &output_path, image_size, |root| {
+        root.fill(&WHITE)?;
+
+        let areas = root.split_by_breakpoints([(width as u32).saturating_sub(40)], [40]);
+
+        let mut scatter_ctx = ChartBuilder::on(&areas[2])
+            .x_label_area_size(40)
+            .y_label_area_size(40)
+            .build_cartesian_2d(
+                0..((width as f32) * window_length) as usize,
+                0..height * frequency_resolution_hz / image_vertical_scale as usize,
+            )?;
+        scatter_ctx
+            .configure_mesh()
+            .disable_x_mesh()
+            .disable_y_mesh()
+            .y_desc("Frequency (Hz)")
+            .x_desc("Time (s)")
+            .draw()?;
+        let position = |(x, y): (usize, usize)| {
             (
-                ((*x as f32) * window_length) as usize,
-                *y * frequency_resolution_hz / image_vertical_scale as usize,
-            ),
-            2,
-            GREEN.filled(),
-        )
-    }))?;
+                ((x as f32) * window_length) as usize,
+                y * frequency_resolution_hz / image_vertical_scale as usize,
+            )
+        };
+        scatter_ctx.draw_series(
+            pairs
+                .iter()
+                .map(|&(a, b)| PathElement::new(vec![position(a), position(b)], BLACK.mix(0.2))),
+        )?;
+        scatter_ctx.draw_series(
+            peak_locations
+                .iter()
+                .map(|&location| Circle::new(position(location), 2, GREEN.filled())),
+        )?;
 
-    // To avoid the IO failure being ignored silently, we manually call the present function
-    root.present().expect("Unable to write result to file, please make sure 'plotters-doc-data' dir exists under current dir");
-    println!("Result has been saved to {}", output_path.to_string_lossy());
+        // To avoid the IO failure being ignored silently, we manually call the present function
+        root.present()
+            .with_context(|| format!("Unable to write {}", output_path.display()))?;
+    });
 
     Ok(())
 }
+
+/// Plots why a track matched: the (sample time, track time) of every hash shared with
+/// the track, where a true match lines up on a diagonal, next to the histogram of
+/// track-sample offsets with the candidate's offset marked
+pub fn plot_match_explanation(
+    explanation: &MatchExplanation,
+    title: &str,
+    offset: i64,
+    window_length: f32,
+    format: PlotFormat,
+    output_path: PathBuf,
+) -> Result<(), anyhow::Error> {
+    with_root!(format, &output_path, (1200, 500), |root| {
+        root.fill(&WHITE)?;
+        let (scatter_area, histogram_area) = root.split_horizontally(600);
+        let seconds = |time: i64| time as f32 * window_length;
+
+        let max_sample_time = explanation.points.iter().map(|p| p.0).max().unwrap_or(0);
+        let max_track_time = explanation.points.iter().map(|p| p.1).max().unwrap_or(0);
+        let mut scatter_ctx = ChartBuilder::on(&scatter_area)
+            .caption(
+                format!("Matching hashes with {}", title),
+                ("sans-serif", 20),
+            )
+            .margin(10)
+            .x_label_area_size(40)
+            .y_label_area_size(50)
+            .build_cartesian_2d(
+                0f32..seconds(max_sample_time as i64 + 1),
+                0f32..seconds(max_track_time as i64 + 1),
+            )?;
+        scatter_ctx
+            .configure_mesh()
+            .x_desc("Sample time (s)")
+            .y_desc("Track time (s)")
+            .draw()?;
+        scatter_ctx.draw_series(explanation.points.iter().map(|&(sample_time, track_time)| {
+            Circle::new(
+                (seconds(sample_time as i64), seconds(track_time as i64)),
+                2,
+                BLUE.filled(),
+            )
+        }))?;
+
+        let bin_width = explanation.bin_width.max(1) as i64;
+        // pad the range so a lone bin doesn't fill the whole plot
+        let padding = 5 * bin_width;
+        let first_offset = explanation.offset_histogram.first().map_or(0, |bin| bin.0) - padding;
+        let last_offset =
+            explanation.offset_histogram.last().map_or(0, |bin| bin.0) + bin_width + padding;
+        let max_count = explanation
+            .offset_histogram
+            .iter()
+            .map(|bin| bin.1)
+            .max()
+            .unwrap_or(0);
+        let mut histogram_ctx = ChartBuilder::on(&histogram_area)
+            .caption("Track - sample offsets", ("sans-serif", 20))
+            .margin(10)
+            .x_label_area_size(40)
+            .y_label_area_size(50)
+            .build_cartesian_2d(
+                seconds(first_offset)..seconds(last_offset),
+                0u32..max_count + 1,
+            )?;
+        histogram_ctx
+            .configure_mesh()
+            .disable_x_mesh()
+            .x_desc("Offset (s)")
+            .y_desc("Hashes")
+            .draw()?;
+        // the candidate's offset is the centre of its bin
+        let candidate_bin = (offset - bin_width / 2).div_euclid(bin_width) * bin_width;
+        histogram_ctx.draw_series(explanation.offset_histogram.iter().map(|&(bin, count)| {
+            let color = if bin == candidate_bin { RED } else { BLUE };
+            Rectangle::new(
+                [(seconds(bin), 0), (seconds(bin + bin_width), count)],
+                color.filled(),
+            )
+        }))?;
+
+        root.present()
+            .with_context(|| format!("Unable to write {}", output_path.display()))?;
+    });
+
+    Ok(())
+}
+
+/// Peaks of one recording and the pairs hashed from them, marking which pairs share a
+/// time-aligned hash with the recording it's compared to
+pub struct Constellation {
+    pub caption: String,
+    pub first_window: usize, // window of the recording shown at the left edge
+    pub peaks: Vec<(usize, usize)>,
+    pub pairs: Vec<PeakLocationPair>,
+    pub matched_pairs: Vec<bool>, // for each pair, whether it matched
+}
+
+/// Plots the query's constellation above the aligned segment of the reference, peaks
+/// coloured by whether they are part of a matching pair and pairs drawn as lines
+pub fn plot_peak_comparison(
+    query: &Constellation,
+    reference: &Constellation,
+    windows: usize, // length of both segments
+    height: usize,  // frequency bins
+    window_length: f32,
+    format: PlotFormat,
+    output_path: PathBuf,
+) -> Result<(), anyhow::Error> {
+    with_root!(format, &output_path, (1200, 900), |root| {
+        root.fill(&WHITE)?;
+        let (query_area, reference_area) = root.split_vertically(450);
+        let frequency_resolution_hz = 1. / window_length;
+
+        for (constellation, area) in [(query, &query_area), (reference, &reference_area)] {
+            let first_sec = constellation.first_window as f32 * window_length;
+            let mut ctx = ChartBuilder::on(area)
+                .caption(&constellation.caption, ("sans-serif", 20))
+                .margin(10)
+                .x_label_area_size(40)
+                .y_label_area_size(60)
+                .build_cartesian_2d(
+                    first_sec..first_sec + windows as f32 * window_length,
+                    0f32..height as f32 * frequency_resolution_hz,
+                )?;
+            ctx.configure_mesh()
+                .disable_x_mesh()
+                .disable_y_mesh()
+                .x_desc("Time (s)")
+                .y_desc("Frequency (Hz)")
+                .draw()?;
+
+            let position = |(time, freq): (usize, usize)| {
+                (
+                    time as f32 * window_length,
+                    freq as f32 * frequency_resolution_hz,
+                )
+            };
+            let in_view = |time: usize| {
+                (constellation.first_window..constellation.first_window + windows).contains(&time)
+            };
+
+            // unmatched pairs first so matched ones are drawn on top
+            let matched_color = RED.mix(0.8);
+            let unmatched_color = BLACK.mix(0.15);
+            let mut matched_peaks = std::collections::HashSet::new();
+            for matched in [false, true] {
+                let lines = constellation
+                    .pairs
+                    .iter()
+                    .zip(&constellation.matched_pairs)
+                    .filter(|&(&(a, b), &pair_matched)| {
+                        pair_matched == matched && in_view(a.0) && in_view(b.0)
+                    })
+                    .map(|(&(a, b), _)| {
+                        if matched {
+                            matched_peaks.insert(a);
+                            matched_peaks.insert(b);
+                        }
+                        let color = if matched {
+                            matched_color
+                        } else {
+                            unmatched_color
+                        };
+                        PathElement::new(vec![position(a), position(b)], color)
+                    })
+                    .collect::<Vec<_>>();
+                ctx.draw_series(lines)?;
+            }
+
+            ctx.draw_series(
+                constellation
+                    .peaks
+                    .iter()
+                    .filter(|peak| in_view(peak.0))
+                    .map(|&peak| {
+                        let color = if matched_peaks.contains(&peak) {
+                            RED.filled()
+                        } else {
+                            BLUE.mix(0.5).filled()
+                        };
+                        Circle::new(position(peak), 3, color)
+                    }),
+            )?;
+        }
+
+        root.present()
+            .with_context(|| format!("Unable to write {}", output_path.display()))?;
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peaks_of_short_recordings_can_be_plotted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("peaks.svg");
+        let peaks = [(1, 10), (3, 40)];
+        plot_peaks(
+            &peaks,
+            &[(peaks[0], peaks[1])],
+            512,
+            5,
+            0.1,
+            PlotFormat::Svg,
+            path.clone(),
+        )
+        .unwrap();
+        assert!(path.exists());
+    }
+}
@@ -0,0 +1,101 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    hash::PairRecord,
+    storage::{Fingerprint, SqliteStorage, Storage},
+    AnalysisParams,
+};
+
+/// Reference tracks and their fingerprints, kept in a [`Storage`]
+pub struct Index<S: Storage = SqliteStorage> {
+    storage: S,
+}
+
+impl Index {
+    /// Opens the SQLite database at `path`, creating it if it doesn't yet exist
+    pub fn open(path: &Path) -> Result<Self, anyhow::Error> {
+        Ok(Index::new(SqliteStorage::open(path)?))
+    }
+
+    /// Opens a temporary SQLite database that lives only in memory
+    pub fn in_memory() -> Result<Self, anyhow::Error> {
+        Ok(Index::new(SqliteStorage::in_memory()?))
+    }
+}
+
+impl<S: Storage> Index<S> {
+    pub fn new(storage: S) -> Self {
+        Index { storage }
+    }
+
+    /// Adds a track analysed from the audio at `path`, if there is audio, replacing any
+    /// track with the same title, which keeps its id. Returns the track id.
+    pub fn add_track(
+        &mut self,
+        title: &str,
+        path: Option<&Path>,
+        hashes: &HashMap<u32, PairRecord>,
+    ) -> Result<u32, anyhow::Error> {
+        // the path is recorded so the track can be analysed again when explaining matches
+        let path = path.map(|path| fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()));
+        self.storage.replace_track(title, path.as_deref(), hashes)
+    }
+
+    /// Removes the track with the title and its fingerprints. Returns whether there was one.
+    pub fn remove_track(&mut self, title: &str) -> Result<bool, anyhow::Error> {
+        match self.storage.find_track(title)? {
+            Some(track_id) => self.storage.delete_track(track_id),
+            None => Ok(false),
+        }
+    }
+
+    /// Fails if the database records analysis parameters other than `params`, since
+    /// fingerprints made with different parameters never match
+    pub fn check_params(&self, params: AnalysisParams) -> Result<(), anyhow::Error> {
+        match self.storage.params()? {
+            Some(recorded) if recorded != params => anyhow::bail!(
+                "The database was made with {:?}, not {:?}. Pass the parameters it was made with.",
+                recorded,
+                params
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks the database was made with `params`, recording them if it doesn't yet say,
+    /// before tracks made with them are added
+    pub fn record_params(&mut self, params: AnalysisParams) -> Result<(), anyhow::Error> {
+        self.check_params(params)?;
+        if self.storage.params()?.is_none() {
+            self.storage.set_params(params)?;
+        }
+        Ok(())
+    }
+
+    /// Every fingerprint of every track with one of the hashes
+    pub fn lookup(&self, hashes: &[u32]) -> Result<Vec<Fingerprint>, anyhow::Error> {
+        self.storage.lookup(hashes)
+    }
+
+    pub fn track_title(&self, track_id: u32) -> Result<Option<String>, anyhow::Error> {
+        Ok(self.storage.track(track_id)?.map(|track| track.title))
+    }
+
+    /// Path of the audio a track was added from, if known
+    pub fn track_path(&self, track_id: u32) -> Result<Option<PathBuf>, anyhow::Error> {
+        Ok(self.storage.track(track_id)?.and_then(|track| track.path))
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    /// Size of the storage in bytes
+    pub fn size_bytes(&self) -> Result<u64, anyhow::Error> {
+        self.storage.size_bytes()
+    }
+}
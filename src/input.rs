@@ -0,0 +1,237 @@
+//! Adding and matching the files given as input, which may be audio or fingerprint files
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::{
+    eval::StageTimings,
+    fingerprint_file::{self, FingerprintFile},
+    plots::PlotOptions,
+    report::MatchReport,
+    service::AddedTrack,
+    storage::Storage,
+    Fingerprinter, Index, MatchOptions, Matcher,
+};
+
+/// Lists the wav files given by a file, a directory or a glob pattern
+pub fn input_files(input: &Path) -> Result<Vec<PathBuf>, anyhow::Error> {
+    if input.is_file() {
+        return Ok(vec![input.to_path_buf()]);
+    }
+
+    // directories are searched for audio and fingerprint files
+    let glob_strings = if input.is_dir() {
+        ["wav", fingerprint_file::EXTENSION]
+            .iter()
+            .map(|extension| format!("{}/*.{}", input.to_string_lossy(), extension))
+            .collect()
+    } else {
+        vec![input.to_string_lossy().to_string()]
+    };
+    let mut files = vec![];
+    for glob_string in glob_strings {
+        for file in glob::glob(&glob_string).context("Error traversing directory")? {
+            files.push(file?);
+        }
+    }
+    if files.is_empty() {
+        anyhow::bail!("Please specify a sound file, directory or glob pattern of files.");
+    }
+
+    Ok(files)
+}
+
+/// A file added as a track
+pub struct AddedFile {
+    pub track: AddedTrack,
+    pub peaks: Option<(usize, usize)>, // found in so many windows, if the file was audio
+    pub plotted: bool,
+}
+
+/// A file matched against the index
+pub struct MatchedFile {
+    pub report: MatchReport,
+    pub plotted: bool,
+    pub uncompared: Vec<String>, // plotted candidates without audio to compare peaks with
+}
+
+/// Fingerprints a track and adds it to the index, saving plots if asked to. Only audio
+/// can be plotted. Fingerprint files keep the parameters they were made with, which
+/// must be those of the database, and audio is analysed with the fingerprinter's.
+pub fn add_file(
+    index: &mut Index<impl Storage>,
+    fingerprinter: &Fingerprinter,
+    input_wav: &Path,
+    plots: Option<&PlotOptions>,
+) -> Result<AddedFile, anyhow::Error> {
+    if fingerprint_file::is_fingerprint_file(input_wav) {
+        let fingerprints = FingerprintFile::read(input_wav)?;
+        index
+            .record_params(fingerprints.params)
+            .with_context(|| format!("Could not add {}.", input_wav.display()))?;
+        let hashes = fingerprints.hashes();
+        let track_id = index.add_track(&fingerprints.title, None, &hashes)?;
+        return Ok(AddedFile {
+            track: AddedTrack {
+                track_id,
+                title: fingerprints.title,
+                fingerprints: hashes.len(),
+            },
+            peaks: None,
+            plotted: false,
+        });
+    }
+
+    let wav_base_name = input_wav
+        .file_stem()
+        .context("Please provide a file not a directory.")?;
+
+    index.record_params(fingerprinter.params())?;
+    let analysis = fingerprinter.analyse_file(input_wav, &mut StageTimings::default())?;
+
+    if let Some(plots) = plots {
+        plots.save_analysis(wav_base_name, &analysis, fingerprinter)?;
+    }
+
+    // add track and its fingerprint to the index, replacing existing records
+    let track_name = wav_base_name.to_string_lossy().to_string();
+    let track_id = index.add_track(&track_name, Some(input_wav), &analysis.hashes)?;
+
+    Ok(AddedFile {
+        track: AddedTrack {
+            track_id,
+            title: track_name,
+            fingerprints: analysis.hashes.len(),
+        },
+        peaks: Some((analysis.peaks.len(), analysis.windows.nrows())),
+        plotted: plots.is_some(),
+    })
+}
+
+/// Fingerprints a sample and ranks the tracks it could be, saving plots of audio if
+/// asked to. The sample's parameters, those of its fingerprint file or else the fingerprinter's,
+/// must be those of the database.
+pub fn match_file(
+    matcher: &mut Matcher<impl Storage>,
+    fingerprinter: &Fingerprinter,
+    input_wav: &Path,
+    options: &MatchOptions,
+    plots: Option<&PlotOptions>,
+    timings: &mut StageTimings,
+) -> Result<MatchedFile, anyhow::Error> {
+    if fingerprint_file::is_fingerprint_file(input_wav) {
+        let fingerprints = FingerprintFile::read(input_wav)?;
+        matcher
+            .index()
+            .check_params(fingerprints.params)
+            .with_context(|| format!("Could not match {}.", input_wav.display()))?;
+        let (_, report) = matcher.match_hashes(
+            &input_wav.to_string_lossy(),
+            &fingerprints.hashes(),
+            fingerprints.params.window_length,
+            options,
+            timings,
+        )?;
+        return Ok(MatchedFile {
+            report,
+            plotted: false,
+            uncompared: vec![],
+        });
+    }
+
+    matcher.index().check_params(fingerprinter.params())?;
+    let outcome = matcher.match_file(fingerprinter, input_wav, options, timings)?;
+    let mut uncompared = vec![];
+    if let Some(plots) = plots {
+        let wav_base_name = input_wav
+            .file_stem()
+            .context("Please provide a file not a directory.")?;
+        uncompared = plots.save_match(wav_base_name, matcher, fingerprinter, &outcome, options)?;
+    }
+
+    Ok(MatchedFile {
+        report: outcome.report,
+        plotted: plots.is_some(),
+        uncompared,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        audio_ops,
+        storage::MemoryStorage,
+        test_audio::{tones, SAMPLE_RATE},
+        AnalysisParams,
+    };
+
+    /// Fingerprints of the samples made with `fingerprinter`, written to `dir`
+    fn fingerprint_file(
+        dir: &Path,
+        fingerprinter: &Fingerprinter,
+        title: &str,
+        samples: &[f32],
+    ) -> PathBuf {
+        let analysis = fingerprinter
+            .analyse_samples(samples, SAMPLE_RATE, &mut StageTimings::default())
+            .unwrap();
+        let path = dir.join(format!("{}.{}", title, fingerprint_file::EXTENSION));
+        FingerprintFile::new(fingerprinter, title, &analysis)
+            .write(&path)
+            .unwrap();
+        path
+    }
+
+    #[test]
+    fn fingerprint_files_are_checked_against_the_database_not_the_fingerprinter() {
+        let dir = tempfile::tempdir().unwrap();
+        let fingerprinter = Fingerprinter::new(AnalysisParams::default()).unwrap();
+        let other = Fingerprinter::new(AnalysisParams {
+            kernel_size: 20,
+            ..AnalysisParams::default()
+        })
+        .unwrap();
+        let track = tones(10, 1);
+
+        // the database takes the parameters of the first file added
+        let mut index = Index::new(MemoryStorage::default());
+        let added = fingerprint_file(dir.path(), &other, "a track", &track);
+        add_file(&mut index, &fingerprinter, &added, None).unwrap();
+        assert_eq!(index.storage().params().unwrap(), Some(other.params()));
+        let mismatched = fingerprint_file(dir.path(), &fingerprinter, "another", &tones(10, 2));
+        assert!(add_file(&mut index, &other, &mismatched, None).is_err());
+
+        let mut matcher = Matcher::new(index).unwrap();
+        let options = MatchOptions::default();
+        let mut timings = StageTimings::default();
+        let sample = fingerprint_file(dir.path(), &other, "sample", &track[8000..64000]);
+        let report = match_file(
+            &mut matcher,
+            &fingerprinter,
+            &sample,
+            &options,
+            None,
+            &mut timings,
+        )
+        .unwrap()
+        .report;
+        assert!(report.matched);
+        assert_eq!(report.results[0].title, "a track");
+
+        // audio is analysed with the fingerprinter's parameters, which must be the database's
+        let wav = dir.path().join("sample.wav");
+        audio_ops::write_wav_mono(&wav, &track[8000..64000], SAMPLE_RATE).unwrap();
+        assert!(match_file(
+            &mut matcher,
+            &fingerprinter,
+            &wav,
+            &options,
+            None,
+            &mut timings
+        )
+        .is_err());
+        assert!(match_file(&mut matcher, &other, &wav, &options, None, &mut timings).is_ok());
+    }
+}
@@ -0,0 +1,64 @@
+//! Audio fingerprinting and recognition.
+//!
+//! A [`Fingerprinter`] turns audio into hashes of pairs of peaks in its spectrogram,
+//! an [`Index`] stores the hashes of reference tracks and a [`Matcher`] finds the
+//! tracks a recording shares the most time-aligned hashes with.
+
+pub mod audio_ops;
+#[cfg(unix)]
+pub mod daemon;
+pub mod degrade;
+pub mod eval;
+pub mod ffi;
+pub mod fingerprint_file;
+pub mod fingerprinter;
+pub mod hash;
+pub mod http;
+pub mod image_ops;
+pub mod index;
+pub mod input;
+pub mod matching;
+pub mod monitor;
+pub mod plots;
+pub mod report;
+pub mod service;
+pub mod storage;
+pub mod stream;
+pub mod transfer;
+pub mod tune;
+mod varint;
+
+#[cfg(test)]
+mod test_audio;
+
+pub use fingerprinter::{Analysis, Fingerprinter};
+pub use index::Index;
+pub use matching::{MatchOptions, Matcher};
+pub use storage::Storage;
+
+/// Parameters of the analysis. Tracks and the samples matched against them must be
+/// analysed with the same parameters.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct AnalysisParams {
+    pub window_length: f32,       // in seconds
+    pub kernel_size: usize,       // used for maximum filter
+    pub magnitude_threshold: f32, // used for maximum filter
+
+    // matching target zone parameters
+    pub target_zone_delay_sec: f32,
+    pub target_zone_height_hz: f32,
+    pub target_zone_width_sec: f32,
+}
+
+impl Default for AnalysisParams {
+    fn default() -> Self {
+        AnalysisParams {
+            window_length: 0.1,
+            kernel_size: 30,
+            magnitude_threshold: 0.0,
+            target_zone_delay_sec: 0.1,
+            target_zone_height_hz: 750.0,
+            target_zone_width_sec: 3.0,
+        }
+    }
+}
@@ -1,47 +1,34 @@
 use anyhow::Context;
+use atlas::{
+    audio_ops, degrade, eval, fingerprint_file, http, image_ops, input, matching, monitor,
+    plots::PlotOptions, report, service, storage, stream, transfer, tune, AnalysisParams,
+    Fingerprinter, Index, MatchOptions, Matcher, Storage,
+};
 use clap::Parser;
-use ndarray::Array2;
-use rusqlite::params;
+use rand::{rngs::StdRng, SeedableRng};
 use std::{
-    collections::HashMap,
-    ffi::OsStr,
     fs,
+    io::Read,
     path::{Path, PathBuf},
+    process::ExitCode,
 };
 
-use crate::hash::PairRecord;
-mod audio_ops;
-mod database;
-mod hash;
-mod image_ops;
-
-#[derive(Debug, Clone, Copy)]
-struct AnalysisParams {
-    window_length: f32,       // in seconds
-    kernel_size: usize,       // used for maximum filter
-    magnitude_threshold: f32, // used for maximum filter
-
-    // matching target zone parameters
-    target_zone_delay_sec: f32,
-    target_zone_height_hz: f32,
-    target_zone_width_sec: f32,
-}
-
-struct TrackInfo {
-    track_id: u32,
-    title: String,
-}
-
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
     #[clap(subcommand)]
     action: Option<Action>,
 
+    /// Give more than once for a catalogue sharded across several databases, always in the
+    /// same order
     #[clap(short, long, parse(from_os_str), default_value = "database.db3")]
-    database: PathBuf,
+    database: Vec<PathBuf>,
+    /// How the database is stored. Each kind has its own file format, so pass the kind the
+    /// database was made with.
+    #[clap(long, arg_enum, default_value_t = storage::StorageKind::Sqlite)]
+    storage: storage::StorageKind,
     #[clap(short, long, parse(from_os_str), value_name = "FILE")]
-    input_wav: PathBuf,
+    input_wav: Option<PathBuf>,
 
     // analysis parameters
     #[clap(long, default_value_t = 0.1)]
@@ -54,302 +41,858 @@ struct Args {
     // matching target zone parameters
     #[clap(short, long, default_value_t = 0.1)]
     target_zone_delay_sec: f32,
-    #[clap(short, long, default_value_t = 750.0)]
+    #[clap(long, default_value_t = 750.0)]
     target_zone_height_hz: f32,
-    #[clap(short, long, default_value_t = 3.0)]
+    #[clap(long, default_value_t = 3.0)]
     target_zone_width_sec: f32,
+}
+
+/// How samples are scored against the tracks and which results are reported
+#[derive(clap::Args, Debug, Clone)]
+struct MatchArgs {
+    // offset histogram
+    #[clap(long, default_value_t = 1)]
+    offset_bin_width: u32, // in windows
+    #[clap(long, default_value_t = 1)]
+    offset_neighbours: u32, // bins either side summed into each bin's score
+
+    // matching decision
+    #[clap(long, default_value_t = 0.99)]
+    min_confidence: f64, // required 1 - p-value of the best track's score
+    #[clap(long, default_value_t = 5)]
+    top: usize, // number of ranked results to report
+}
 
-    // actions
+impl MatchArgs {
+    fn options(&self) -> MatchOptions {
+        MatchOptions {
+            binning: self.binning(),
+            top: self.top,
+            min_confidence: self.min_confidence,
+        }
+    }
+
+    fn binning(&self) -> matching::OffsetBinning {
+        matching::OffsetBinning {
+            bin_width: self.offset_bin_width,
+            neighbours: self.offset_neighbours,
+        }
+    }
+}
+
+/// Plots of the analysis to save while adding or matching
+#[derive(clap::Args, Debug, Clone)]
+struct PlotArgs {
     #[clap(short, long, action, default_value_t = false)]
     save_png: bool,
+    #[clap(long)]
+    plot_width: Option<u32>, // downsample spectrogram images to at most this many pixels wide
+    #[clap(long)]
+    plot_height: Option<u32>,
+    #[clap(long, parse(from_os_str))]
+    plot_dir: Option<PathBuf>, // save plots here, implies --save-png
+    #[clap(long, arg_enum, default_value_t = image_ops::PlotFormat::Png)]
+    plot_format: image_ops::PlotFormat,
+    #[clap(long, arg_enum, value_delimiter = ',')]
+    plots: Vec<image_ops::PlotKind>, // plots to save, all but pairs if not given
 }
 
-#[derive(clap::Subcommand, Debug, Clone, Copy)]
+impl PlotArgs {
+    /// Plot options, if any plots are to be saved. Creates the plot directory.
+    fn options(&self) -> Result<Option<PlotOptions>, anyhow::Error> {
+        if !self.save_png && self.plot_dir.is_none() {
+            return Ok(None);
+        }
+        let dir = self
+            .plot_dir
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("output"));
+        let size = image_ops::PlotSize {
+            max_width: self.plot_width,
+            max_height: self.plot_height,
+        };
+        PlotOptions::new(dir, self.plot_format, self.plots.clone(), size).map(Some)
+    }
+}
+
+/// Values of the analysis parameters to search, each defaulting to the single value
+/// the analysis is given
+#[derive(clap::Args, Debug, Clone)]
+struct SearchArgs {
+    #[clap(long, value_delimiter = ',')]
+    tune_window_lengths: Vec<f32>,
+    #[clap(long, value_delimiter = ',')]
+    tune_kernel_sizes: Vec<usize>,
+    #[clap(long, value_delimiter = ',')]
+    tune_magnitude_thresholds: Vec<f32>,
+    #[clap(long, value_delimiter = ',')]
+    tune_zone_delays: Vec<f32>,
+    #[clap(long, value_delimiter = ',')]
+    tune_zone_heights: Vec<f32>,
+    #[clap(long, value_delimiter = ',')]
+    tune_zone_widths: Vec<f32>,
+}
+
+impl SearchArgs {
+    /// The values to search, parameters not being searched keeping those of `params`
+    fn space(&self, params: AnalysisParams) -> tune::SearchSpace {
+        fn or_current<T: Clone>(values: &[T], current: T) -> Vec<T> {
+            if values.is_empty() {
+                vec![current]
+            } else {
+                values.to_vec()
+            }
+        }
+        tune::SearchSpace {
+            window_lengths: or_current(&self.tune_window_lengths, params.window_length),
+            kernel_sizes: or_current(&self.tune_kernel_sizes, params.kernel_size),
+            magnitude_thresholds: or_current(
+                &self.tune_magnitude_thresholds,
+                params.magnitude_threshold,
+            ),
+            target_zone_delays_sec: or_current(
+                &self.tune_zone_delays,
+                params.target_zone_delay_sec,
+            ),
+            target_zone_heights_hz: or_current(
+                &self.tune_zone_heights,
+                params.target_zone_height_hz,
+            ),
+            target_zone_widths_sec: or_current(
+                &self.tune_zone_widths,
+                params.target_zone_width_sec,
+            ),
+        }
+    }
+}
+
+#[derive(clap::Subcommand, Debug, Clone)]
 enum Action {
-    Add,
-    Match,
+    /// Fingerprint tracks and add them to the database
+    Add {
+        #[clap(flatten)]
+        plots: PlotArgs,
+    },
+    /// Match samples against the tracks in the database
+    Match {
+        #[clap(flatten)]
+        matching: MatchArgs,
+        #[clap(flatten)]
+        plots: PlotArgs,
+        #[clap(long, arg_enum, default_value_t = report::OutputFormat::Text)]
+        format: report::OutputFormat,
+    },
+    /// Match successive segments of a long recording to produce a timeline
+    Monitor {
+        #[clap(flatten)]
+        matching: MatchArgs,
+        #[clap(long, default_value_t = 10.0)]
+        segment_length: f32, // in seconds
+        #[clap(long, default_value_t = 5.0)]
+        segment_hop: f32, // in seconds
+        #[clap(long, arg_enum, default_value_t = report::OutputFormat::Text)]
+        format: report::OutputFormat,
+    },
+    /// Identify tracks in audio streamed to stdin (`-i -`) or a fifo as it arrives
+    Stream {
+        #[clap(flatten)]
+        matching: MatchArgs,
+        #[clap(long, arg_enum, default_value_t = audio_ops::PcmFormat::Wav)]
+        pcm_format: audio_ops::PcmFormat,
+        #[clap(long, default_value_t = 44100)]
+        sample_rate: u32, // of raw pcm input
+        #[clap(long, default_value_t = 1)]
+        channels: usize, // of raw pcm input
+        #[clap(long, default_value_t = 10.0)]
+        segment_length: f32, // in seconds of audio decided on
+        #[clap(long, default_value_t = 1.0)]
+        decision_interval: f32, // in seconds of audio
+        #[clap(long, value_delimiter = ',')]
+        thresholds: Vec<f64>, // confidences to report events at, the minimum confidence if not given
+        #[clap(long, arg_enum, default_value_t = report::OutputFormat::Text)]
+        format: report::OutputFormat,
+    },
+    /// Measure accuracy on labelled queries, from `--labels` or named `<track>@<offset>.wav`
+    Eval {
+        #[clap(long, parse(from_os_str), value_name = "CSV")]
+        labels: Option<PathBuf>, // columns query, expected_track, expected_offset
+        #[clap(long, value_delimiter = ',', default_value = "0.5,0.9,0.99,0.999")]
+        thresholds: Vec<f64>, // confidences to report accuracy at
+        #[clap(flatten)]
+        matching: MatchArgs,
+        #[clap(flatten)]
+        plots: PlotArgs,
+        #[clap(long, arg_enum, default_value_t = report::OutputFormat::Text)]
+        format: report::OutputFormat,
+    },
+    /// Write randomly placed, degraded excerpts of the input tracks with their labels
+    Degrade {
+        #[clap(long, parse(from_os_str), default_value = "clips")]
+        clip_dir: PathBuf,
+        #[clap(long, default_value_t = 5)]
+        clips_per_track: usize,
+        #[clap(long, default_value_t = 8.0)]
+        clip_length: f32, // in seconds
+        #[clap(long, arg_enum, default_value_t = degrade::NoiseKind::None)]
+        noise: degrade::NoiseKind,
+        #[clap(long, default_value_t = 10.0)]
+        snr: f32, // signal to noise ratio of added noise, in dB
+        #[clap(long)]
+        clip_level: Option<f32>, // fraction of full scale to clip samples at
+        #[clap(long)]
+        lowpass: Option<f32>, // cutoff in Hz
+        #[clap(long)]
+        highpass: Option<f32>, // cutoff in Hz
+        #[clap(long, default_value_t = 0.0)]
+        gain_db: f32, // largest random gain change, in dB
+        #[clap(long, default_value_t = 0.0)]
+        speed_change: f32, // largest random relative change in playback speed
+        #[clap(long)]
+        seed: Option<u64>, // for reproducible clips
+    },
+    /// Search analysis parameters using the input tracks as references and labelled queries
+    Tune {
+        #[clap(long, parse(from_os_str), value_name = "CSV")]
+        labels: Option<PathBuf>, // columns query, expected_track, expected_offset
+        #[clap(long, parse(from_os_str))]
+        queries: Option<PathBuf>, // queries named <track>@<offset>.wav, if no labels are given
+        #[clap(flatten)]
+        search: SearchArgs,
+        #[clap(long)]
+        random_trials: Option<usize>, // try this many settings sampled from the ranges the values span
+        #[clap(long)]
+        seed: Option<u64>, // for reproducible random trials
+        #[clap(flatten)]
+        matching: MatchArgs,
+        #[clap(long, arg_enum, default_value_t = report::OutputFormat::Text)]
+        format: report::OutputFormat,
+    },
+    /// Serve matching and adding tracks over HTTP, keeping the database open
+    Serve {
+        #[clap(long, default_value = "127.0.0.1:8080")]
+        bind: String, // address to listen on
+        #[clap(long, default_value_t = 4)]
+        workers: usize, // threads serving requests, each with its own handle on the database
+        #[clap(long, default_value_t = 1000)]
+        lookups: usize, // stored hashes looked up to time lookups for stats
+        #[clap(flatten)]
+        matching: MatchArgs,
+    },
+    /// Answer newline-delimited JSON requests on a Unix domain socket
+    #[cfg(unix)]
+    Daemon {
+        #[clap(long, parse(from_os_str), default_value = "atlas.sock")]
+        socket: PathBuf,
+        #[clap(long, default_value_t = 4)]
+        workers: usize, // readers, each with its own handle on the database
+        #[clap(long, default_value_t = 1000)]
+        lookups: usize, // stored hashes looked up to time lookups for stats
+        #[clap(flatten)]
+        matching: MatchArgs,
+    },
+    /// Write the peak pairs of the input audio to fingerprint files, which `add` and
+    /// `match` accept in place of audio
+    Fingerprint {
+        #[clap(short, long, parse(from_os_str), default_value = "fingerprints")]
+        output_dir: PathBuf,
+    },
+    /// Report the size of the database, its compression and how fast lookups are
+    Stats {
+        #[clap(long, default_value_t = 1000)]
+        lookups: usize, // stored hashes looked up to time lookups
+        #[clap(long, arg_enum, default_value_t = report::OutputFormat::Text)]
+        format: report::OutputFormat,
+    },
+    /// Manage inverted indexes of the database
+    #[clap(subcommand)]
+    Index(IndexAction),
+    /// Combine, export and import databases
+    #[clap(subcommand)]
+    Db(DbAction),
 }
 
-fn save_plots(
-    wav_base_name: &OsStr,
-    unfiltered_windows: Array2<f32>,
-    filtered_windows: Array2<f32>,
-    window_length: f32,
-    max_peak_locations: &[(usize, usize)],
-) -> Result<(), anyhow::Error> {
-    // save image files of output if requested
-    let output_dir = Path::new("output");
-    fs::create_dir_all(output_dir)?;
-
-    let mut output_name = wav_base_name.to_os_string();
-    output_name.push("_spec.png");
-    let mut output_name_max = wav_base_name.to_os_string();
-    output_name_max.push("_spec_max.png");
-    let out_path = output_dir.join(output_name);
-    let out_path_max = output_dir.join(output_name_max);
-
-    image_ops::save_png(&unfiltered_windows, out_path);
-    image_ops::save_png(&filtered_windows, out_path_max);
-
-    let mut peaks_filename = wav_base_name.to_os_string();
-    peaks_filename.push("_peaks.png");
-    image_ops::plot_peaks(
-        &max_peak_locations,
-        unfiltered_windows.ncols(),
-        unfiltered_windows.nrows(),
-        window_length,
-        output_dir.join(peaks_filename),
-    )
-    .context(format!("Unable to plot peaks"))?;
+#[derive(clap::Subcommand, Debug, Clone)]
+enum DbAction {
+    /// Add the tracks of other databases to the database, skipping those it already has
+    Merge {
+        #[clap(parse(from_os_str), required = true)]
+        sources: Vec<PathBuf>,
+        /// What to do with tracks titled like a track with other fingerprints
+        #[clap(long, arg_enum, default_value_t = transfer::TitleConflict::Reject)]
+        on_title_conflict: transfer::TitleConflict,
+    },
+    /// Write the tracks and fingerprints of the database to a portable file, with the
+    /// analysis parameters it records, or those given if it was made before they were
+    /// recorded
+    Export {
+        #[clap(short, long, parse(from_os_str))]
+        output: PathBuf,
+    },
+    /// Add the tracks of an export to the database, skipping those it already has
+    Import {
+        #[clap(parse(from_os_str))]
+        input: PathBuf,
+        /// What to do with tracks titled like a track with other fingerprints
+        #[clap(long, arg_enum, default_value_t = transfer::TitleConflict::Reject)]
+        on_title_conflict: transfer::TitleConflict,
+    },
+}
 
-    Ok(())
+#[derive(clap::Subcommand, Debug, Clone)]
+enum IndexAction {
+    /// Compile the database into an immutable inverted index, matched with `--storage inverted`
+    Build {
+        #[clap(short, long, parse(from_os_str))]
+        output: PathBuf,
+    },
 }
 
-fn add(args: &Args) -> Result<(), anyhow::Error> {
-    let params = AnalysisParams {
+/// The input file or directory, which every action but `index` needs
+fn input_wav(args: &Args) -> Result<&Path, anyhow::Error> {
+    args.input_wav
+        .as_deref()
+        .context("Please provide an input with -i.")
+}
+
+/// Opens the database with the storage asked for, checking it was made with the
+/// analysis parameters given
+fn open_index(args: &Args) -> Result<Index<Box<dyn Storage>>, anyhow::Error> {
+    let index = open_unchecked_index(args)?;
+    index.check_params(analysis_params(args))?;
+    Ok(index)
+}
+
+/// Opens the database for adding or matching input files, which are each checked against
+/// its analysis parameters, as fingerprint files bring their own
+fn open_unchecked_index(args: &Args) -> Result<Index<Box<dyn Storage>>, anyhow::Error> {
+    Ok(Index::new(open_storage(args)?))
+}
+
+/// Opens the database, or the shards of the catalogue if there are several
+fn open_storage(args: &Args) -> Result<Box<dyn Storage>, anyhow::Error> {
+    storage::open_catalogue(args.storage, &args.database)
+}
+
+fn analysis_params(args: &Args) -> AnalysisParams {
+    AnalysisParams {
         window_length: args.window_length,
         kernel_size: args.kernel_size,
         magnitude_threshold: args.magnitude_threshold,
         target_zone_delay_sec: args.target_zone_delay_sec,
         target_zone_height_hz: args.target_zone_height_hz,
         target_zone_width_sec: args.target_zone_width_sec,
-    };
+    }
+}
+
+/// A random number generator, seeded if a seed is given
+fn rng(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    }
+}
 
-    if args.input_wav.is_file() {
-        add_file(&args.input_wav, args.save_png, &args.database, params)
-    } else if args.input_wav.is_dir() {
-        let mut glob_string = args.input_wav.to_string_lossy().to_string();
-        glob_string.push_str("/*.wav");
-        for entry in glob::glob(&glob_string).context("Error traversing directory")? {
-            let entry = entry?;
-            println!("\nAdding {}", entry.display());
-            add_file(&entry, args.save_png, &args.database, params)?;
+fn add(args: &Args, plots: &PlotArgs) -> Result<(), anyhow::Error> {
+    let fingerprinter = Fingerprinter::new(analysis_params(args))?;
+    let mut index = open_unchecked_index(args)?;
+    let plots = plots.options()?;
+
+    let input = input_wav(args)?;
+    let batch = !input.is_file();
+    let inputs = match batch {
+        true => input::input_files(input)?,
+        false => vec![input.to_path_buf()],
+    };
+    for input in inputs {
+        if batch {
+            eprintln!("\nAdding {}", input.display());
         }
+        let added = input::add_file(&mut index, &fingerprinter, &input, plots.as_ref())?;
+        if let Some((peaks, windows)) = added.peaks {
+            eprintln!("Found {} peaks in {} windows", peaks, windows);
+        }
+        eprintln!(
+            "Track {} added with id {} and {} fingerprints",
+            added.track.title, added.track.track_id, added.track.fingerprints
+        );
+        note_plots(&input, plots.as_ref(), added.plotted, &[]);
+    }
+
+    Ok(())
+}
 
-        Ok(())
-    } else {
-        panic!("Please specify a sound file or directory of files to add.");
+/// Tells where the plots of an input were saved, or why they weren't
+fn note_plots(input: &Path, plots: Option<&PlotOptions>, plotted: bool, uncompared: &[String]) {
+    let Some(plots) = plots else {
+        return;
+    };
+    if !plotted {
+        eprintln!("No plots of {}, plots need audio", input.display());
+        return;
+    }
+    for title in uncompared {
+        eprintln!("No audio recorded for {}, not comparing peaks", title);
     }
+    eprintln!("Plots saved to {}", plots.dir.display());
 }
 
-fn add_file(
-    input_wav: &Path,
-    save_png: bool,
-    database_path: &Path,
-    analysis_params: AnalysisParams,
-) -> Result<(), anyhow::Error> {
-    let wav_base_name = input_wav
-        .file_stem()
-        .context("Please provide a file not a directory.")?;
-
-    let windows = audio_ops::read_wav_to_fft(input_wav, analysis_params.window_length)?;
-    let filtered = image_ops::max_filter(&windows, analysis_params.kernel_size);
-
-    // find peak locations
-    println!("Finding peak locations");
-    let peak_locations = image_ops::find_equal(&windows, &filtered);
-    // filter for only peaks bigger than magnitude threshold
-    let max_peak_locations: Vec<(usize, usize)> = peak_locations
-        .iter()
-        .filter(|&&loc| *windows.get(loc).unwrap() > analysis_params.magnitude_threshold)
-        .map(|&loc| loc)
-        .collect();
-    println!("Found {} peaks", max_peak_locations.len());
-
-    if save_png {
-        save_plots(
-            wav_base_name,
-            windows,
-            filtered,
-            analysis_params.window_length,
-            &max_peak_locations,
+/// Matches a sample, or a batch of samples given by a directory or glob pattern,
+/// against the database and reports the results. A batch reports one row per sample,
+/// carrying on past samples that can't be matched.
+/// Returns the exit code: no match if any sample didn't match, error if any failed.
+fn match_samples(
+    args: &Args,
+    matching: &MatchArgs,
+    plots: &PlotArgs,
+    format: report::OutputFormat,
+) -> Result<u8, anyhow::Error> {
+    let fingerprinter = Fingerprinter::new(analysis_params(args))?;
+    let mut matcher = Matcher::new(open_unchecked_index(args)?)?;
+    let options = matching.options();
+    let plots = plots.options()?;
+
+    let input = input_wav(args)?;
+    if input.is_file() {
+        let matched = input::match_file(
+            &mut matcher,
+            &fingerprinter,
+            input,
+            &options,
+            plots.as_ref(),
+            &mut eval::StageTimings::default(),
         )?;
+        note_plots(input, plots.as_ref(), matched.plotted, &matched.uncompared);
+        report::write_report(&matched.report, format, std::io::stdout().lock())?;
+        return Ok(matched.report.exit_code());
     }
 
-    // add track to track list
-    let mut conn = database::connect(database_path)?;
-    let track_name = wav_base_name.to_string_lossy().to_string();
-    let track_id = database::add_track(&conn, &track_name)?;
-    println!("Track {} added with id {}", track_name, track_id);
-
-    // generate fingerprint
-    println!("Fingerprinting");
-    let pair_records = hash::fingerprint(
-        &max_peak_locations,
-        analysis_params.window_length,
-        analysis_params.target_zone_delay_sec,
-        analysis_params.target_zone_height_hz,
-        analysis_params.target_zone_width_sec,
-    );
-    println!("Done fingerprinting");
-    let sum: u32 = pair_records.keys().sum();
-    let ave: f32 = (sum as f32) / (pair_records.len() as f32);
-    println!("{:?}", ave);
-
-    // add fingerprint to database, deleting existing records
-    let transaction = conn.transaction()?;
-    {
-        let mut delete_statement =
-            transaction.prepare("DELETE FROM fingerprints WHERE track_id = (?1)")?;
-        delete_statement.execute([track_id])?;
-
-        let mut insert_statement = transaction
-            .prepare("INSERT INTO fingerprints (hash, track_time, track_id) values (?1, ?2, ?3)")?;
-        for (hash, record) in &pair_records {
-            insert_statement
-                .execute(&[
-                    &hash.to_string(),
-                    &record.time_a.to_string(),
-                    &track_id.to_string(),
-                ])
-                .context("Failed to insert.")?;
-        }
+    let mut row_writer = report::RowWriter::new(format, std::io::stdout().lock());
+    let mut exit_code = report::EXIT_MATCH;
+    let mut timings = eval::StageTimings::default();
+    for input in input::input_files(input)? {
+        eprintln!("\nMatching {}", input.display());
+        let matched = input::match_file(
+            &mut matcher,
+            &fingerprinter,
+            &input,
+            &options,
+            plots.as_ref(),
+            &mut timings,
+        );
+        let row = match matched {
+            Ok(matched) => {
+                note_plots(&input, plots.as_ref(), matched.plotted, &matched.uncompared);
+                report::QueryRow::from_report(&matched.report)
+            }
+            Err(err) => report::QueryRow::from_error(input.to_string_lossy().to_string(), &err),
+        };
+        exit_code = exit_code.max(row.exit_code());
+        row_writer.write(&row)?;
     }
-    transaction.commit()?;
-    println!("Inserted {} fingerprints", pair_records.len());
 
-    Ok(())
+    Ok(exit_code)
 }
 
-fn match_sample(args: &Args) -> Result<(), anyhow::Error> {
-    let windows = audio_ops::read_wav_to_fft(&args.input_wav, args.window_length)?;
-    let filtered = image_ops::max_filter(&windows, args.kernel_size);
-
-    // find peak locations
-    println!("Finding peak locations");
-    let peak_locations = image_ops::find_equal(&windows, &filtered);
-    // filter for only peaks bigger than magnitude threshold
-    let max_peak_locations: Vec<(usize, usize)> = peak_locations
-        .iter()
-        .filter(|&&loc| *windows.get(loc).unwrap() > args.magnitude_threshold)
-        .map(|&loc| loc)
-        .collect();
-    println!("Found {} peaks", max_peak_locations.len());
-
-    // generate fingerprint
-    let pair_records = hash::fingerprint(
-        &max_peak_locations,
-        args.window_length,
-        args.target_zone_delay_sec,
-        args.target_zone_height_hz,
-        args.target_zone_width_sec,
-    );
+/// A handle on the database for writing and handles for reading
+type Handles = (Index<Box<dyn Storage>>, Vec<Matcher<Box<dyn Storage>>>);
 
-    if args.save_png {
-        let wav_base_name = args
-            .input_wav
+/// Opens the database once for the writer and once for each of `readers`, for servers
+/// reading while they write
+fn open_handles(args: &Args, readers: usize) -> Result<Handles, anyhow::Error> {
+    if args.storage == storage::StorageKind::Kv {
+        anyhow::bail!(
+            "Key-value databases can only be opened once, so can't be served. Import an export of it into another kind of database."
+        );
+    }
+    let writer = open_index(args)?;
+    let readers = (0..readers.max(1))
+        .map(|_| Matcher::new(open_index(args)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((writer, readers))
+}
+
+/// Writes a fingerprint file of each input to `output_dir`, named after the audio
+fn write_fingerprints(args: &Args, output_dir: &Path) -> Result<(), anyhow::Error> {
+    let fingerprinter = Fingerprinter::new(analysis_params(args))?;
+    fs::create_dir_all(output_dir).context("Could not create fingerprint directory.")?;
+    for input in input::input_files(input_wav(args)?)? {
+        if fingerprint_file::is_fingerprint_file(&input) {
+            continue;
+        }
+        let title = input
             .file_stem()
-            .context("Please provide a file not a directory.")?;
-
-        save_plots(
-            wav_base_name,
-            windows,
-            filtered,
-            args.window_length,
-            &max_peak_locations,
-        )?;
+            .context("Please provide a file not a directory.")?
+            .to_string_lossy()
+            .to_string();
+        let analysis = fingerprinter.analyse_file(&input, &mut eval::StageTimings::default())?;
+        let fingerprints =
+            fingerprint_file::FingerprintFile::new(&fingerprinter, &title, &analysis);
+        // the whole title, which may itself contain dots
+        let output = output_dir.join(format!("{}.{}", title, fingerprint_file::EXTENSION));
+        fingerprints.write(&output)?;
+        eprintln!(
+            "Wrote {} peak pairs of {} to {}",
+            fingerprints.pairs.len(),
+            input.display(),
+            output.display()
+        );
     }
+    Ok(())
+}
 
-    // retrieve all fingerprints with a matching hash, grouped by track_id
-    // for each track id, for each matching hash, calculate track_time-sample_time
-    // keep track of the number of instances of that time difference in a hash map
-    // once done a track, find bin with highest count. If high enough, a match has been found.
-    // if not, continue to next track
-    // if done all tracks, return no match
-    let conn = database::connect(&args.database)?;
-    rusqlite::vtab::array::load_module(&conn)?;
-
-    let hashes = std::rc::Rc::new(
-        pair_records
-            .keys()
-            .copied()
-            .map(rusqlite::types::Value::from)
-            .collect::<Vec<rusqlite::types::Value>>(),
+/// Compiles the database into an inverted index file. An index built from one shard of
+/// a catalogue is a shard in the same position.
+fn build_index(args: &Args, output: &Path) -> Result<(), anyhow::Error> {
+    let source = match &args.database[..] {
+        [database] => storage::open(args.storage, database)?,
+        _ => open_storage(args)?,
+    };
+    let summary = storage::InvertedIndex::build(&source, output)?;
+    eprintln!(
+        "Indexed {} tracks, {} hashes and {} fingerprints into {} ({} bytes)",
+        summary.tracks,
+        summary.hashes,
+        summary.postings,
+        output.display(),
+        summary.bytes
     );
+    Ok(())
+}
 
-    // find tracks that have at least one match
-    let mut track_query =
-        conn.prepare("SELECT DISTINCT track_id FROM fingerprints WHERE hash IN rarray(?1)")?;
-    let candidate_ids = track_query
-        .query_map(params![hashes], |row| row.get::<_, u32>(0))?
-        .map(|res| res.unwrap());
-    // get titles
-    let candidate_ids = std::rc::Rc::new(
-        candidate_ids
-            .map(rusqlite::types::Value::from)
-            .collect::<Vec<rusqlite::types::Value>>(),
-    );
-    let mut track_info_query =
-        conn.prepare("SELECT id, title FROM tracks WHERE id IN rarray(?1)")?;
-    let candidate_tracks = track_info_query.query_map(params![candidate_ids], |row| {
-        Ok(TrackInfo {
-            track_id: row.get::<_, u32>(0)?,
-            title: row.get::<_, String>(1)?,
-        })
-    })?;
-
-    println!("\nMatching fingerprints against the database:");
-    for track_info in candidate_tracks {
-        // find hash matches and bin based on track-sample time offset
-        let track_info = track_info?;
-        let track_id = track_info.track_id;
-
-        let mut hash_query = conn.prepare("SELECT hash, track_time FROM fingerprints WHERE track_id = (?1) AND hash IN rarray(?2)")?;
-        let rows = hash_query.query_map(params![track_id, hashes], |row| {
-            let hash = row.get::<_, u32>(0);
-            let track_time = row.get::<_, u32>(1);
-            Ok(PairRecord {
-                hash: hash?,
-                time_a: track_time?,
-            })
-        })?;
-        let track_times = rows
-            .into_iter()
-            .collect::<Result<Vec<PairRecord>, rusqlite::Error>>()?;
-
-        let mut time_bins = HashMap::new();
-
-        for track_record in track_times {
-            let track_time = track_record.time_a;
-            let hash = track_record.hash;
-            let sample_time = pair_records
-                .get(&hash)
-                .context("Erroneous hash returned")?
-                .time_a;
-
-            if track_time >= sample_time {
-                // matches are only possible in this case
-                let match_offset = track_time - sample_time;
-                *time_bins.entry(match_offset).or_insert(0) += 1;
+/// Merges, exports or imports the database. Databases that don't record the analysis
+/// parameters they were made with are taken to have been made with those given.
+fn database(args: &Args, action: &DbAction) -> Result<(), anyhow::Error> {
+    let mut target = open_storage(args)?;
+    let assumed = analysis_params(args);
+    match action {
+        DbAction::Merge {
+            sources,
+            on_title_conflict,
+        } => {
+            for source in sources {
+                let summary = transfer::merge(
+                    &mut target,
+                    &storage::open(args.storage, source)?,
+                    assumed,
+                    *on_title_conflict,
+                )
+                .with_context(|| format!("Could not merge {}.", source.display()))?;
+                report_transfer(&format!("Merged {}", source.display()), &summary);
             }
         }
-
-        let (&best_offset, count) = time_bins.iter().max_by_key(|entry| entry.1).unwrap();
-        let best_offset_time = best_offset as f32 * args.window_length;
-        println!("track_id: {:2.}, best offset: {:>3}s, match score: {:3.}, name: {}", track_id, best_offset_time as u32, count, track_info.title);
+        DbAction::Export { output } => {
+            let file = fs::File::create(output)
+                .with_context(|| format!("Could not create {}.", output.display()))?;
+            let tracks = transfer::export(&target, assumed, std::io::BufWriter::new(file))?;
+            eprintln!("Exported {} tracks to {}", tracks, output.display());
+        }
+        DbAction::Import {
+            input,
+            on_title_conflict,
+        } => {
+            let file = fs::File::open(input)
+                .with_context(|| format!("Could not open {}.", input.display()))?;
+            let summary = transfer::import(
+                &mut target,
+                assumed,
+                *on_title_conflict,
+                std::io::BufReader::new(file),
+            )?;
+            report_transfer(&format!("Imported {}", input.display()), &summary);
+        }
     }
-
     Ok(())
 }
 
-fn main() -> Result<(), anyhow::Error> {
-    let args = Args::parse();
+fn report_transfer(done: &str, summary: &transfer::TransferSummary) {
+    eprintln!(
+        "{}: {} tracks added, {} already present",
+        done, summary.added, summary.duplicates
+    );
+    if summary.renamed > 0 {
+        eprintln!(
+            "{} tracks renamed as their titles were taken",
+            summary.renamed
+        );
+    }
+    if summary.replaced > 0 {
+        eprintln!(
+            "{} tracks replaced by tracks with their titles",
+            summary.replaced
+        );
+    }
+}
 
-    if let Some(action) = args.action {
-        match action {
-            Action::Add => {
-                println!("Adding track(s) to database.");
-                add(&args)?;
+fn run(args: &Args) -> Result<u8, anyhow::Error> {
+    let Some(action) = &args.action else {
+        let path = input_wav(args)?;
+        println!("{}", path.is_dir());
+        return Ok(report::EXIT_MATCH);
+    };
+
+    match action {
+        Action::Add { plots } => {
+            eprintln!("Adding track(s) to database.");
+            add(args, plots)?;
+        }
+        Action::Match {
+            matching,
+            plots,
+            format,
+        } => {
+            eprintln!("Attempting to match sample(s) to existing tracks");
+            return match_samples(args, matching, plots, *format);
+        }
+        Action::Monitor {
+            matching,
+            segment_length,
+            segment_hop,
+            format,
+        } => {
+            eprintln!("Monitoring input for tracks in the database");
+            let params = Fingerprinter::new(analysis_params(args))?.params();
+            let segmenting = monitor::Segmenting {
+                length_sec: *segment_length,
+                hop_sec: *segment_hop,
+            };
+            let timeline = monitor::monitor(
+                &mut Matcher::new(open_index(args)?)?,
+                params,
+                &mut audio_ops::PcmReader::open_wav(input_wav(args)?)?,
+                segmenting,
+                &matching.options(),
+            )?;
+            report::write_timeline(&timeline, *format, std::io::stdout().lock())?;
+            if timeline.is_empty() {
+                return Ok(report::EXIT_NO_MATCH);
             }
-            Action::Match => {
-                println!("Attempting to match sample to existing tracks");
-                match_sample(&args)?;
+        }
+        Action::Stream {
+            matching,
+            pcm_format,
+            sample_rate,
+            channels,
+            segment_length,
+            decision_interval,
+            thresholds,
+            format,
+        } => {
+            eprintln!("Listening for tracks in the database");
+            let input = input_wav(args)?;
+            let reader: Box<dyn Read> = if input == Path::new("-") {
+                Box::new(std::io::stdin())
+            } else {
+                Box::new(fs::File::open(input).context("Could not open stream for reading.")?)
+            };
+            let mut pcm_reader =
+                audio_ops::PcmReader::new(reader, *pcm_format, *sample_rate, *channels)?;
+            let options = stream::StreamOptions {
+                segment_length: *segment_length,
+                decision_interval: *decision_interval,
+                binning: matching.binning(),
+                thresholds: if thresholds.is_empty() {
+                    vec![matching.min_confidence]
+                } else {
+                    thresholds.clone()
+                },
+            };
+            let mut event_writer = report::RowWriter::new(*format, std::io::stdout().lock());
+            let identified = stream::identify(
+                &mut pcm_reader,
+                analysis_params(args),
+                &mut Matcher::new(open_index(args)?)?,
+                &options,
+                |event| event_writer.write(&event),
+            )?;
+            if !identified {
+                return Ok(report::EXIT_NO_MATCH);
             }
         }
-    } else {
-        let path = args.input_wav;
-        println!("{}", path.is_dir());
+        Action::Eval {
+            labels,
+            thresholds,
+            matching,
+            plots,
+            format,
+        } => {
+            eprintln!("Evaluating recognition accuracy");
+            let labels = eval::find_labels(labels.as_deref(), args.input_wav.as_deref())?;
+            let plots = plots.options()?;
+            let summary = eval::evaluate(
+                &mut Matcher::new(open_unchecked_index(args)?)?,
+                &Fingerprinter::new(analysis_params(args))?,
+                labels,
+                &matching.options(),
+                thresholds,
+                plots.as_ref(),
+                |query, matched| {
+                    eprintln!("\nEvaluated {}", query.display());
+                    if let Ok(matched) = matched {
+                        note_plots(query, plots.as_ref(), matched.plotted, &matched.uncompared);
+                    }
+                },
+            );
+            report::write_eval_summary(&summary, *format, std::io::stdout().lock())?;
+        }
+        Action::Degrade {
+            clip_dir,
+            clips_per_track,
+            clip_length,
+            noise,
+            snr,
+            clip_level,
+            lowpass,
+            highpass,
+            gain_db,
+            speed_change,
+            seed,
+        } => {
+            eprintln!("Generating degraded clips");
+            let ranges = degrade::DegradationRanges {
+                noise: *noise,
+                snr_db: *snr,
+                clip_level: *clip_level,
+                lowpass_hz: *lowpass,
+                highpass_hz: *highpass,
+                max_gain_db: *gain_db,
+                max_speed_change: *speed_change,
+            };
+            degrade::write_clips(
+                &input::input_files(input_wav(args)?)?,
+                clip_dir,
+                *clip_length,
+                *clips_per_track,
+                &ranges,
+                &mut rng(*seed),
+                |track| eprintln!("\nGenerating clips from {}", track.display()),
+            )?;
+            eprintln!("Clips and labels.csv written to {}", clip_dir.display());
+        }
+        Action::Tune {
+            labels,
+            queries,
+            search,
+            random_trials,
+            seed,
+            matching,
+            format,
+        } => {
+            eprintln!("Tuning analysis parameters");
+            let references = input::input_files(input_wav(args)?)?;
+            let labels = eval::find_labels(labels.as_deref(), queries.as_deref())?;
+            let space = search.space(analysis_params(args));
+            let trials = match random_trials {
+                Some(trials) => space.random(*trials, &mut rng(*seed)),
+                None => space.grid(),
+            };
+            let results = tune::run_trials(
+                &references,
+                &labels,
+                trials,
+                &matching.options(),
+                |trial, params| eprintln!("\nTrial {}: {:?}", trial, params),
+            )?;
+            let suggested = tune::suggest(&results);
+            report::write_tune_results(&results, suggested, *format, std::io::stdout().lock())?;
+        }
+        Action::Serve {
+            bind,
+            workers,
+            lookups,
+            matching,
+        } => {
+            let (writer, readers) = open_handles(args, *workers)?;
+            let service = service::Service::new(
+                Fingerprinter::new(analysis_params(args))?,
+                matching.options(),
+                *lookups,
+                writer,
+                readers,
+            )?;
+            let server = http::HttpServer::bind(bind, *workers)?;
+            match server.local_addr() {
+                Some(address) => eprintln!("Listening on http://{}", address),
+                None => eprintln!("Listening on {}", bind),
+            }
+            server.run(&service, |err| eprintln!("{:#}", err));
+        }
+        #[cfg(unix)]
+        Action::Daemon {
+            socket,
+            workers,
+            lookups,
+            matching,
+        } => {
+            let (writer, readers) = open_handles(args, *workers)?;
+            let service = service::Service::new(
+                Fingerprinter::new(analysis_params(args))?,
+                matching.options(),
+                *lookups,
+                writer,
+                readers,
+            )?;
+            let daemon = atlas::daemon::Daemon::bind(socket)?;
+            eprintln!("Listening on {}", socket.display());
+            daemon.run(&service, |err| eprintln!("{:#}", err));
+        }
+        Action::Fingerprint { output_dir } => {
+            eprintln!("Fingerprinting audio");
+            write_fingerprints(args, output_dir)?;
+        }
+        Action::Stats { lookups, format } => {
+            eprintln!("Measuring the database");
+            let stats = storage::measure(&open_storage(args)?, *lookups)?;
+            report::write_storage_stats(&stats, *format, std::io::stdout().lock())?;
+        }
+        Action::Db(action) => {
+            database(args, action)?;
+        }
+        Action::Index(IndexAction::Build { output }) => {
+            eprintln!("Building inverted index");
+            build_index(args, output)?;
+        }
     }
 
-    Ok(())
+    Ok(report::EXIT_MATCH)
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    match run(&args) {
+        Ok(code) => ExitCode::from(code),
+        Err(err) => {
+            eprintln!("Error: {:?}", err);
+            ExitCode::from(report::EXIT_ERROR)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use clap::CommandFactory;
+
+    #[test]
+    fn arguments_are_consistent() {
+        Args::command().debug_assert();
+    }
+
+    #[test]
+    fn options_follow_their_subcommand() {
+        let args = Args::try_parse_from([
+            "atlas",
+            "-i",
+            "tracks",
+            "degrade",
+            "--snr",
+            "5",
+            "--clip-dir",
+            "clips",
+        ])
+        .unwrap();
+        assert!(matches!(args.action, Some(Action::Degrade { snr, .. }) if snr == 5.));
+        assert!(Args::try_parse_from(["atlas", "--snr", "5", "degrade"]).is_err());
+    }
 }
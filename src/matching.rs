@@ -0,0 +1,683 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use anyhow::Context;
+
+use crate::{
+    eval::StageTimings,
+    fingerprinter::{Analysis, Fingerprinter},
+    hash::{self, PairRecord, PeakLocationPair},
+    image_ops::Constellation,
+    index::Index,
+    report::MatchReport,
+    storage::{Fingerprint, SqliteStorage, Storage},
+};
+
+/// Number of tracks kept after the first pass over the matching fingerprints
+pub const MAX_CANDIDATES: usize = 10;
+
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub track_id: u32,
+    pub title: String,
+    pub offset: i64, // track_time - sample_time, multiple of window_length. Negative if the track starts within the sample
+    pub score: u32,  // number of hashes agreeing on the offset
+    pub confidence: f64, // 1 - p-value of the score under the background distribution
+}
+
+/// The matching hashes behind a candidate, for plotting why it matched
+#[derive(Debug, Clone)]
+pub struct MatchExplanation {
+    pub points: Vec<(u32, u32)>, // (sample_time, track_time) of each matching hash
+    pub offset_histogram: Vec<(i64, u32)>, // (first offset of the bin, count), ordered by offset
+    pub bin_width: u32,
+}
+
+/// How track-sample time offsets are grouped into histogram bins.
+/// Timing jitter spreads a true match over neighbouring offsets, so a bin's score is
+/// the sum of the bins within `neighbours` of it.
+#[derive(Debug, Clone, Copy)]
+pub struct OffsetBinning {
+    pub bin_width: u32,  // in multiples of window_length
+    pub neighbours: u32, // bins either side added to a bin's score
+}
+
+impl OffsetBinning {
+    /// Number of bins summed into each score
+    fn window_bins(&self) -> u32 {
+        2 * self.neighbours + 1
+    }
+}
+
+/// How a recording is matched and how many results are reported
+#[derive(Debug, Clone, Copy)]
+pub struct MatchOptions {
+    pub binning: OffsetBinning,
+    pub top: usize,          // number of ranked results to report
+    pub min_confidence: f64, // required 1 - p-value of the best track's score
+}
+
+impl Default for MatchOptions {
+    fn default() -> Self {
+        MatchOptions {
+            binning: OffsetBinning {
+                bin_width: 1,
+                neighbours: 1,
+            },
+            top: 5,
+            min_confidence: 0.99,
+        }
+    }
+}
+
+/// A recording's analysis, the tracks it could be and the report of them
+pub struct MatchOutcome {
+    pub analysis: Analysis,
+    pub candidates: Vec<Candidate>,
+    pub report: MatchReport,
+}
+
+/// Per-track totals gathered while binning, used to model the background of chance matches
+#[derive(Default)]
+struct TrackTotals {
+    matched_hashes: u32,
+    max_track_time: u32,
+}
+
+/// Matches samples against an index, keeping its storage and the titles of tracks
+/// already seen warm between samples
+pub struct Matcher<S: Storage = SqliteStorage> {
+    index: Index<S>,
+    titles: HashMap<u32, String>,
+    postings_scanned: u64, // stored fingerprints ranked, over every match made
+}
+
+impl<S: Storage> Matcher<S> {
+    pub fn new(index: Index<S>) -> Result<Self, anyhow::Error> {
+        Ok(Matcher {
+            index,
+            titles: HashMap::new(),
+            postings_scanned: 0,
+        })
+    }
+
+    pub fn index(&self) -> &Index<S> {
+        &self.index
+    }
+
+    /// The index, for adding and removing tracks. Forgets the titles already seen, as
+    /// their ids may be reused.
+    pub fn index_mut(&mut self) -> &mut Index<S> {
+        self.forget_titles();
+        &mut self.index
+    }
+
+    /// Forgets the titles already seen, for when the tracks have been changed through
+    /// another handle on the storage
+    pub fn forget_titles(&mut self) {
+        self.titles.clear();
+    }
+
+    /// The stored fingerprints looked up and ranked by every match made so far, a cost
+    /// of matching that doesn't vary between runs as time does
+    pub fn postings_scanned(&self) -> u64 {
+        self.postings_scanned
+    }
+
+    /// Fingerprints a recording and ranks the tracks it could be, adding the time
+    /// spent in each stage to `timings`
+    pub fn match_file(
+        &mut self,
+        fingerprinter: &Fingerprinter,
+        path: &Path,
+        options: &MatchOptions,
+        timings: &mut StageTimings,
+    ) -> Result<MatchOutcome, anyhow::Error> {
+        let analysis = fingerprinter.analyse_file(path, timings)?;
+        let (candidates, report) = self.match_hashes(
+            &path.to_string_lossy(),
+            &analysis.hashes,
+            fingerprinter.params().window_length,
+            options,
+            timings,
+        )?;
+        Ok(MatchOutcome {
+            analysis,
+            candidates,
+            report,
+        })
+    }
+
+    /// Ranks the tracks a recording's hashes could be from, for when the recording was
+    /// fingerprinted elsewhere. `window_length` is the one the hashes were made with.
+    pub fn match_hashes(
+        &mut self,
+        query: &str,
+        hashes: &HashMap<u32, PairRecord>,
+        window_length: f32,
+        options: &MatchOptions,
+        timings: &mut StageTimings,
+    ) -> Result<(Vec<Candidate>, MatchReport), anyhow::Error> {
+        // retrieve all fingerprints with a matching hash
+        // for each matching hash, calculate track_time-sample_time and bin it by track
+        // the tracks with the highest bins are the best candidates
+        let start = Instant::now();
+        let candidates =
+            self.find_candidates(hashes, options.binning, MAX_CANDIDATES.max(options.top))?;
+        timings.lookup += start.elapsed();
+
+        let report = MatchReport::new(
+            query.to_string(),
+            hashes.len(),
+            &candidates,
+            options,
+            window_length,
+        );
+        Ok((candidates, report))
+    }
+
+    /// Finds the tracks sharing the most time-aligned hashes with the sample.
+    /// All fingerprints matching the sample are looked up at once and binned by
+    /// track and track-sample time offset together, so the cost doesn't depend on how
+    /// many tracks a popular hash appears in. Only the best `max_candidates` tracks are
+    /// kept, and only titles not already cached are looked up.
+    pub fn find_candidates(
+        &mut self,
+        pair_records: &HashMap<u32, PairRecord>,
+        binning: OffsetBinning,
+        max_candidates: usize,
+    ) -> Result<Vec<Candidate>, anyhow::Error> {
+        let hashes: Vec<u32> = pair_records.keys().copied().collect();
+        let fingerprints = self.index.lookup(&hashes)?;
+        self.rank_fingerprints(pair_records, &fingerprints, binning, max_candidates)
+    }
+
+    /// Ranks tracks as [`Matcher::find_candidates`] does, given every stored fingerprint
+    /// with one of the sample's hashes, for callers that look them up themselves
+    pub fn rank_fingerprints(
+        &mut self,
+        pair_records: &HashMap<u32, PairRecord>,
+        fingerprints: &[Fingerprint],
+        binning: OffsetBinning,
+        max_candidates: usize,
+    ) -> Result<Vec<Candidate>, anyhow::Error> {
+        let bin_width = binning.bin_width.max(1) as i64;
+        let max_sample_time = pair_records
+            .values()
+            .map(|record| record.time_a)
+            .max()
+            .unwrap_or(0);
+
+        // histogram of (track_id, offset bin) for every candidate track at once
+        let mut time_bins: HashMap<(u32, i64), u32> = HashMap::new();
+        let mut totals: HashMap<u32, TrackTotals> = HashMap::new();
+        self.postings_scanned += fingerprints.len() as u64;
+        for fingerprint in fingerprints {
+            let (hash, track_time, track_id) = (
+                fingerprint.hash,
+                fingerprint.track_time,
+                fingerprint.track_id,
+            );
+            let sample_time = pair_records
+                .get(&hash)
+                .context("Erroneous hash returned")?
+                .time_a;
+
+            let track_totals = totals.entry(track_id).or_default();
+            track_totals.matched_hashes += 1;
+            track_totals.max_track_time = track_totals.max_track_time.max(track_time);
+
+            // negative when the sample has lead-in before the start of the track
+            let match_offset = track_time as i64 - sample_time as i64;
+            *time_bins
+                .entry((track_id, match_offset.div_euclid(bin_width)))
+                .or_insert(0) += 1;
+        }
+
+        // score each bin with its neighbours and keep the highest of each track
+        let mut best_bins: HashMap<u32, (i64, u32)> = HashMap::new();
+        for (&(track_id, bin), &count) in &time_bins {
+            let first = bin - binning.neighbours as i64;
+            let last = bin + binning.neighbours as i64;
+            let score = (first..=last)
+                .filter_map(|neighbour| time_bins.get(&(track_id, neighbour)))
+                .sum::<u32>();
+            let best = best_bins.entry(track_id).or_insert((bin, score));
+            let best_count = time_bins[&(track_id, best.0)];
+            if (score, count) > (best.1, best_count)
+                || ((score, count) == (best.1, best_count) && bin < best.0)
+            {
+                *best = (bin, score);
+            }
+        }
+        let mut ranked: Vec<(u32, i64, u32)> = best_bins
+            .into_iter()
+            .map(|(track_id, (bin, score))| (track_id, bin * bin_width + bin_width / 2, score))
+            .collect();
+        let tracks_considered = ranked.len();
+        ranked.sort_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(&b.0)));
+        ranked.truncate(max_candidates);
+
+        // get titles of the remaining tracks only
+        for &(track_id, _, _) in &ranked {
+            if !self.titles.contains_key(&track_id) {
+                if let Some(title) = self.index.track_title(track_id)? {
+                    self.titles.insert(track_id, title);
+                }
+            }
+        }
+
+        let candidates = ranked
+            .into_iter()
+            .map(|(track_id, offset, score)| {
+                let track_totals = &totals[&track_id];
+                // every offset the track could have produced is a bin chance matches can land in
+                let offset_span = track_totals.max_track_time as i64 + max_sample_time as i64;
+                let offset_bins = (offset_span / bin_width + 1) as u32;
+                let p_value = peak_p_value(
+                    score,
+                    track_totals.matched_hashes,
+                    offset_bins,
+                    binning.window_bins(),
+                    tracks_considered,
+                );
+                Candidate {
+                    track_id,
+                    title: self.titles.get(&track_id).cloned().unwrap_or_default(),
+                    offset,
+                    score,
+                    confidence: 1. - p_value,
+                }
+            })
+            .collect();
+
+        Ok(candidates)
+    }
+
+    /// Path of the audio a track was added from, if known
+    pub fn track_path(&self, track_id: u32) -> Result<Option<PathBuf>, anyhow::Error> {
+        self.index.track_path(track_id)
+    }
+
+    /// Gathers the hashes the sample shares with each of the tracks and the offset
+    /// histograms they make, binned the same way as when finding candidates. The stored
+    /// fingerprints are looked up once for all the tracks, whose explanations are returned
+    /// in the same order.
+    pub fn explain(
+        &mut self,
+        pair_records: &HashMap<u32, PairRecord>,
+        track_ids: &[u32],
+        binning: OffsetBinning,
+    ) -> Result<Vec<MatchExplanation>, anyhow::Error> {
+        let bin_width = binning.bin_width.max(1) as i64;
+        let hashes: Vec<u32> = pair_records.keys().copied().collect();
+
+        // (sample_time, track_time) of each hash shared with each track
+        let mut points: HashMap<u32, Vec<(u32, u32)>> = track_ids
+            .iter()
+            .map(|&track_id| (track_id, vec![]))
+            .collect();
+        for fingerprint in self.index.lookup(&hashes)? {
+            let Some(track_points) = points.get_mut(&fingerprint.track_id) else {
+                continue;
+            };
+            let sample_time = pair_records
+                .get(&fingerprint.hash)
+                .context("Erroneous hash returned")?
+                .time_a;
+            track_points.push((sample_time, fingerprint.track_time));
+        }
+
+        Ok(track_ids
+            .iter()
+            .map(|track_id| {
+                let mut points = points.get(track_id).cloned().unwrap_or_default();
+                points.sort_unstable();
+
+                let mut time_bins: HashMap<i64, u32> = HashMap::new();
+                for &(sample_time, track_time) in &points {
+                    let match_offset = track_time as i64 - sample_time as i64;
+                    *time_bins
+                        .entry(match_offset.div_euclid(bin_width))
+                        .or_insert(0) += 1;
+                }
+                let mut offset_histogram: Vec<(i64, u32)> = time_bins
+                    .into_iter()
+                    .map(|(bin, count)| (bin * bin_width, count))
+                    .collect();
+                offset_histogram.sort_unstable();
+
+                MatchExplanation {
+                    points,
+                    offset_histogram,
+                    bin_width: bin_width as u32,
+                }
+            })
+            .collect())
+    }
+
+    /// Pairs up the sample's peaks and those of a candidate track, marking the pairs whose
+    /// hashes match at the candidate's alignment, each constellation starting where the
+    /// two recordings overlap. The track is analysed again from the audio it was added
+    /// from, so there is nothing to compare if that wasn't recorded.
+    pub fn compare_constellations(
+        &self,
+        fingerprinter: &Fingerprinter,
+        candidate: &Candidate,
+        sample_peaks: &[(usize, usize)],
+        tolerance: i64, // windows a pair's alignment may differ from the candidate offset by
+    ) -> Result<Option<(Constellation, Constellation)>, anyhow::Error> {
+        let Some(track_path) = self.track_path(candidate.track_id)? else {
+            return Ok(None);
+        };
+        let track_peaks = fingerprinter
+            .analyse_file(&track_path, &mut StageTimings::default())?
+            .peaks;
+
+        let sample_pairs = fingerprinter.find_pairs(sample_peaks);
+        let track_pairs = fingerprinter.find_pairs(&track_peaks);
+
+        // times each hash occurs at in a recording
+        let hash_times = |pairs: &[PeakLocationPair]| {
+            let mut times: HashMap<u32, Vec<usize>> = HashMap::new();
+            for &(a, b) in pairs {
+                let hash = hash::calculate_hash(&hash::pair_from_locations(a, b));
+                times.entry(hash).or_default().push(a.0);
+            }
+            times
+        };
+        // whether each pair's hash occurs in the other recording at the candidate's alignment
+        let matched_pairs =
+            |pairs: &[PeakLocationPair], other_times: &HashMap<u32, Vec<usize>>, offset: i64| {
+                pairs
+                    .iter()
+                    .map(|&(a, b)| {
+                        let hash = hash::calculate_hash(&hash::pair_from_locations(a, b));
+                        other_times.get(&hash).is_some_and(|times| {
+                            times
+                                .iter()
+                                .any(|&time| (time as i64 - a.0 as i64 - offset).abs() <= tolerance)
+                        })
+                    })
+                    .collect::<Vec<bool>>()
+            };
+        let sample_matched =
+            matched_pairs(&sample_pairs, &hash_times(&track_pairs), candidate.offset);
+        let track_matched =
+            matched_pairs(&track_pairs, &hash_times(&sample_pairs), -candidate.offset);
+
+        // with lead-in, the track starts partway into the sample
+        let sample_start = (-candidate.offset).max(0) as usize;
+        let track_start = candidate.offset.max(0) as usize;
+        Ok(Some((
+            Constellation {
+                caption: "Sample".to_string(),
+                first_window: sample_start,
+                peaks: sample_peaks.to_vec(),
+                pairs: sample_pairs,
+                matched_pairs: sample_matched,
+            },
+            Constellation {
+                caption: candidate.title.clone(),
+                first_window: track_start,
+                peaks: track_peaks,
+                pairs: track_pairs,
+                matched_pairs: track_matched,
+            },
+        )))
+    }
+}
+
+/// Returns the best candidate if it is confident enough to be called a match
+pub fn decide(candidates: &[Candidate], min_confidence: f64) -> Option<&Candidate> {
+    candidates
+        .first()
+        .filter(|candidate| candidate.confidence >= min_confidence)
+}
+
+/// Probability of a track's highest offset bin reaching `score` by chance.
+/// Under the null hypothesis the track isn't in the sample, so each of its
+/// `matched_hashes` hashes is a collision that lands in one of `offset_bins`
+/// uniformly at random. A score summing `window_bins` bins is then
+/// Binomial(matched_hashes, window_bins/offset_bins).
+/// The tail probability is Bonferroni-corrected for taking the maximum over every
+/// bin of every track considered.
+fn peak_p_value(
+    score: u32,
+    matched_hashes: u32,
+    offset_bins: u32,
+    window_bins: u32,
+    tracks_considered: usize,
+) -> f64 {
+    let bin_probability = (window_bins as f64 / offset_bins.max(1) as f64).min(1.);
+    let tail = binomial_tail(matched_hashes, score, bin_probability);
+    let comparisons = offset_bins as f64 * tracks_considered.max(1) as f64;
+    (tail * comparisons).min(1.)
+}
+
+/// P(X >= k) for X ~ Binomial(n, p), summed in log space to avoid underflow
+fn binomial_tail(n: u32, k: u32, p: f64) -> f64 {
+    if k == 0 {
+        return 1.;
+    }
+    if k > n || p <= 0. {
+        return 0.;
+    }
+    if p >= 1. {
+        return 1.;
+    }
+
+    // ln P(X = k) = ln C(n, k) + k ln p + (n - k) ln(1 - p)
+    let ln_choose: f64 = (0..k)
+        .map(|i| ((n - i) as f64).ln() - ((i + 1) as f64).ln())
+        .sum();
+    let mut ln_term = ln_choose + k as f64 * p.ln() + (n - k) as f64 * (1. - p).ln();
+    let ln_ratio = p.ln() - (1. - p).ln();
+
+    let mut tail = 0.;
+    for i in k..=n {
+        let term = ln_term.exp();
+        tail += term;
+        if term < tail * f64::EPSILON {
+            break; // remaining terms are negligible
+        }
+        // P(X = i + 1) = P(X = i) * (n - i) / (i + 1) * p / (1 - p)
+        ln_term += ((n - i) as f64).ln() - ((i + 1) as f64).ln() + ln_ratio;
+    }
+    tail.min(1.)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    fn matcher() -> Matcher<MemoryStorage> {
+        Matcher::new(Index::new(MemoryStorage::default())).unwrap()
+    }
+
+    /// A query of `len` hashes, the hash of each its time
+    fn query(len: u32) -> HashMap<u32, PairRecord> {
+        (0..len)
+            .map(|time| {
+                (
+                    time,
+                    PairRecord {
+                        hash: time,
+                        time_a: time,
+                    },
+                )
+            })
+            .collect()
+    }
+
+    /// The query's hashes from `first` on in a track, `offset` windows later
+    fn aligned(track_id: u32, first: u32, len: u32, offset: i64) -> Vec<Fingerprint> {
+        (first..first + len)
+            .map(|hash| Fingerprint {
+                hash,
+                track_time: (hash as i64 + offset) as u32,
+                track_id,
+            })
+            .collect()
+    }
+
+    /// Each of the query's hashes in `count` tracks at random times, as popular hashes are
+    fn collisions(rng: &mut StdRng, len: u32, tracks: u32, count: usize) -> Vec<Fingerprint> {
+        let mut fingerprints = vec![];
+        for hash in 0..len {
+            for _ in 0..count {
+                fingerprints.push(Fingerprint {
+                    hash,
+                    track_time: rng.gen_range(0..2000),
+                    track_id: rng.gen_range(0..tracks),
+                });
+            }
+        }
+        fingerprints
+    }
+
+    fn ranks(candidates: &[Candidate]) -> Vec<(u32, i64, u32)> {
+        candidates
+            .iter()
+            .map(|candidate| (candidate.track_id, candidate.offset, candidate.score))
+            .collect()
+    }
+
+    #[test]
+    fn ranking_every_track_at_once_scores_each_as_alone() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut fingerprints = collisions(&mut rng, 300, 40, 20);
+        for track_id in 0..6 {
+            let len = 10 * (track_id + 1);
+            fingerprints.extend(aligned(track_id, 5 * track_id, len, 7 * track_id as i64));
+        }
+        let binning = MatchOptions::default().binning;
+        let mut matcher = matcher();
+
+        let together = matcher
+            .rank_fingerprints(&query(300), &fingerprints, binning, usize::MAX)
+            .unwrap();
+        assert_eq!(together.len(), 40);
+        for candidate in &together {
+            let alone: Vec<Fingerprint> = fingerprints
+                .iter()
+                .filter(|fingerprint| fingerprint.track_id == candidate.track_id)
+                .copied()
+                .collect();
+            let alone = matcher
+                .rank_fingerprints(&query(300), &alone, binning, 1)
+                .unwrap();
+            assert_eq!(ranks(&alone), ranks(std::slice::from_ref(candidate)));
+        }
+
+        // only the best are kept, the planted tracks ahead of those only colliding
+        let best = matcher
+            .rank_fingerprints(&query(300), &fingerprints, binning, 3)
+            .unwrap();
+        assert_eq!(ranks(&best), ranks(&together[..3]));
+        let best_tracks: Vec<u32> = best.iter().map(|candidate| candidate.track_id).collect();
+        assert_eq!(best_tracks, vec![5, 4, 3]);
+    }
+
+    #[test]
+    fn binomial_tails_match_known_values() {
+        assert_eq!(binomial_tail(10, 0, 0.5), 1.);
+        assert!((binomial_tail(10, 8, 0.5) - 56. / 1024.).abs() < 1e-12);
+        assert!((binomial_tail(20, 1, 0.1) - (1. - 0.9f64.powi(20))).abs() < 1e-12);
+        assert!((binomial_tail(3, 3, 0.2) - 0.008).abs() < 1e-12);
+        assert_eq!(binomial_tail(5, 6, 0.5), 0.);
+        // far in the tail without underflowing to nothing
+        let tiny = binomial_tail(10_000, 60, 0.001);
+        assert!(tiny > 0. && tiny < 1e-20);
+    }
+
+    #[test]
+    fn chance_peaks_are_not_confident_once_corrected() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let fingerprints = collisions(&mut rng, 200, 1, 1);
+        let options = MatchOptions::default();
+        let candidates = matcher()
+            .rank_fingerprints(&query(200), &fingerprints, options.binning, 1)
+            .unwrap();
+        let chance = &candidates[0];
+        assert!(chance.score >= 3);
+
+        // the best of the track's offsets would seem confident alone
+        let offset_bins = 2000 + 200; // about as many as the track and query times make
+        let window_bins = options.binning.window_bins();
+        let uncorrected = binomial_tail(200, chance.score, window_bins as f64 / offset_bins as f64);
+        assert!(1. - uncorrected >= options.min_confidence);
+        assert!(chance.confidence < options.min_confidence);
+        assert!(decide(&candidates, options.min_confidence).is_none());
+
+        // while a real match is
+        let mut fingerprints = fingerprints;
+        fingerprints.extend(aligned(0, 50, 30, 400));
+        let candidates = matcher()
+            .rank_fingerprints(&query(200), &fingerprints, options.binning, 1)
+            .unwrap();
+        let matched = decide(&candidates, options.min_confidence).unwrap();
+        assert_eq!((matched.offset, matched.score), (400, 30));
+    }
+
+    #[test]
+    fn peaks_straddling_bin_edges_are_summed_with_their_neighbours() {
+        // one track jitters between offsets 9 and 10, either side of a bin edge, another
+        // has fewer hashes all at one offset
+        let mut fingerprints: Vec<Fingerprint> = (0..20)
+            .flat_map(|hash| aligned(1, hash, 1, 9 + hash as i64 % 2))
+            .collect();
+        fingerprints.extend(aligned(2, 100, 14, 20));
+        let mut matcher = matcher();
+        let mut best = |neighbours| {
+            let binning = OffsetBinning {
+                bin_width: 2,
+                neighbours,
+            };
+            let candidates = matcher
+                .rank_fingerprints(&query(200), &fingerprints, binning, 1)
+                .unwrap();
+            (candidates[0].track_id, candidates[0].score)
+        };
+        assert_eq!(best(0), (2, 14));
+        assert_eq!(best(1), (1, 20));
+    }
+
+    #[test]
+    fn tracks_starting_within_the_query_have_negative_offsets() {
+        // the query has 40 windows of lead-in before the track starts
+        let track: HashMap<u32, PairRecord> = (40..100)
+            .map(|hash| {
+                (
+                    hash,
+                    PairRecord {
+                        hash,
+                        time_a: hash - 40,
+                    },
+                )
+            })
+            .collect();
+        let mut matcher = matcher();
+        let track_id = matcher.index_mut().add_track("a", None, &track).unwrap();
+
+        // wider bins report their middle, here of -42 to -40
+        for (bin_width, offset) in [(1, -40), (3, -41)] {
+            let binning = OffsetBinning {
+                bin_width,
+                neighbours: 1,
+            };
+            let candidates = matcher.find_candidates(&query(100), binning, 1).unwrap();
+            assert_eq!((candidates[0].offset, candidates[0].score), (offset, 60));
+
+            let explanation = &matcher.explain(&query(100), &[track_id], binning).unwrap()[0];
+            assert_eq!(explanation.points.len(), 60);
+            assert!(explanation.offset_histogram[0].0 <= -40);
+        }
+    }
+}
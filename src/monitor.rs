@@ -0,0 +1,274 @@
+use crate::{
+    audio_ops::{self, PcmReader},
+    matching::{self, MatchOptions, Matcher},
+    report::TimelineRow,
+    storage::Storage,
+    stream::StreamFingerprinter,
+    AnalysisParams,
+};
+
+/// How a long input is cut into segments that are matched one at a time
+#[derive(Debug, Clone, Copy)]
+pub struct Segmenting {
+    pub length_sec: f32,
+    pub hop_sec: f32, // between the starts of consecutive segments
+}
+
+/// Best match of one window of a long input
+#[derive(Debug, Clone)]
+pub struct SegmentMatch {
+    pub start: usize, // first window of the segment in the input
+    pub end: usize,   // window after the last one of the segment
+    pub track_id: u32,
+    pub title: String,
+    pub offset: i64, // track_time - segment time, multiple of window_length
+    pub confidence: f64,
+}
+
+/// A stretch of the input during which one track was playing
+#[derive(Debug, Clone)]
+pub struct TimelineEntry {
+    pub start: usize, // in windows of the input
+    pub end: usize,
+    pub track_id: u32,
+    pub title: String,
+    pub track_offset: i64, // position in the track at the start of the entry, in windows
+    pub confidence: f64,   // highest confidence of the merged segments
+}
+
+/// Merges consecutive segment matches into contiguous timeline entries.
+/// Segments join an entry if they overlap or touch it, match the same track and agree
+/// on how the track is aligned to the input to within `offset_tolerance` windows.
+/// Entries don't overlap: one that runs into the next ends where the next begins.
+/// Segment matches must be ordered by start.
+pub fn merge_segments(matches: &[SegmentMatch], offset_tolerance: i64) -> Vec<TimelineEntry> {
+    let mut timeline: Vec<TimelineEntry> = vec![];
+    for segment in matches {
+        // track time at the start of the input, constant while the track plays through
+        let alignment = segment.offset - segment.start as i64;
+
+        if let Some(entry) = timeline.last_mut() {
+            let entry_alignment = entry.track_offset - entry.start as i64;
+            if entry.track_id == segment.track_id
+                && segment.start <= entry.end
+                && (alignment - entry_alignment).abs() <= offset_tolerance
+            {
+                entry.end = entry.end.max(segment.end);
+                entry.confidence = entry.confidence.max(segment.confidence);
+                continue;
+            }
+        }
+
+        // a negative offset says exactly where within the segment the track begins
+        let lead_in = (-segment.offset).max(0);
+        timeline.push(TimelineEntry {
+            start: segment.start + lead_in as usize,
+            end: segment.end,
+            track_id: segment.track_id,
+            title: segment.title.clone(),
+            track_offset: segment.offset + lead_in,
+            confidence: segment.confidence,
+        });
+    }
+
+    // the segments around a change of track overlap both tracks, so the earlier track's
+    // last segment runs past where the next track begins
+    let mut next_start = None;
+    for entry in timeline.iter_mut().rev() {
+        if let Some(next_start) = next_start {
+            entry.end = entry.end.min(next_start).max(entry.start);
+        }
+        next_start = Some(entry.start);
+    }
+    timeline
+}
+
+/// Slides a segment over audio as it is read, matching each one, and reports a timeline
+/// of the tracks found. The audio is fingerprinted as it arrives and the peaks of
+/// windows before the current segment are dropped, so memory doesn't grow with the
+/// length of the input.
+pub fn monitor(
+    matcher: &mut Matcher<impl Storage>,
+    params: AnalysisParams,
+    pcm_reader: &mut PcmReader,
+    segmenting: Segmenting,
+    options: &MatchOptions,
+) -> Result<Vec<TimelineRow>, anyhow::Error> {
+    let sample_rate = pcm_reader.sample_rate();
+    let mut fingerprinter = StreamFingerprinter::new(params, sample_rate)?;
+    let window_length = params.window_length;
+    let binning = options.binning;
+    let segment_windows = ((segmenting.length_sec / window_length) as usize).max(1);
+    let hop_windows = ((segmenting.hop_sec / window_length) as usize).max(1);
+    let chunk_frames = hop_windows * audio_ops::window_size(sample_rate, window_length);
+
+    let mut segment_matches = vec![];
+    let mut start = 0;
+    'reading: loop {
+        let chunk = pcm_reader.read_chunk(chunk_frames)?;
+        let finished = chunk.len() < chunk_frames;
+        fingerprinter.push_samples(&chunk)?;
+        if finished {
+            fingerprinter.finish();
+        }
+
+        // match each segment once its peaks have all been found, the last one being
+        // cut short by the end of the input
+        loop {
+            let total_windows = fingerprinter.windows_seen();
+            let end = start + segment_windows;
+            let complete = fingerprinter.peaks_found_until() >= end && total_windows > end;
+            if !(complete || finished) {
+                break;
+            }
+            let end = end.min(total_windows);
+            let pair_records = fingerprinter.fingerprint_between(start, end);
+
+            let candidates =
+                matcher.find_candidates(&pair_records, binning, matching::MAX_CANDIDATES)?;
+            if let Some(best) = matching::decide(&candidates, options.min_confidence) {
+                segment_matches.push(SegmentMatch {
+                    start,
+                    end,
+                    track_id: best.track_id,
+                    title: best.title.clone(),
+                    offset: best.offset,
+                    confidence: best.confidence,
+                });
+            }
+
+            if end >= total_windows {
+                break 'reading;
+            }
+            start += hop_windows;
+            fingerprinter.forget_before(start);
+        }
+    }
+
+    // segments of the same track can disagree by up to the width of a scoring window
+    let offset_tolerance = (binning.bin_width.max(1) * (2 * binning.neighbours + 1)) as i64;
+    Ok(merge_segments(&segment_matches, offset_tolerance)
+        .into_iter()
+        .map(|entry| TimelineRow {
+            start_sec: entry.start as f32 * window_length,
+            end_sec: entry.end as f32 * window_length,
+            track_id: entry.track_id,
+            title: entry.title,
+            track_offset_sec: entry.track_offset as f32 * window_length,
+            confidence: entry.confidence,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{
+        audio_ops::PcmFormat,
+        eval::StageTimings,
+        storage::MemoryStorage,
+        test_audio::{tones, SAMPLE_RATE},
+        Fingerprinter, Index,
+    };
+
+    /// Segments of 4 windows every 2 windows over tracks starting at 0, 12 and 24.
+    /// The segment straddling each change is won by the earlier track.
+    fn segments() -> Vec<SegmentMatch> {
+        (0..=32)
+            .step_by(2)
+            .map(|start| {
+                let (track_id, track_start) = match start {
+                    0..=10 => (1, 0),
+                    12..=22 => (2, 12),
+                    _ => (3, 24),
+                };
+                SegmentMatch {
+                    start,
+                    end: start + 4,
+                    track_id,
+                    title: format!("track{}", track_id),
+                    offset: start as i64 - track_start,
+                    confidence: 1.,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn entries_end_where_the_next_begins() {
+        let timeline = merge_segments(&segments(), 1);
+        let spans: Vec<(u32, usize, usize)> = timeline
+            .iter()
+            .map(|entry| (entry.track_id, entry.start, entry.end))
+            .collect();
+        assert_eq!(spans, vec![(1, 0, 12), (2, 12, 24), (3, 24, 36)]);
+    }
+
+    #[test]
+    fn a_track_starting_within_a_segment_starts_there() {
+        let segments = vec![SegmentMatch {
+            start: 10,
+            end: 20,
+            track_id: 1,
+            title: "track1".to_string(),
+            offset: -3,
+            confidence: 1.,
+        }];
+        let timeline = merge_segments(&segments, 1);
+        assert_eq!((timeline[0].start, timeline[0].track_offset), (13, 0));
+    }
+
+    #[test]
+    fn monitoring_finds_each_track_as_it_plays() {
+        let params = AnalysisParams::default();
+        let fingerprinter = Fingerprinter::new(params).unwrap();
+        let mut index = Index::new(MemoryStorage::default());
+        let tracks = [tones(12, 1), tones(12, 2)];
+        for (title, samples) in ["first", "second"].into_iter().zip(&tracks) {
+            let analysis = fingerprinter
+                .analyse_samples(samples, SAMPLE_RATE, &mut StageTimings::default())
+                .unwrap();
+            index.add_track(title, None, &analysis.hashes).unwrap();
+        }
+        let mut matcher = Matcher::new(index).unwrap();
+
+        // 8s of the first track from its start, then 8s of the second from 2s in
+        let second = SAMPLE_RATE as usize;
+        let mut samples = tracks[0][..8 * second].to_vec();
+        samples.extend_from_slice(&tracks[1][2 * second..10 * second]);
+        let bytes: Vec<u8> = samples
+            .iter()
+            .flat_map(|&sample| (sample as i16).to_le_bytes())
+            .collect();
+        let mut pcm_reader = PcmReader::new(
+            Box::new(Cursor::new(bytes)),
+            PcmFormat::S16le,
+            SAMPLE_RATE,
+            1,
+        )
+        .unwrap();
+
+        let segmenting = Segmenting {
+            length_sec: 4.,
+            hop_sec: 2.,
+        };
+        let timeline = monitor(
+            &mut matcher,
+            params,
+            &mut pcm_reader,
+            segmenting,
+            &MatchOptions::default(),
+        )
+        .unwrap();
+
+        let titles: Vec<&str> = timeline.iter().map(|row| row.title.as_str()).collect();
+        assert_eq!(titles, vec!["first", "second"]);
+        let close = |a: f32, b: f32| (a - b).abs() < 0.3;
+        assert!(close(timeline[0].start_sec, 0.) && close(timeline[0].track_offset_sec, 0.));
+        assert!(close(timeline[1].start_sec, 8.), "{:?}", timeline[1]);
+        assert!(close(timeline[1].track_offset_sec, 2.), "{:?}", timeline[1]);
+        assert!(close(timeline[1].end_sec, 16.), "{:?}", timeline[1]);
+    }
+}
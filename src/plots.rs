@@ -0,0 +1,179 @@
+//! Saving plots of the analysis of inputs and of why they matched
+
+use std::{ffi::OsStr, fs, path::PathBuf};
+
+use anyhow::Context;
+
+use crate::{
+    image_ops::{self, PlotFormat, PlotKind, PlotSize},
+    matching::{MatchOptions, MatchOutcome, Matcher},
+    storage::Storage,
+    Analysis, Fingerprinter,
+};
+
+/// Which plots of the analysis to save, and where
+#[derive(Debug, Clone)]
+pub struct PlotOptions {
+    pub dir: PathBuf,
+    pub format: PlotFormat,
+    pub kinds: Vec<PlotKind>,
+    pub size: PlotSize,
+}
+
+impl PlotOptions {
+    /// Plots of the kinds given, or all but pairs if none are. Creates the plot directory.
+    pub fn new(
+        dir: PathBuf,
+        format: PlotFormat,
+        kinds: Vec<PlotKind>,
+        size: PlotSize,
+    ) -> Result<Self, anyhow::Error> {
+        fs::create_dir_all(&dir).context("Could not create plot directory.")?;
+        let kinds = if kinds.is_empty() {
+            vec![
+                PlotKind::Spectrogram,
+                PlotKind::Filtered,
+                PlotKind::Peaks,
+                PlotKind::Match,
+                PlotKind::Compare,
+            ]
+        } else {
+            kinds
+        };
+        Ok(PlotOptions {
+            dir,
+            format,
+            kinds,
+            size,
+        })
+    }
+
+    pub fn wants(&self, kind: PlotKind) -> bool {
+        self.kinds.contains(&kind)
+    }
+
+    /// Path of a plot of the named input, `<dir>/<name><suffix>.<extension>`
+    pub fn path(&self, name: &OsStr, suffix: &str) -> PathBuf {
+        let mut filename = name.to_os_string();
+        filename.push(format!("{}.{}", suffix, self.format.extension()));
+        self.dir.join(filename)
+    }
+
+    /// Saves the spectrogram and peak plots of the analysis of the named input
+    pub fn save_analysis(
+        &self,
+        name: &OsStr,
+        analysis: &Analysis,
+        fingerprinter: &Fingerprinter,
+    ) -> Result<(), anyhow::Error> {
+        let window_length = fingerprinter.params().window_length;
+        if self.wants(PlotKind::Spectrogram) {
+            image_ops::plot_spectrogram(
+                &analysis.windows,
+                window_length,
+                self.size,
+                self.format,
+                self.path(name, "_spec"),
+            )
+            .context("Unable to plot spectrogram")?;
+        }
+        if self.wants(PlotKind::Filtered) {
+            image_ops::plot_spectrogram(
+                &analysis.filtered,
+                window_length,
+                self.size,
+                self.format,
+                self.path(name, "_spec_max"),
+            )
+            .context("Unable to plot filtered spectrogram")?;
+        }
+
+        let mut peak_plots = vec![];
+        if self.wants(PlotKind::Peaks) {
+            peak_plots.push(("_peaks", vec![]));
+        }
+        if self.wants(PlotKind::Pairs) {
+            peak_plots.push(("_pairs", fingerprinter.find_pairs(&analysis.peaks)));
+        }
+        for (suffix, pairs) in peak_plots {
+            image_ops::plot_peaks(
+                &analysis.peaks,
+                &pairs,
+                analysis.windows.ncols(),
+                analysis.windows.nrows(),
+                window_length,
+                self.format,
+                self.path(name, suffix),
+            )
+            .context("Unable to plot peaks")?;
+        }
+
+        Ok(())
+    }
+
+    /// Saves the plots of the analysis of the named input and of why each reported
+    /// candidate of its match was found. Returns the titles of the candidates whose
+    /// peaks couldn't be compared, having no audio recorded.
+    pub fn save_match(
+        &self,
+        name: &OsStr,
+        matcher: &mut Matcher<impl Storage>,
+        fingerprinter: &Fingerprinter,
+        outcome: &MatchOutcome,
+        options: &MatchOptions,
+    ) -> Result<Vec<String>, anyhow::Error> {
+        let analysis = &outcome.analysis;
+        self.save_analysis(name, analysis, fingerprinter)?;
+
+        let window_length = fingerprinter.params().window_length;
+        let plotted = &outcome.candidates[..outcome.candidates.len().min(options.top)];
+        let explanations = if self.wants(PlotKind::Match) {
+            let track_ids: Vec<u32> = plotted.iter().map(|candidate| candidate.track_id).collect();
+            matcher.explain(&analysis.hashes, &track_ids, options.binning)?
+        } else {
+            vec![]
+        };
+        let mut uncompared = vec![];
+        for (rank, candidate) in plotted.iter().enumerate() {
+            if let Some(explanation) = explanations.get(rank) {
+                image_ops::plot_match_explanation(
+                    explanation,
+                    &candidate.title,
+                    candidate.offset,
+                    window_length,
+                    self.format,
+                    self.path(name, &format!("_match_{}", rank + 1)),
+                )
+                .context("Unable to plot match explanation")?;
+            }
+
+            if self.wants(PlotKind::Compare) {
+                let binning = options.binning;
+                let tolerance = binning.bin_width.max(1) as i64 * (binning.neighbours as i64 + 1);
+                let Some((sample, track)) = matcher.compare_constellations(
+                    fingerprinter,
+                    candidate,
+                    &analysis.peaks,
+                    tolerance,
+                )?
+                else {
+                    uncompared.push(candidate.title.clone());
+                    continue;
+                };
+                let (sample_windows, frequency_bins) = analysis.windows.dim();
+                image_ops::plot_peak_comparison(
+                    &sample,
+                    &track,
+                    sample_windows.saturating_sub(sample.first_window),
+                    frequency_bins,
+                    window_length,
+                    self.format,
+                    self.path(name, &format!("_compare_{}", rank + 1)),
+                )
+                .context("Unable to plot peak comparison")?;
+            }
+        }
+
+        Ok(uncompared)
+    }
+}
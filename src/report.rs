@@ -0,0 +1,762 @@
+use std::{fmt, io::Write};
+
+use serde::Serialize;
+
+use crate::{
+    eval::EvalSummary,
+    matching::{self, Candidate, MatchOptions},
+    storage::StorageStats,
+    tune::TrialResult,
+};
+
+/// Process exit codes of the match command
+pub const EXIT_MATCH: u8 = 0;
+pub const EXIT_NO_MATCH: u8 = 1;
+pub const EXIT_ERROR: u8 = 2;
+
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+    Csv,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct MatchResult {
+    pub track_id: u32,
+    pub title: String,
+    pub offset_sec: f32, // where the sample starts in the track, negative if the track starts within the sample
+    pub score: u32,
+    pub confidence: f64,
+    pub accepted: bool, // whether this result was decided to be the match
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct MatchReport {
+    pub query: String,
+    pub query_hashes: usize,
+    pub matched: bool,
+    pub results: Vec<MatchResult>, // ranked by score, best first
+}
+
+impl MatchReport {
+    /// Reports the top candidates, accepting the best if it is confident enough
+    pub fn new(
+        query: String,
+        query_hashes: usize,
+        candidates: &[Candidate],
+        options: &MatchOptions,
+        window_length: f32,
+    ) -> Self {
+        let best = matching::decide(candidates, options.min_confidence);
+        let results = candidates
+            .iter()
+            .take(options.top)
+            .map(|candidate| MatchResult {
+                track_id: candidate.track_id,
+                title: candidate.title.clone(),
+                offset_sec: candidate.offset as f32 * window_length,
+                score: candidate.score,
+                confidence: candidate.confidence,
+                accepted: best.is_some_and(|best| best.track_id == candidate.track_id),
+            })
+            .collect();
+
+        MatchReport {
+            query,
+            query_hashes,
+            matched: best.is_some(),
+            results,
+        }
+    }
+
+    /// Exit code of a match command reporting this
+    pub fn exit_code(&self) -> u8 {
+        if self.matched {
+            EXIT_MATCH
+        } else {
+            EXIT_NO_MATCH
+        }
+    }
+}
+
+/// One line of csv output, flattening the report so each row stands alone
+#[derive(Serialize)]
+struct CsvRow<'a> {
+    query: &'a str,
+    query_hashes: usize,
+    track_id: u32,
+    title: &'a str,
+    offset_sec: f32,
+    score: u32,
+    confidence: f64,
+    accepted: bool,
+}
+
+pub fn write_report(
+    report: &MatchReport,
+    format: OutputFormat,
+    mut writer: impl Write,
+) -> Result<(), anyhow::Error> {
+    match format {
+        OutputFormat::Text => {
+            writeln!(writer, "\nMatching fingerprints against the database:")?;
+            for result in &report.results {
+                writeln!(
+                    writer,
+                    "track_id: {:2.}, best offset: {:>4}s, match score: {:3.}, confidence: {:.4}, name: {}",
+                    result.track_id,
+                    result.offset_sec as i32,
+                    result.score,
+                    result.confidence,
+                    result.title
+                )?;
+            }
+            match report.results.iter().find(|result| result.accepted) {
+                Some(best) => writeln!(
+                    writer,
+                    "\nMatch: {} ({}, confidence {:.4})",
+                    best.title,
+                    describe_offset(best.offset_sec),
+                    best.confidence
+                )?,
+                None => writeln!(writer, "\nNo match")?,
+            }
+        }
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut writer, report)?;
+            writeln!(writer)?;
+        }
+        OutputFormat::Csv => {
+            let mut csv_writer = csv::Writer::from_writer(writer);
+            for result in &report.results {
+                csv_writer.serialize(CsvRow {
+                    query: &report.query,
+                    query_hashes: report.query_hashes,
+                    track_id: result.track_id,
+                    title: &result.title,
+                    offset_sec: result.offset_sec,
+                    score: result.score,
+                    confidence: result.confidence,
+                    accepted: result.accepted,
+                })?;
+            }
+            csv_writer.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Describes which of the sample and track starts first
+pub fn describe_offset(offset_sec: f32) -> String {
+    if offset_sec >= 0. {
+        format!("sample starts {:.1}s into track", offset_sec)
+    } else {
+        format!("track starts {:.1}s into sample", -offset_sec)
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TimelineRow {
+    pub start_sec: f32,
+    pub end_sec: f32,
+    pub track_id: u32,
+    pub title: String,
+    pub track_offset_sec: f32, // position in the track at start_sec
+    pub confidence: f64,
+}
+
+pub fn write_timeline(
+    timeline: &[TimelineRow],
+    format: OutputFormat,
+    mut writer: impl Write,
+) -> Result<(), anyhow::Error> {
+    match format {
+        OutputFormat::Text => {
+            if timeline.is_empty() {
+                writeln!(writer, "No matches")?;
+            }
+            for row in timeline {
+                writeln!(
+                    writer,
+                    "{:>8.1}s - {:>8.1}s: {} (track_id: {}, from {:.1}s into track, confidence {:.4})",
+                    row.start_sec,
+                    row.end_sec,
+                    row.title,
+                    row.track_id,
+                    row.track_offset_sec,
+                    row.confidence
+                )?;
+            }
+        }
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut writer, timeline)?;
+            writeln!(writer)?;
+        }
+        OutputFormat::Csv => {
+            let mut csv_writer = csv::Writer::from_writer(writer);
+            for row in timeline {
+                csv_writer.serialize(row)?;
+            }
+            csv_writer.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Identification made while listening to a stream
+#[derive(Serialize, Debug, Clone)]
+pub struct StreamEvent {
+    pub stream_time_sec: f32, // audio received when the decision was made
+    pub latency_sec: f32,     // audio received since the track's evidence began
+    pub track_id: u32,
+    pub title: String,
+    pub track_offset_sec: f32, // position in the track at stream_time_sec
+    pub confidence: f64,
+    pub threshold: f64, // decision threshold the confidence reached
+}
+
+impl fmt::Display for StreamEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:>8.1}s: {} (track_id: {}, {:.1}s into track, confidence {:.4} >= {}, latency {:.1}s)",
+            self.stream_time_sec,
+            self.title,
+            self.track_id,
+            self.track_offset_sec,
+            self.confidence,
+            self.threshold,
+            self.latency_sec
+        )
+    }
+}
+
+/// Result of one query of a batch, with the best ranked track if there was one
+#[derive(Serialize, Debug, Clone)]
+pub struct QueryRow {
+    pub query: String,
+    pub query_hashes: Option<usize>,
+    pub matched: bool,
+    pub track_id: Option<u32>,
+    pub title: Option<String>,
+    pub offset_sec: Option<f32>,
+    pub score: Option<u32>,
+    pub confidence: Option<f64>,
+    pub error: Option<String>, // why the query couldn't be matched
+}
+
+impl QueryRow {
+    pub fn from_report(report: &MatchReport) -> Self {
+        let best = report.results.first();
+        QueryRow {
+            query: report.query.clone(),
+            query_hashes: Some(report.query_hashes),
+            matched: report.matched,
+            track_id: best.map(|result| result.track_id),
+            title: best.map(|result| result.title.clone()),
+            offset_sec: best.map(|result| result.offset_sec),
+            score: best.map(|result| result.score),
+            confidence: best.map(|result| result.confidence),
+            error: None,
+        }
+    }
+
+    pub fn from_error(query: String, error: &anyhow::Error) -> Self {
+        QueryRow {
+            query,
+            query_hashes: None,
+            matched: false,
+            track_id: None,
+            title: None,
+            offset_sec: None,
+            score: None,
+            confidence: None,
+            error: Some(format!("{:#}", error)),
+        }
+    }
+
+    /// Exit code of a match command reporting only this row
+    pub fn exit_code(&self) -> u8 {
+        if self.error.is_some() {
+            EXIT_ERROR
+        } else if self.matched {
+            EXIT_MATCH
+        } else {
+            EXIT_NO_MATCH
+        }
+    }
+}
+
+impl fmt::Display for QueryRow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.error, &self.title) {
+            (Some(error), _) => write!(f, "{}: error: {}", self.query, error),
+            (None, Some(title)) if self.matched => write!(
+                f,
+                "{}: {} ({}, confidence {:.4})",
+                self.query,
+                title,
+                describe_offset(self.offset_sec.unwrap_or_default()),
+                self.confidence.unwrap_or_default()
+            ),
+            (None, _) => write!(f, "{}: no match", self.query),
+        }
+    }
+}
+
+/// Writes rows as they are produced, flushing after each one.
+/// Json output has one object per line so consumers can act on each row as it arrives.
+pub struct RowWriter<W: Write> {
+    format: OutputFormat,
+    writer: RowSink<W>,
+}
+
+enum RowSink<W: Write> {
+    Plain(W),
+    Csv(Box<csv::Writer<W>>),
+}
+
+impl<W: Write> RowWriter<W> {
+    pub fn new(format: OutputFormat, writer: W) -> Self {
+        let writer = match format {
+            OutputFormat::Csv => RowSink::Csv(Box::new(csv::Writer::from_writer(writer))),
+            OutputFormat::Text | OutputFormat::Json => RowSink::Plain(writer),
+        };
+        RowWriter { format, writer }
+    }
+
+    pub fn write<T: Serialize + fmt::Display>(&mut self, row: &T) -> Result<(), anyhow::Error> {
+        match (&mut self.writer, self.format) {
+            (RowSink::Csv(writer), _) => {
+                writer.serialize(row)?;
+                writer.flush()?;
+            }
+            (RowSink::Plain(writer), OutputFormat::Json) => {
+                serde_json::to_writer(&mut *writer, row)?;
+                writeln!(writer)?;
+                writer.flush()?;
+            }
+            (RowSink::Plain(writer), _) => {
+                writeln!(writer, "{}", row)?;
+                writer.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn write_storage_stats(
+    stats: &StorageStats,
+    format: OutputFormat,
+    mut writer: impl Write,
+) -> Result<(), anyhow::Error> {
+    match format {
+        OutputFormat::Text => {
+            writeln!(
+                writer,
+                "Tracks: {}\nFingerprints: {} ({} distinct hashes)",
+                stats.tracks, stats.fingerprints, stats.distinct_hashes
+            )?;
+            writeln!(
+                writer,
+                "Size: {} bytes, {:.1} per fingerprint, compression ratio {:.2}",
+                stats.bytes, stats.bytes_per_fingerprint, stats.compression_ratio
+            )?;
+            writeln!(
+                writer,
+                "Lookup: {:.3}ms per batch of hashes over {} batches, {:.0} fingerprints/s",
+                stats.lookup_ms, stats.lookup_batches, stats.fingerprints_per_sec
+            )?;
+        }
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut writer, stats)?;
+            writeln!(writer)?;
+        }
+        OutputFormat::Csv => {
+            let mut csv_writer = csv::Writer::from_writer(writer);
+            csv_writer.serialize(stats)?;
+            csv_writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+pub fn write_eval_summary(
+    summary: &EvalSummary,
+    format: OutputFormat,
+    mut writer: impl Write,
+) -> Result<(), anyhow::Error> {
+    match format {
+        OutputFormat::Text => {
+            let percent = |rate: f64| rate * 100.;
+            let seconds = |value: Option<f32>| {
+                value.map_or_else(|| "n/a".to_string(), |value| format!("{:.2}s", value))
+            };
+            writeln!(
+                writer,
+                "Queries: {} ({} expected to match, {} errors)",
+                summary.queries, summary.positives, summary.errors
+            )?;
+            writeln!(
+                writer,
+                "Top-1 accuracy: {:.1}%",
+                percent(summary.top1_accuracy)
+            )?;
+            writeln!(
+                writer,
+                "Top-{} accuracy: {:.1}%",
+                summary.top_k,
+                percent(summary.topk_accuracy)
+            )?;
+            writeln!(
+                writer,
+                "Offset error: mean {}, median {}, max {}",
+                seconds(summary.offset_error_mean_sec),
+                seconds(summary.offset_error_median_sec),
+                seconds(summary.offset_error_max_sec)
+            )?;
+            writeln!(writer, "\nthreshold  true accept  false positive")?;
+            for stats in &summary.thresholds {
+                writeln!(
+                    writer,
+                    "{:>9}  {:>10.1}%  {:>13.1}%",
+                    stats.threshold,
+                    percent(stats.true_accept_rate),
+                    percent(stats.false_positive_rate)
+                )?;
+            }
+            let timings = &summary.mean_timings;
+            writeln!(
+                writer,
+                "\nMean time per query: decode {:.1}ms, max filter {:.1}ms, peaks {:.1}ms, fingerprint {:.1}ms, lookup {:.1}ms",
+                timings.decode_ms,
+                timings.max_filter_ms,
+                timings.peaks_ms,
+                timings.fingerprint_ms,
+                timings.lookup_ms
+            )?;
+        }
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut writer, summary)?;
+            writeln!(writer)?;
+        }
+        OutputFormat::Csv => {
+            // the rates at each threshold, the part of the summary that is a table
+            let mut csv_writer = csv::Writer::from_writer(writer);
+            for stats in &summary.thresholds {
+                csv_writer.serialize(stats)?;
+            }
+            csv_writer.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct TuneReport<'a> {
+    trials: &'a [TrialResult],
+    suggested: Option<&'a TrialResult>,
+}
+
+pub fn write_tune_results(
+    results: &[TrialResult],
+    suggested: Option<&TrialResult>,
+    format: OutputFormat,
+    mut writer: impl Write,
+) -> Result<(), anyhow::Error> {
+    match format {
+        OutputFormat::Text => {
+            writeln!(
+                writer,
+                "window  kernel  threshold  delay  height  width   top-1  false pos  hashes/s   db bytes  postings/query  query ms  pareto"
+            )?;
+            for result in results {
+                writeln!(
+                    writer,
+                    "{:>6}  {:>6}  {:>9}  {:>5}  {:>6}  {:>5}  {:>5.1}%  {:>8.1}%  {:>8.1}  {:>9}  {:>14.1}  {:>8.1}  {}",
+                    result.window_length,
+                    result.kernel_size,
+                    result.magnitude_threshold,
+                    result.target_zone_delay_sec,
+                    result.target_zone_height_hz,
+                    result.target_zone_width_sec,
+                    result.top1_accuracy * 100.,
+                    result.false_positive_rate * 100.,
+                    result.hashes_per_sec,
+                    result.db_bytes,
+                    result.postings_per_query,
+                    result.query_ms,
+                    if result.pareto_optimal { "*" } else { "" }
+                )?;
+            }
+            if let Some(best) = suggested {
+                writeln!(
+                    writer,
+                    "\nSuggested: --window-length {} -k {} --magnitude-threshold {} --target-zone-delay-sec {} --target-zone-height-hz {} --target-zone-width-sec {}",
+                    best.window_length,
+                    best.kernel_size,
+                    best.magnitude_threshold,
+                    best.target_zone_delay_sec,
+                    best.target_zone_height_hz,
+                    best.target_zone_width_sec
+                )?;
+            }
+        }
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(
+                &mut writer,
+                &TuneReport {
+                    trials: results,
+                    suggested,
+                },
+            )?;
+            writeln!(writer)?;
+        }
+        OutputFormat::Csv => {
+            let mut csv_writer = csv::Writer::from_writer(writer);
+            for result in results {
+                csv_writer.serialize(result)?;
+            }
+            csv_writer.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(track_id: u32, offset: i64, score: u32, confidence: f64) -> Candidate {
+        Candidate {
+            track_id,
+            title: format!("track {}", track_id),
+            offset,
+            score,
+            confidence,
+        }
+    }
+
+    fn options(top: usize) -> MatchOptions {
+        MatchOptions {
+            top,
+            ..MatchOptions::default()
+        }
+    }
+
+    fn report(best_confidence: f64) -> MatchReport {
+        let candidates = [
+            candidate(7, 40, 30, best_confidence),
+            candidate(3, -20, 12, 0.999),
+            candidate(9, 5, 4, 0.2),
+        ];
+        MatchReport::new("query.wav".to_string(), 250, &candidates, &options(2), 0.1)
+    }
+
+    fn written(report: &MatchReport, format: OutputFormat) -> String {
+        let mut bytes = vec![];
+        write_report(report, format, &mut bytes).unwrap();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn only_the_decided_candidate_of_the_top_is_accepted() {
+        let matched = report(0.9999);
+        let ranked: Vec<(u32, f32, bool)> = matched
+            .results
+            .iter()
+            .map(|result| (result.track_id, result.offset_sec, result.accepted))
+            .collect();
+        assert_eq!(ranked, vec![(7, 4., true), (3, -2., false)]);
+        assert!(matched.matched);
+        assert_eq!(matched.exit_code(), EXIT_MATCH);
+
+        // a confident runner-up isn't accepted in place of the best
+        let unmatched = report(0.5);
+        assert!(!unmatched.matched);
+        assert!(unmatched.results.iter().all(|result| !result.accepted));
+        assert_eq!(unmatched.exit_code(), EXIT_NO_MATCH);
+    }
+
+    #[test]
+    fn reports_are_written_as_json() {
+        let json: serde_json::Value =
+            serde_json::from_str(&written(&report(0.9999), OutputFormat::Json)).unwrap();
+        assert_eq!(json["query"], "query.wav");
+        assert_eq!(json["query_hashes"], 250);
+        assert_eq!(json["matched"], true);
+        let best = &json["results"][0];
+        assert_eq!(best["track_id"], 7);
+        assert_eq!(best["title"], "track 7");
+        assert_eq!(best["offset_sec"], 4.);
+        assert_eq!(best["score"], 30);
+        assert_eq!(best["confidence"], 0.9999);
+        assert_eq!(best["accepted"], true);
+        assert_eq!(json["results"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn reports_are_written_as_a_csv_row_per_result() {
+        let csv = written(&report(0.9999), OutputFormat::Csv);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                "query,query_hashes,track_id,title,offset_sec,score,confidence,accepted",
+                "query.wav,250,7,track 7,4.0,30,0.9999,true",
+                "query.wav,250,3,track 3,-2.0,12,0.999,false",
+            ]
+        );
+    }
+
+    #[test]
+    fn text_reports_say_whether_there_was_a_match() {
+        assert!(written(&report(0.9999), OutputFormat::Text)
+            .contains("Match: track 7 (sample starts 4.0s into track, confidence 0.9999)"));
+        assert!(written(&report(0.5), OutputFormat::Text).ends_with("\nNo match\n"));
+    }
+
+    #[test]
+    fn offsets_are_described_from_whichever_starts_first() {
+        assert_eq!(describe_offset(12.34), "sample starts 12.3s into track");
+        assert_eq!(describe_offset(0.), "sample starts 0.0s into track");
+        assert_eq!(describe_offset(-3.), "track starts 3.0s into sample");
+    }
+
+    fn rows() -> Vec<QueryRow> {
+        vec![
+            QueryRow::from_report(&report(0.9999)),
+            QueryRow::from_report(&report(0.5)),
+            QueryRow::from_error(
+                "broken.wav".to_string(),
+                &anyhow::anyhow!("Could not read it.").context("Could not open broken.wav."),
+            ),
+        ]
+    }
+
+    fn written_rows(format: OutputFormat) -> Vec<String> {
+        let mut bytes = vec![];
+        let mut row_writer = RowWriter::new(format, &mut bytes);
+        for row in rows() {
+            row_writer.write(&row).unwrap();
+        }
+        drop(row_writer);
+        String::from_utf8(bytes)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn query_rows_carry_the_best_result_or_the_error() {
+        let codes: Vec<u8> = rows().iter().map(QueryRow::exit_code).collect();
+        assert_eq!(codes, vec![EXIT_MATCH, EXIT_NO_MATCH, EXIT_ERROR]);
+        assert_eq!(
+            written_rows(OutputFormat::Text),
+            vec![
+                "query.wav: track 7 (sample starts 4.0s into track, confidence 0.9999)",
+                "query.wav: no match",
+                "broken.wav: error: Could not open broken.wav.: Could not read it.",
+            ]
+        );
+    }
+
+    #[test]
+    fn query_rows_are_written_one_json_object_per_line() {
+        let lines = written_rows(OutputFormat::Json);
+        assert_eq!(lines.len(), 3);
+        let rows: Vec<serde_json::Value> = lines
+            .iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(rows[0]["title"], "track 7");
+        assert_eq!(rows[0]["query_hashes"], 250);
+        assert_eq!(rows[1]["matched"], false);
+        assert_eq!(rows[1]["track_id"], 7); // the best ranked, even if not accepted
+        assert_eq!(rows[2]["track_id"], serde_json::Value::Null);
+        assert_eq!(
+            rows[2]["error"],
+            "Could not open broken.wav.: Could not read it."
+        );
+    }
+
+    #[test]
+    fn query_rows_are_written_under_one_csv_header() {
+        assert_eq!(
+            written_rows(OutputFormat::Csv),
+            vec![
+                "query,query_hashes,matched,track_id,title,offset_sec,score,confidence,error",
+                "query.wav,250,true,7,track 7,4.0,30,0.9999,",
+                "query.wav,250,false,7,track 7,4.0,30,0.5,",
+                "broken.wav,,false,,,,,,Could not open broken.wav.: Could not read it.",
+            ]
+        );
+    }
+
+    #[test]
+    fn stream_events_are_written_as_they_happen() {
+        let event = StreamEvent {
+            stream_time_sec: 7.,
+            latency_sec: 4.,
+            track_id: 7,
+            title: "track 7".to_string(),
+            track_offset_sec: 12.5,
+            confidence: 0.995,
+            threshold: 0.99,
+        };
+        let mut text = vec![];
+        RowWriter::new(OutputFormat::Text, &mut text)
+            .write(&event)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(text).unwrap(),
+            "     7.0s: track 7 (track_id: 7, 12.5s into track, confidence 0.9950 >= 0.99, latency 4.0s)\n"
+        );
+
+        let mut json = vec![];
+        RowWriter::new(OutputFormat::Json, &mut json)
+            .write(&event)
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json["latency_sec"], 4.);
+        assert_eq!(json["track_offset_sec"], 12.5);
+        assert_eq!(json["threshold"], 0.99);
+    }
+
+    #[test]
+    fn timelines_are_written_in_each_format() {
+        let timeline = vec![TimelineRow {
+            start_sec: 0.,
+            end_sec: 18.,
+            track_id: 1,
+            title: "track 1".to_string(),
+            track_offset_sec: 5.,
+            confidence: 1.,
+        }];
+        let written = |timeline: &[TimelineRow], format| {
+            let mut bytes = vec![];
+            write_timeline(timeline, format, &mut bytes).unwrap();
+            String::from_utf8(bytes).unwrap()
+        };
+
+        assert_eq!(
+            written(&timeline, OutputFormat::Text),
+            "     0.0s -     18.0s: track 1 (track_id: 1, from 5.0s into track, confidence 1.0000)\n"
+        );
+        assert_eq!(written(&[], OutputFormat::Text), "No matches\n");
+        let json: serde_json::Value =
+            serde_json::from_str(&written(&timeline, OutputFormat::Json)).unwrap();
+        assert_eq!(json[0]["end_sec"], 18.);
+        assert_eq!(json[0]["track_offset_sec"], 5.);
+        assert_eq!(
+            written(&timeline, OutputFormat::Csv),
+            "start_sec,end_sec,track_id,title,track_offset_sec,confidence\n0.0,18.0,1,track 1,5.0,1.0\n"
+        );
+    }
+}
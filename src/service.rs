@@ -0,0 +1,228 @@
+//! Matching and cataloguing uploaded recordings for long-lived servers, which keep the
+//! index and its storage open between requests.
+
+use std::{
+    collections::HashMap,
+    ops::{Deref, DerefMut},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Condvar, Mutex,
+    },
+};
+
+use anyhow::Context;
+use serde::Serialize;
+
+use crate::{
+    audio_ops,
+    eval::StageTimings,
+    fingerprint_file::{self, FingerprintFile},
+    hash::PairRecord,
+    report::MatchReport,
+    storage::{self, StorageStats},
+    Fingerprinter, Index, MatchOptions, Matcher, Storage,
+};
+
+/// The fingerprints of a recording sent to a server, as wav audio or a fingerprint file
+pub struct Upload {
+    pub title: Option<String>, // given in a fingerprint file
+    pub hashes: HashMap<u32, PairRecord>,
+}
+
+impl Upload {
+    /// Fingerprints wav data or reads fingerprint file data. Fails on anything else, and
+    /// on fingerprints made with parameters other than the fingerprinter's.
+    pub fn parse(bytes: &[u8], fingerprinter: &Fingerprinter) -> Result<Self, anyhow::Error> {
+        if fingerprint_file::is_fingerprint_data(bytes) {
+            let fingerprints = FingerprintFile::from_bytes(bytes)
+                .context("The upload is not a valid fingerprint file.")?;
+            fingerprints.check_params(fingerprinter.params())?;
+            return Ok(Upload {
+                hashes: fingerprints.hashes(),
+                title: Some(fingerprints.title),
+            });
+        }
+        let (samples, sample_rate) = audio_ops::decode_wav_mono(bytes)
+            .context("The upload is neither a fingerprint file nor 16 bit wav audio.")?;
+        let analysis =
+            fingerprinter.analyse_samples(&samples, sample_rate, &mut StageTimings::default())?;
+        Ok(Upload {
+            title: None,
+            hashes: analysis.hashes,
+        })
+    }
+}
+
+/// A track added from an upload
+#[derive(Serialize, Debug, Clone)]
+pub struct AddedTrack {
+    pub track_id: u32,
+    pub title: String,
+    pub fingerprints: usize,
+}
+
+/// The catalogue behind a server, shared by the threads serving requests. Uploads are
+/// fingerprinted concurrently and matched by a pool of readers, each with its own handle
+/// on the storage, while adds and removals take turns with the single writer.
+pub struct Service<S: Storage> {
+    fingerprinter: Fingerprinter,
+    options: MatchOptions,
+    lookups: usize, // stored hashes looked up to time lookups in stats
+    readers: Mutex<Vec<(u64, Matcher<S>)>>, // idle, with the generation of the tracks seen
+    reader_returned: Condvar,
+    writer: Mutex<Index<S>>,
+    generation: AtomicU64, // of the tracks, counting the writes made
+    stats: Mutex<Option<(u64, StorageStats)>>, // last measured, with the generation then
+}
+
+/// A reader taken from the pool, put back when dropped
+struct Reader<'a, S: Storage> {
+    service: &'a Service<S>,
+    matcher: Option<Matcher<S>>,
+    generation: u64,
+}
+
+impl<S: Storage> Deref for Reader<'_, S> {
+    type Target = Matcher<S>;
+
+    fn deref(&self) -> &Matcher<S> {
+        self.matcher
+            .as_ref()
+            .expect("A reader is only emptied when dropped.")
+    }
+}
+
+impl<S: Storage> DerefMut for Reader<'_, S> {
+    fn deref_mut(&mut self) -> &mut Matcher<S> {
+        self.matcher
+            .as_mut()
+            .expect("A reader is only emptied when dropped.")
+    }
+}
+
+impl<S: Storage> Drop for Reader<'_, S> {
+    fn drop(&mut self) {
+        if let (Some(matcher), Ok(mut idle)) = (self.matcher.take(), self.service.readers.lock()) {
+            idle.push((self.generation, matcher));
+            self.service.reader_returned.notify_one();
+        }
+    }
+}
+
+fn failed<T>(_: T) -> anyhow::Error {
+    anyhow::anyhow!("The catalogue failed during an earlier request.")
+}
+
+impl<S: Storage> Service<S> {
+    /// A service matching with the `readers`, which must all be handles on the same
+    /// storage as `writer`, the only handle used to add and remove tracks
+    pub fn new(
+        fingerprinter: Fingerprinter,
+        options: MatchOptions,
+        lookups: usize,
+        writer: Index<S>,
+        readers: Vec<Matcher<S>>,
+    ) -> Result<Self, anyhow::Error> {
+        if readers.is_empty() {
+            anyhow::bail!("The service needs at least one reader.");
+        }
+        Ok(Service {
+            fingerprinter,
+            options,
+            lookups,
+            readers: Mutex::new(readers.into_iter().map(|reader| (0, reader)).collect()),
+            reader_returned: Condvar::new(),
+            writer: Mutex::new(writer),
+            generation: AtomicU64::new(0),
+            stats: Mutex::new(None),
+        })
+    }
+
+    pub fn fingerprinter(&self) -> &Fingerprinter {
+        &self.fingerprinter
+    }
+
+    /// Waits for an idle reader, which forgets the titles it has seen if tracks have
+    /// been written since
+    fn reader(&self) -> Result<Reader<'_, S>, anyhow::Error> {
+        let mut idle = self.readers.lock().map_err(failed)?;
+        let (seen, mut matcher) = loop {
+            match idle.pop() {
+                Some(reader) => break reader,
+                None => idle = self.reader_returned.wait(idle).map_err(failed)?,
+            }
+        };
+        drop(idle);
+        let generation = self.generation.load(Ordering::Acquire);
+        if seen != generation {
+            matcher.forget_titles();
+        }
+        Ok(Reader {
+            service: self,
+            matcher: Some(matcher),
+            generation,
+        })
+    }
+
+    /// Runs a write with the writer, then has readers forget the titles they've seen
+    fn write<T>(
+        &self,
+        write: impl FnOnce(&mut Index<S>) -> Result<T, anyhow::Error>,
+    ) -> Result<T, anyhow::Error> {
+        let mut writer = self.writer.lock().map_err(failed)?;
+        let result = write(&mut writer);
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        result
+    }
+
+    /// Ranks the tracks an upload could be, as the match command does
+    pub fn match_upload(&self, query: &str, upload: &Upload) -> Result<MatchReport, anyhow::Error> {
+        let (_, report) = self.reader()?.match_hashes(
+            query,
+            &upload.hashes,
+            self.fingerprinter.params().window_length,
+            &self.options,
+            &mut StageTimings::default(),
+        )?;
+        Ok(report)
+    }
+
+    /// Adds an upload as a track, replacing any track with the same title. The path of
+    /// its `audio` is recorded if it is known, so matches can be explained.
+    pub fn add_upload(
+        &self,
+        title: &str,
+        audio: Option<&Path>,
+        upload: &Upload,
+    ) -> Result<AddedTrack, anyhow::Error> {
+        let track_id = self.write(|index| {
+            index.record_params(self.fingerprinter.params())?;
+            index.add_track(title, audio, &upload.hashes)
+        })?;
+        Ok(AddedTrack {
+            track_id,
+            title: title.to_string(),
+            fingerprints: upload.hashes.len(),
+        })
+    }
+
+    /// Removes the track with the title. Returns whether there was one.
+    pub fn remove_track(&self, title: &str) -> Result<bool, anyhow::Error> {
+        self.write(|index| index.remove_track(title))
+    }
+
+    /// Measures the catalogue with a reader, while the others keep serving. The
+    /// measurement is kept until tracks are next added or removed.
+    pub fn stats(&self) -> Result<StorageStats, anyhow::Error> {
+        let generation = self.generation.load(Ordering::Acquire);
+        if let Some((measured, stats)) = *self.stats.lock().map_err(failed)? {
+            if measured == generation {
+                return Ok(stats);
+            }
+        }
+        let stats = storage::measure(self.reader()?.index().storage(), self.lookups)?;
+        *self.stats.lock().map_err(failed)? = Some((generation, stats));
+        Ok(stats)
+    }
+}
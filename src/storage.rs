@@ -0,0 +1,401 @@
+//! Where the tracks and fingerprints of an [`Index`](crate::Index) are kept
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use rand::{rngs::StdRng, seq::IteratorRandom, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::{hash::PairRecord, AnalysisParams};
+
+mod inverted;
+mod kv;
+mod memory;
+mod packed;
+mod sharded;
+mod sqlite;
+
+pub use inverted::{BuildSummary, InvertedIndex};
+pub use kv::KvStorage;
+pub use memory::MemoryStorage;
+pub use packed::PackedStorage;
+pub use sharded::ShardedStorage;
+pub use sqlite::SqliteStorage;
+
+/// The kinds of storage a database file can be kept in
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageKind {
+    Sqlite,
+    Packed,   // SQLite with the fingerprints packed into a blob per bucket of hashes
+    Kv,       // embedded key-value store
+    Inverted, // read-only memory-mapped index made by `index build`
+}
+
+/// Opens the database at `path` with the given kind of storage, creating it if it
+/// doesn't yet exist and can be written to
+pub fn open(kind: StorageKind, path: &Path) -> Result<Box<dyn Storage>, anyhow::Error> {
+    Ok(match kind {
+        StorageKind::Sqlite => Box::new(SqliteStorage::open(path)?),
+        StorageKind::Packed => Box::new(PackedStorage::open(path)?),
+        StorageKind::Kv => Box::new(KvStorage::open(path)?),
+        StorageKind::Inverted => Box::new(InvertedIndex::open(path)?),
+    })
+}
+
+/// Opens a catalogue kept in one database, or split across the shards at `paths` in the
+/// order they were made. A shard of a larger catalogue can't be opened on its own.
+pub fn open_catalogue(
+    kind: StorageKind,
+    paths: &[PathBuf],
+) -> Result<Box<dyn Storage>, anyhow::Error> {
+    match paths {
+        [path] => {
+            let storage = open(kind, path)?;
+            match storage.shard_position()? {
+                Some(position) if position.count > 1 => anyhow::bail!(
+                    "{} is shard {} of {}, give every shard of the catalogue.",
+                    path.display(),
+                    position.index,
+                    position.count
+                ),
+                _ => Ok(storage),
+            }
+        }
+        shards => Ok(Box::new(ShardedStorage::open(kind, shards)?)),
+    }
+}
+
+/// Bytes of a fingerprint's hash, track id and time as plain 32 bit integers
+const RAW_FINGERPRINT_BYTES: u64 = 12;
+
+/// Hashes looked up together when timing lookups, about as many as a short sample has
+const LOOKUP_BATCH: usize = 100;
+
+/// How big a store is and how fast it is to look hashes up in
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct StorageStats {
+    pub tracks: usize,
+    pub fingerprints: usize,
+    pub distinct_hashes: usize,
+    pub bytes: u64,
+    pub bytes_per_fingerprint: f64,
+    pub compression_ratio: f64, // size of the fingerprints as plain integers over `bytes`
+    pub lookup_batches: usize,  // of LOOKUP_BATCH stored hashes, chosen at random
+    pub lookup_ms: f64,         // mean per batch
+    pub fingerprints_per_sec: f64, // found by the lookups
+}
+
+/// Measures the size of a store and times looking up `lookups` of its hashes
+pub fn measure(storage: &impl Storage, lookups: usize) -> Result<StorageStats, anyhow::Error> {
+    let tracks = storage.tracks()?.len();
+    let mut fingerprints = 0;
+    let mut hashes = HashSet::new();
+    storage.scan_fingerprints(&mut |fingerprint| {
+        fingerprints += 1;
+        hashes.insert(fingerprint.hash);
+        Ok(())
+    })?;
+    let bytes = storage.size_bytes()?;
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let sample = hashes.iter().copied().choose_multiple(&mut rng, lookups);
+    let start = Instant::now();
+    let mut found = 0;
+    for batch in sample.chunks(LOOKUP_BATCH) {
+        found += storage.lookup(batch)?.len();
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    let lookup_batches = sample.chunks(LOOKUP_BATCH).len();
+
+    Ok(StorageStats {
+        tracks,
+        fingerprints,
+        distinct_hashes: hashes.len(),
+        bytes,
+        bytes_per_fingerprint: bytes as f64 / fingerprints.max(1) as f64,
+        compression_ratio: (fingerprints as u64 * RAW_FINGERPRINT_BYTES) as f64
+            / bytes.max(1) as f64,
+        lookup_batches,
+        lookup_ms: elapsed * 1000. / lookup_batches.max(1) as f64,
+        fingerprints_per_sec: found as f64 / elapsed.max(f64::EPSILON),
+    })
+}
+
+/// A hash of a reference track and when it occurs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fingerprint {
+    pub hash: u32,
+    pub track_time: u32, // in windows
+    pub track_id: u32,
+}
+
+/// A reference track
+#[derive(Debug, Clone)]
+pub struct Track {
+    pub title: String,
+    pub path: Option<PathBuf>, // audio the track was added from, if known
+}
+
+/// Which shard of a catalogue a store is, which decides the tracks routed to it and the
+/// ids they are given
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShardPosition {
+    pub index: u16,
+    pub count: u16, // shards in the catalogue
+}
+
+/// A store of tracks and their fingerprints
+pub trait Storage: Send {
+    /// Adds a track and its fingerprints, replacing any track with the same title, which
+    /// keeps its id. Either all of it is stored or, if it fails, none. Returns the id.
+    /// Ids of removed tracks aren't given to new ones.
+    fn replace_track(
+        &mut self,
+        title: &str,
+        path: Option<&Path>,
+        pair_records: &HashMap<u32, PairRecord>,
+    ) -> Result<u32, anyhow::Error>;
+
+    /// Removes a track and its fingerprints. Returns whether it existed.
+    fn delete_track(&mut self, track_id: u32) -> Result<bool, anyhow::Error>;
+
+    /// Every fingerprint of every track with one of the hashes
+    fn lookup(&self, hashes: &[u32]) -> Result<Vec<Fingerprint>, anyhow::Error>;
+
+    /// Id of the track with the title, if there is one
+    fn find_track(&self, title: &str) -> Result<Option<u32>, anyhow::Error>;
+
+    fn track(&self, track_id: u32) -> Result<Option<Track>, anyhow::Error>;
+
+    /// Every track, ordered by id
+    fn tracks(&self) -> Result<Vec<(u32, Track)>, anyhow::Error>;
+
+    /// Calls `visit` with every fingerprint of every track, in no particular order
+    fn scan_fingerprints(
+        &self,
+        visit: &mut dyn FnMut(Fingerprint) -> Result<(), anyhow::Error>,
+    ) -> Result<(), anyhow::Error>;
+
+    /// Space taken by the store, in bytes
+    fn size_bytes(&self) -> Result<u64, anyhow::Error>;
+
+    /// The analysis parameters the fingerprints were made with, if recorded. Stores made
+    /// before they were recorded don't say.
+    fn params(&self) -> Result<Option<AnalysisParams>, anyhow::Error>;
+
+    /// Records the analysis parameters the fingerprints are made with
+    fn set_params(&mut self, params: AnalysisParams) -> Result<(), anyhow::Error>;
+
+    /// Which shard of a catalogue the store is, if it was made as one
+    fn shard_position(&self) -> Result<Option<ShardPosition>, anyhow::Error>;
+
+    /// Records which shard of a catalogue the store is
+    fn set_shard_position(&mut self, position: ShardPosition) -> Result<(), anyhow::Error>;
+}
+
+impl<S: Storage + ?Sized> Storage for Box<S> {
+    fn replace_track(
+        &mut self,
+        title: &str,
+        path: Option<&Path>,
+        pair_records: &HashMap<u32, PairRecord>,
+    ) -> Result<u32, anyhow::Error> {
+        (**self).replace_track(title, path, pair_records)
+    }
+
+    fn delete_track(&mut self, track_id: u32) -> Result<bool, anyhow::Error> {
+        (**self).delete_track(track_id)
+    }
+
+    fn lookup(&self, hashes: &[u32]) -> Result<Vec<Fingerprint>, anyhow::Error> {
+        (**self).lookup(hashes)
+    }
+
+    fn find_track(&self, title: &str) -> Result<Option<u32>, anyhow::Error> {
+        (**self).find_track(title)
+    }
+
+    fn track(&self, track_id: u32) -> Result<Option<Track>, anyhow::Error> {
+        (**self).track(track_id)
+    }
+
+    fn tracks(&self) -> Result<Vec<(u32, Track)>, anyhow::Error> {
+        (**self).tracks()
+    }
+
+    fn scan_fingerprints(
+        &self,
+        visit: &mut dyn FnMut(Fingerprint) -> Result<(), anyhow::Error>,
+    ) -> Result<(), anyhow::Error> {
+        (**self).scan_fingerprints(visit)
+    }
+
+    fn size_bytes(&self) -> Result<u64, anyhow::Error> {
+        (**self).size_bytes()
+    }
+
+    fn params(&self) -> Result<Option<AnalysisParams>, anyhow::Error> {
+        (**self).params()
+    }
+
+    fn set_params(&mut self, params: AnalysisParams) -> Result<(), anyhow::Error> {
+        (**self).set_params(params)
+    }
+
+    fn shard_position(&self) -> Result<Option<ShardPosition>, anyhow::Error> {
+        (**self).shard_position()
+    }
+
+    fn set_shard_position(&mut self, position: ShardPosition) -> Result<(), anyhow::Error> {
+        (**self).set_shard_position(position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hashes(values: &[(u32, u32)]) -> HashMap<u32, PairRecord> {
+        values
+            .iter()
+            .map(|&(hash, time_a)| (hash, PairRecord { hash, time_a }))
+            .collect()
+    }
+
+    /// (track id, time) of each fingerprint with the hash
+    fn lookup(storage: &impl Storage, hash: u32) -> HashSet<(u32, u32)> {
+        storage
+            .lookup(&[hash])
+            .unwrap()
+            .into_iter()
+            .map(|fingerprint| (fingerprint.track_id, fingerprint.track_time))
+            .collect()
+    }
+
+    /// Re-adding a track keeps its id and replaces its fingerprints, and removed ids
+    /// aren't given out again
+    fn replaces_tracks(mut storage: impl Storage) {
+        let first = storage
+            .replace_track("a", None, &hashes(&[(1, 10), (2, 20)]))
+            .unwrap();
+        let second = storage
+            .replace_track("b", None, &hashes(&[(1, 30)]))
+            .unwrap();
+        assert_ne!(first, second);
+        assert_eq!(
+            lookup(&storage, 1),
+            HashSet::from([(first, 10), (second, 30)])
+        );
+
+        let replaced = storage
+            .replace_track("a", Some(Path::new("a.wav")), &hashes(&[(1, 11), (3, 5)]))
+            .unwrap();
+        assert_eq!(replaced, first);
+        assert_eq!(
+            lookup(&storage, 1),
+            HashSet::from([(first, 11), (second, 30)])
+        );
+        assert!(lookup(&storage, 2).is_empty());
+        assert_eq!(lookup(&storage, 3), HashSet::from([(first, 5)]));
+        assert_eq!(
+            storage.track(first).unwrap().unwrap().path,
+            Some(PathBuf::from("a.wav"))
+        );
+        assert_eq!(storage.tracks().unwrap().len(), 2);
+
+        assert!(storage.delete_track(second).unwrap());
+        assert!(!storage.delete_track(second).unwrap());
+        let third = storage
+            .replace_track("c", None, &hashes(&[(1, 40)]))
+            .unwrap();
+        assert!(third != first && third != second);
+        assert_eq!(
+            lookup(&storage, 1),
+            HashSet::from([(first, 11), (third, 40)])
+        );
+    }
+
+    fn params() -> AnalysisParams {
+        AnalysisParams {
+            window_length: 0.05,
+            kernel_size: 12,
+            magnitude_threshold: 0.25,
+            target_zone_delay_sec: 0.1,
+            target_zone_height_hz: 500.,
+            target_zone_width_sec: 2.,
+        }
+    }
+
+    #[test]
+    fn stores_record_their_params() {
+        let dir = tempfile::tempdir().unwrap();
+        let stores: Vec<Box<dyn Storage>> = vec![
+            Box::new(MemoryStorage::default()),
+            Box::new(SqliteStorage::in_memory().unwrap()),
+            Box::new(PackedStorage::open(&dir.path().join("packed.db3")).unwrap()),
+            Box::new(KvStorage::open(&dir.path().join("kv.redb")).unwrap()),
+        ];
+        for mut storage in stores {
+            assert_eq!(storage.params().unwrap(), None);
+            storage.set_params(params()).unwrap();
+            assert_eq!(storage.params().unwrap(), Some(params()));
+        }
+
+        // and keep them when opened again
+        let path = dir.path().join("packed.db3");
+        assert_eq!(
+            PackedStorage::open(&path).unwrap().params().unwrap(),
+            Some(params())
+        );
+    }
+
+    #[test]
+    fn inverted_indexes_keep_the_params_of_their_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.inv");
+        let mut source = MemoryStorage::default();
+        InvertedIndex::build(&source, &path).unwrap();
+        assert_eq!(InvertedIndex::open(&path).unwrap().params().unwrap(), None);
+
+        source.set_params(params()).unwrap();
+        InvertedIndex::build(&source, &path).unwrap();
+        assert_eq!(
+            InvertedIndex::open(&path).unwrap().params().unwrap(),
+            Some(params())
+        );
+    }
+
+    #[test]
+    fn memory_storage_replaces_tracks() {
+        replaces_tracks(MemoryStorage::default());
+    }
+
+    #[test]
+    fn sqlite_storage_replaces_tracks() {
+        replaces_tracks(SqliteStorage::in_memory().unwrap());
+    }
+
+    #[test]
+    fn packed_storage_replaces_tracks() {
+        let dir = tempfile::tempdir().unwrap();
+        replaces_tracks(PackedStorage::open(&dir.path().join("packed.db3")).unwrap());
+    }
+
+    #[test]
+    fn kv_storage_replaces_tracks() {
+        let dir = tempfile::tempdir().unwrap();
+        replaces_tracks(KvStorage::open(&dir.path().join("kv.redb")).unwrap());
+    }
+
+    #[test]
+    fn sharded_storage_replaces_tracks() {
+        let shards: Vec<Box<dyn Storage>> = vec![
+            Box::new(MemoryStorage::default()),
+            Box::new(MemoryStorage::default()),
+        ];
+        replaces_tracks(ShardedStorage::new(shards).unwrap());
+    }
+}
@@ -0,0 +1,599 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use itertools::Itertools;
+use memmap2::Mmap;
+
+use super::{Fingerprint, ShardPosition, Storage, Track};
+use crate::varint::{read_varint, write_varint};
+use crate::{hash::PairRecord, AnalysisParams};
+
+const MAGIC: &[u8; 8] = b"ATLASINV";
+const VERSION: u32 = 2;
+const HEADER_LEN: usize = 96;
+const SHARD_AT: usize = 12; // shard index u16 and shard count u16, or 0 if not a shard
+const PARAMS_AT: usize = 64; // known u32, then the analysis parameters, in the header
+const KEY_LEN: usize = 16; // hash u32, postings count u32, postings start u64
+const TRACK_LEN: usize = 24; // id u32, title length u32, path length u32, unused u32, strings start u64
+const NO_PATH: u32 = u32::MAX;
+
+/// An immutable inverted index built from another storage, read through a memory map.
+///
+/// All integers are little-endian. After a 96 byte header, which records which shard of
+/// a catalogue the source was and ends with the analysis parameters the fingerprints were
+/// made with, if the source recorded them, the file holds
+/// - the keys: each hash, sorted, with the number and start of its postings
+/// - the postings of each hash: (track_id, track_time) sorted by track then time, as
+///   varints of the difference from the previous posting's track id and either the
+///   time or, within the same track, the difference from the previous time
+/// - the tracks, sorted by id, and the strings of their titles and paths
+pub struct InvertedIndex {
+    map: Mmap,
+    key_count: usize,
+    track_count: usize,
+    postings_start: usize,
+    tracks_start: usize,
+    strings_start: usize,
+    params: Option<AnalysisParams>,
+    shard_position: Option<ShardPosition>,
+}
+
+/// What went into a built index
+#[derive(Debug, Clone, Copy)]
+pub struct BuildSummary {
+    pub tracks: usize,
+    pub hashes: usize,   // distinct hashes
+    pub postings: usize, // fingerprints
+    pub bytes: u64,
+}
+
+impl InvertedIndex {
+    /// Writes the tracks and fingerprints of `source` to an index file at `path`. The
+    /// file is written alongside and renamed into place, so readers of an existing
+    /// index at `path` keep seeing a consistent file.
+    pub fn build(source: &impl Storage, path: &Path) -> Result<BuildSummary, anyhow::Error> {
+        let tracks = source.tracks()?;
+        let mut fingerprints = vec![];
+        source.scan_fingerprints(&mut |fingerprint| {
+            fingerprints.push((
+                fingerprint.hash,
+                fingerprint.track_id,
+                fingerprint.track_time,
+            ));
+            Ok(())
+        })?;
+        fingerprints.sort_unstable();
+
+        // encode the postings of each hash, keeping where they start
+        let mut keys: Vec<(u32, u32, u64)> = vec![];
+        let mut postings = vec![];
+        for (hash, group) in &fingerprints.iter().group_by(|entry| entry.0) {
+            let start = postings.len() as u64;
+            let mut count = 0u32;
+            let (mut previous_track, mut previous_time) = (0, 0);
+            for &(_, track_id, track_time) in group {
+                write_varint(&mut postings, track_id - previous_track);
+                if count > 0 && track_id == previous_track {
+                    write_varint(&mut postings, track_time - previous_time);
+                } else {
+                    write_varint(&mut postings, track_time);
+                }
+                (previous_track, previous_time) = (track_id, track_time);
+                count += 1;
+            }
+            keys.push((hash, count, start));
+        }
+
+        let mut strings = vec![];
+        let mut track_entries = Vec::with_capacity(tracks.len() * TRACK_LEN);
+        for (track_id, track) in &tracks {
+            let path = track
+                .path
+                .as_ref()
+                .map(|path| path.to_string_lossy().to_string());
+            track_entries.extend(track_id.to_le_bytes());
+            track_entries.extend((track.title.len() as u32).to_le_bytes());
+            track_entries.extend(
+                path.as_ref()
+                    .map_or(NO_PATH, |path| path.len() as u32)
+                    .to_le_bytes(),
+            );
+            track_entries.extend(0u32.to_le_bytes());
+            track_entries.extend((strings.len() as u64).to_le_bytes());
+            strings.extend(track.title.as_bytes());
+            strings.extend(path.unwrap_or_default().as_bytes());
+        }
+
+        let postings_start = HEADER_LEN + keys.len() * KEY_LEN;
+        let tracks_start = postings_start + postings.len();
+        let strings_start = tracks_start + track_entries.len();
+        let file_len = strings_start + strings.len();
+
+        let temporary_path = path.with_extension("building");
+        let file = fs::File::create(&temporary_path)
+            .with_context(|| format!("Could not create {}.", temporary_path.display()))?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&encode_shard_position(source.shard_position()?))?;
+        for value in [
+            keys.len(),
+            tracks.len(),
+            postings_start,
+            tracks_start,
+            strings_start,
+            file_len,
+        ] {
+            writer.write_all(&(value as u64).to_le_bytes())?;
+        }
+        writer.write_all(&encode_params(source.params()?))?;
+        for (hash, count, start) in &keys {
+            writer.write_all(&hash.to_le_bytes())?;
+            writer.write_all(&count.to_le_bytes())?;
+            writer.write_all(&start.to_le_bytes())?;
+        }
+        writer.write_all(&postings)?;
+        writer.write_all(&track_entries)?;
+        writer.write_all(&strings)?;
+        writer.into_inner()?.sync_all()?;
+        fs::rename(&temporary_path, path)?;
+
+        Ok(BuildSummary {
+            tracks: tracks.len(),
+            hashes: keys.len(),
+            postings: fingerprints.len(),
+            bytes: file_len as u64,
+        })
+    }
+
+    /// Maps the index file at `path`, checking its header
+    pub fn open(path: &Path) -> Result<Self, anyhow::Error> {
+        let file = fs::File::open(path)
+            .with_context(|| format!("Could not open index {}.", path.display()))?;
+        // Safety: index files are never modified in place, only replaced by renaming a
+        // new file over them, so the mapped bytes don't change while they are read
+        let map = unsafe { Mmap::map(&file)? };
+
+        if map.get(..MAGIC.len()) != Some(MAGIC) {
+            anyhow::bail!("{} is not an inverted index.", path.display());
+        }
+        let version = read_u32(&map, 8).context("Index header is truncated.")?;
+        if version != VERSION {
+            anyhow::bail!(
+                "Index version {} is not supported, rebuild it with `index build`.",
+                version
+            );
+        }
+        let field = |number: usize| {
+            read_u64(&map, 16 + 8 * number)
+                .map(|value| value as usize)
+                .context("Index header is truncated.")
+        };
+        let key_count = field(0)?;
+        let track_count = field(1)?;
+        let postings_start = field(2)?;
+        let tracks_start = field(3)?;
+        let strings_start = field(4)?;
+        let file_len = field(5)?;
+
+        let sections_fit = key_count
+            .checked_mul(KEY_LEN)
+            .and_then(|keys_len| keys_len.checked_add(HEADER_LEN))
+            == Some(postings_start)
+            && postings_start <= tracks_start
+            && track_count
+                .checked_mul(TRACK_LEN)
+                .and_then(|tracks_len| tracks_len.checked_add(tracks_start))
+                == Some(strings_start)
+            && strings_start <= file_len
+            && file_len == map.len();
+        if !sections_fit {
+            anyhow::bail!("Index {} is truncated or corrupt.", path.display());
+        }
+
+        let params = decode_params(&map[PARAMS_AT..HEADER_LEN])?;
+        let shard_position = decode_shard_position(&map[SHARD_AT..SHARD_AT + 4]);
+
+        Ok(InvertedIndex {
+            map,
+            key_count,
+            track_count,
+            postings_start,
+            tracks_start,
+            strings_start,
+            params,
+            shard_position,
+        })
+    }
+
+    /// Number of distinct hashes
+    pub fn key_count(&self) -> usize {
+        self.key_count
+    }
+
+    /// The hash, postings count and postings range of a key
+    fn key(&self, index: usize) -> Option<(u32, u32, std::ops::Range<usize>)> {
+        let at = HEADER_LEN + index * KEY_LEN;
+        let hash = read_u32(&self.map, at)?;
+        let count = read_u32(&self.map, at + 4)?;
+        let start = self
+            .postings_start
+            .checked_add(read_u64(&self.map, at + 8)? as usize)?;
+        let end = if index + 1 < self.key_count {
+            self.postings_start
+                .checked_add(read_u64(&self.map, at + KEY_LEN + 8)? as usize)?
+        } else {
+            self.tracks_start
+        };
+        (start <= end && end <= self.tracks_start).then_some((hash, count, start..end))
+    }
+
+    /// Position of the key for `hash`, by binary search over the sorted keys
+    fn find_key(&self, hash: u32) -> Result<Option<usize>, anyhow::Error> {
+        let (mut low, mut high) = (0, self.key_count);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            let key = read_u32(&self.map, HEADER_LEN + middle * KEY_LEN).context(CORRUPT)?;
+            match key.cmp(&hash) {
+                std::cmp::Ordering::Less => low = middle + 1,
+                std::cmp::Ordering::Greater => high = middle,
+                std::cmp::Ordering::Equal => return Ok(Some(middle)),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Decodes the postings of a key, passing each to `visit`
+    fn decode_postings(
+        &self,
+        index: usize,
+        visit: &mut dyn FnMut(Fingerprint) -> Result<(), anyhow::Error>,
+    ) -> Result<(), anyhow::Error> {
+        let (hash, count, range) = self.key(index).context(CORRUPT)?;
+        let postings = &self.map[range];
+        let mut position = 0;
+        let (mut track_id, mut track_time) = (0u32, 0u32);
+        for posting in 0..count {
+            let track_delta = read_varint(postings, &mut position).context(CORRUPT)?;
+            let time = read_varint(postings, &mut position).context(CORRUPT)?;
+            track_id = track_id.checked_add(track_delta).context(CORRUPT)?;
+            track_time = if posting > 0 && track_delta == 0 {
+                track_time.checked_add(time).context(CORRUPT)?
+            } else {
+                time
+            };
+            visit(Fingerprint {
+                hash,
+                track_time,
+                track_id,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// The track entry at a position in the sorted tracks
+    fn track_at(&self, index: usize) -> Option<(u32, Track)> {
+        let at = self.tracks_start + index * TRACK_LEN;
+        let track_id = read_u32(&self.map, at)?;
+        let title_len = read_u32(&self.map, at + 4)? as usize;
+        let path_len = read_u32(&self.map, at + 8)?;
+        let start = self
+            .strings_start
+            .checked_add(read_u64(&self.map, at + 16)? as usize)?;
+
+        let title = self.map.get(start..start.checked_add(title_len)?)?;
+        let path = if path_len == NO_PATH {
+            None
+        } else {
+            let path_start = start.checked_add(title_len)?;
+            let path = self
+                .map
+                .get(path_start..path_start.checked_add(path_len as usize)?)?;
+            Some(PathBuf::from(String::from_utf8_lossy(path).to_string()))
+        };
+        Some((
+            track_id,
+            Track {
+                title: String::from_utf8_lossy(title).to_string(),
+                path,
+            },
+        ))
+    }
+}
+
+const CORRUPT: &str = "Index is corrupt, rebuild it with `index build`.";
+const READ_ONLY: &str =
+    "Inverted indexes are read-only, add tracks to the database they were built from and rebuild.";
+
+impl Storage for InvertedIndex {
+    fn replace_track(
+        &mut self,
+        _title: &str,
+        _path: Option<&Path>,
+        _pair_records: &HashMap<u32, PairRecord>,
+    ) -> Result<u32, anyhow::Error> {
+        anyhow::bail!(READ_ONLY)
+    }
+
+    fn delete_track(&mut self, _track_id: u32) -> Result<bool, anyhow::Error> {
+        anyhow::bail!(READ_ONLY)
+    }
+
+    fn lookup(&self, hashes: &[u32]) -> Result<Vec<Fingerprint>, anyhow::Error> {
+        let mut fingerprints = vec![];
+        for &hash in hashes {
+            if let Some(index) = self.find_key(hash)? {
+                self.decode_postings(index, &mut |fingerprint| {
+                    fingerprints.push(fingerprint);
+                    Ok(())
+                })?;
+            }
+        }
+        Ok(fingerprints)
+    }
+
+    fn find_track(&self, title: &str) -> Result<Option<u32>, anyhow::Error> {
+        for index in 0..self.track_count {
+            let (track_id, track) = self.track_at(index).context(CORRUPT)?;
+            if track.title == title {
+                return Ok(Some(track_id));
+            }
+        }
+        Ok(None)
+    }
+
+    fn track(&self, track_id: u32) -> Result<Option<Track>, anyhow::Error> {
+        let (mut low, mut high) = (0, self.track_count);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            let (id, track) = self.track_at(middle).context(CORRUPT)?;
+            match id.cmp(&track_id) {
+                std::cmp::Ordering::Less => low = middle + 1,
+                std::cmp::Ordering::Greater => high = middle,
+                std::cmp::Ordering::Equal => return Ok(Some(track)),
+            }
+        }
+        Ok(None)
+    }
+
+    fn tracks(&self) -> Result<Vec<(u32, Track)>, anyhow::Error> {
+        (0..self.track_count)
+            .map(|index| self.track_at(index).context(CORRUPT))
+            .collect()
+    }
+
+    fn scan_fingerprints(
+        &self,
+        visit: &mut dyn FnMut(Fingerprint) -> Result<(), anyhow::Error>,
+    ) -> Result<(), anyhow::Error> {
+        for index in 0..self.key_count {
+            self.decode_postings(index, visit)?;
+        }
+        Ok(())
+    }
+
+    fn size_bytes(&self) -> Result<u64, anyhow::Error> {
+        Ok(self.map.len() as u64)
+    }
+
+    fn params(&self) -> Result<Option<AnalysisParams>, anyhow::Error> {
+        Ok(self.params)
+    }
+
+    fn set_params(&mut self, _params: AnalysisParams) -> Result<(), anyhow::Error> {
+        anyhow::bail!(READ_ONLY)
+    }
+
+    fn shard_position(&self) -> Result<Option<ShardPosition>, anyhow::Error> {
+        Ok(self.shard_position)
+    }
+
+    /// Indexes keep the position of the shard they were built from
+    fn set_shard_position(&mut self, _position: ShardPosition) -> Result<(), anyhow::Error> {
+        anyhow::bail!(READ_ONLY)
+    }
+}
+
+fn encode_shard_position(position: Option<ShardPosition>) -> [u8; 4] {
+    let mut bytes = [0; 4];
+    if let Some(position) = position {
+        bytes[..2].copy_from_slice(&position.index.to_le_bytes());
+        bytes[2..].copy_from_slice(&position.count.to_le_bytes());
+    }
+    bytes
+}
+
+/// Indexes built before positions were recorded have zeros, as have those not of a shard
+fn decode_shard_position(bytes: &[u8]) -> Option<ShardPosition> {
+    let count = u16::from_le_bytes([bytes[2], bytes[3]]);
+    (count > 0).then(|| ShardPosition {
+        index: u16::from_le_bytes([bytes[0], bytes[1]]),
+        count,
+    })
+}
+
+/// The header's parameters section: whether they are known, then each parameter
+fn encode_params(params: Option<AnalysisParams>) -> [u8; HEADER_LEN - PARAMS_AT] {
+    let mut bytes = [0; HEADER_LEN - PARAMS_AT];
+    if let Some(params) = params {
+        let fields = [
+            1,
+            params.window_length.to_bits(),
+            params.kernel_size as u32,
+            params.magnitude_threshold.to_bits(),
+            params.target_zone_delay_sec.to_bits(),
+            params.target_zone_height_hz.to_bits(),
+            params.target_zone_width_sec.to_bits(),
+        ];
+        for (chunk, field) in bytes.chunks_exact_mut(4).zip(fields) {
+            chunk.copy_from_slice(&field.to_le_bytes());
+        }
+    }
+    bytes
+}
+
+fn decode_params(bytes: &[u8]) -> Result<Option<AnalysisParams>, anyhow::Error> {
+    let field = |number: usize| read_u32(bytes, 4 * number).context("Index header is truncated.");
+    if field(0)? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(AnalysisParams {
+        window_length: f32::from_bits(field(1)?),
+        kernel_size: field(2)? as usize,
+        magnitude_threshold: f32::from_bits(field(3)?),
+        target_zone_delay_sec: f32::from_bits(field(4)?),
+        target_zone_height_hz: f32::from_bits(field(5)?),
+        target_zone_width_sec: f32::from_bits(field(6)?),
+    }))
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(at..at + 8)?.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    fn source() -> MemoryStorage {
+        let mut source = MemoryStorage::default();
+        source.set_params(AnalysisParams::default()).unwrap();
+        let records = |fingerprints: &[(u32, u32)]| -> HashMap<u32, PairRecord> {
+            fingerprints
+                .iter()
+                .map(|&(hash, time_a)| (hash, PairRecord { hash, time_a }))
+                .collect()
+        };
+        source
+            .replace_track(
+                "a",
+                Some(Path::new("/music/a.wav")),
+                &records(&[(7, 3), (9, 400), (u32::MAX, 1)]),
+            )
+            .unwrap();
+        source
+            .replace_track("b", None, &records(&[(7, 5), (8, 70_000)]))
+            .unwrap();
+        source
+    }
+
+    fn build(dir: &Path) -> (PathBuf, Vec<u8>) {
+        let path = dir.join("index.inv");
+        InvertedIndex::build(&source(), &path).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        (path, bytes)
+    }
+
+    fn sorted(fingerprints: Vec<Fingerprint>) -> Vec<(u32, u32, u32)> {
+        let mut entries: Vec<_> = fingerprints
+            .into_iter()
+            .map(|fingerprint| {
+                (
+                    fingerprint.hash,
+                    fingerprint.track_id,
+                    fingerprint.track_time,
+                )
+            })
+            .collect();
+        entries.sort_unstable();
+        entries
+    }
+
+    #[test]
+    fn indexes_hold_what_they_were_built_from() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, _) = build(dir.path());
+        let source = source();
+        let index = InvertedIndex::open(&path).unwrap();
+
+        assert_eq!(index.key_count(), 4);
+        assert_eq!(index.params().unwrap(), Some(AnalysisParams::default()));
+        let tracks = index.tracks().unwrap();
+        assert_eq!(tracks.len(), 2);
+        for ((id, track), (source_id, source_track)) in tracks.iter().zip(source.tracks().unwrap())
+        {
+            assert_eq!((id, &track.title), (&source_id, &source_track.title));
+            assert_eq!(track.path, source_track.path);
+        }
+        assert_eq!(index.find_track("b").unwrap(), Some(tracks[1].0));
+        assert_eq!(index.find_track("c").unwrap(), None);
+
+        let hashes = [7, 8, 9, 10, u32::MAX];
+        assert_eq!(
+            sorted(index.lookup(&hashes).unwrap()),
+            sorted(source.lookup(&hashes).unwrap())
+        );
+        let mut scanned = vec![];
+        index
+            .scan_fingerprints(&mut |fingerprint| {
+                scanned.push(fingerprint);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(sorted(scanned), sorted(source.lookup(&hashes).unwrap()));
+    }
+
+    #[test]
+    fn indexes_of_stores_without_params_or_tracks_can_be_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("empty.inv");
+        InvertedIndex::build(&MemoryStorage::default(), &path).unwrap();
+        let index = InvertedIndex::open(&path).unwrap();
+        assert_eq!(index.params().unwrap(), None);
+        assert!(index.tracks().unwrap().is_empty());
+        assert!(index.lookup(&[1, 2]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn other_files_and_versions_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, bytes) = build(dir.path());
+
+        let mut other = bytes.clone();
+        other[..MAGIC.len()].copy_from_slice(b"NOTANIDX");
+        fs::write(&path, &other).unwrap();
+        assert!(InvertedIndex::open(&path).is_err());
+
+        let mut newer = bytes;
+        newer[8..12].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        fs::write(&path, &newer).unwrap();
+        let error = InvertedIndex::open(&path).err().unwrap();
+        assert!(error.to_string().contains("rebuild"), "{}", error);
+    }
+
+    #[test]
+    fn truncated_indexes_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, bytes) = build(dir.path());
+        for len in [0, 4, 12, 40, PARAMS_AT, HEADER_LEN, bytes.len() - 1] {
+            fs::write(&path, &bytes[..len]).unwrap();
+            assert!(InvertedIndex::open(&path).is_err(), "{} bytes", len);
+        }
+        let mut longer = bytes;
+        longer.push(0);
+        fs::write(&path, &longer).unwrap();
+        assert!(InvertedIndex::open(&path).is_err());
+    }
+
+    #[test]
+    fn corrupt_postings_are_reported_not_misread() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mut bytes) = build(dir.path());
+        // more postings for the first hash, 7, than its postings hold
+        bytes[HEADER_LEN + 4..HEADER_LEN + 8].copy_from_slice(&100u32.to_le_bytes());
+        fs::write(&path, &bytes).unwrap();
+        let index = InvertedIndex::open(&path).unwrap();
+        assert!(index.lookup(&[7]).is_err());
+        assert_eq!(index.lookup(&[8]).unwrap().len(), 1);
+    }
+}
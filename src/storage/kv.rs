@@ -0,0 +1,266 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use redb::{
+    Database, MultimapTableDefinition, ReadableMultimapTable, ReadableTable, TableDefinition,
+    WriteTransaction,
+};
+
+use super::{Fingerprint, ShardPosition, Storage, Track};
+use crate::{hash::PairRecord, AnalysisParams};
+
+// track id -> (title, path), with an empty path when it isn't known
+const TRACKS: TableDefinition<u32, (&str, &str)> = TableDefinition::new("tracks");
+// hash -> track id in the high half, track time in the low half
+const FINGERPRINTS: MultimapTableDefinition<u32, u64> =
+    MultimapTableDefinition::new("fingerprints");
+// track id -> hash in the high half, track time in the low half, for deleting a track
+const TRACK_FINGERPRINTS: MultimapTableDefinition<u32, u64> =
+    MultimapTableDefinition::new("track_fingerprints");
+// name -> value of counters kept between writes
+const COUNTERS: TableDefinition<&str, u32> = TableDefinition::new("counters");
+// the id last given to a track, so ids of removed tracks aren't reused
+const LAST_TRACK_ID: &str = "last_track_id";
+// name -> JSON of facts about the store as a whole
+const META: TableDefinition<&str, &str> = TableDefinition::new("meta");
+const PARAMS_KEY: &str = "analysis_params";
+const SHARD_POSITION_KEY: &str = "shard_position";
+
+/// Tracks and fingerprints in an embedded B-tree key-value store
+pub struct KvStorage {
+    db: Database,
+    path: PathBuf,
+}
+
+impl KvStorage {
+    /// Opens the store at `path`, creating it if it doesn't yet exist
+    pub fn open(path: &Path) -> Result<Self, anyhow::Error> {
+        let db = Database::create(path)?;
+        // create the tables so reads never find them missing
+        let transaction = db.begin_write()?;
+        transaction.open_table(TRACKS)?;
+        transaction.open_multimap_table(FINGERPRINTS)?;
+        transaction.open_multimap_table(TRACK_FINGERPRINTS)?;
+        transaction.open_table(META)?;
+        {
+            // stores made before the counter was kept start it from their last track
+            let mut counters = transaction.open_table(COUNTERS)?;
+            if counters.get(LAST_TRACK_ID)?.is_none() {
+                let last = transaction
+                    .open_table(TRACKS)?
+                    .last()?
+                    .map_or(0, |(last, _)| last.value());
+                counters.insert(LAST_TRACK_ID, last)?;
+            }
+        }
+        transaction.commit()?;
+
+        Ok(KvStorage {
+            db,
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+fn pack(high: u32, low: u32) -> u64 {
+    (high as u64) << 32 | low as u64
+}
+
+fn unpack(value: u64) -> (u32, u32) {
+    ((value >> 32) as u32, value as u32)
+}
+
+/// Removes every fingerprint of a track, leaving the track itself
+fn delete_fingerprints(transaction: &WriteTransaction, track_id: u32) -> Result<(), anyhow::Error> {
+    let mut fingerprints = transaction.open_multimap_table(FINGERPRINTS)?;
+    let mut track_fingerprints = transaction.open_multimap_table(TRACK_FINGERPRINTS)?;
+    let removed = track_fingerprints
+        .remove_all(track_id)?
+        .map(|value| Ok(unpack(value?.value())))
+        .collect::<Result<Vec<_>, redb::StorageError>>()?;
+    for (hash, track_time) in removed {
+        fingerprints.remove(hash, pack(track_id, track_time))?;
+    }
+    Ok(())
+}
+
+fn find_track(
+    tracks: &impl ReadableTable<u32, (&'static str, &'static str)>,
+    title: &str,
+) -> Result<Option<u32>, anyhow::Error> {
+    for entry in tracks.iter()? {
+        let (track_id, track) = entry?;
+        if track.value().0 == title {
+            return Ok(Some(track_id.value()));
+        }
+    }
+    Ok(None)
+}
+
+impl Storage for KvStorage {
+    /// Replaces the track and its fingerprints in a single transaction
+    fn replace_track(
+        &mut self,
+        title: &str,
+        path: Option<&Path>,
+        pair_records: &HashMap<u32, PairRecord>,
+    ) -> Result<u32, anyhow::Error> {
+        let path = path.map_or(String::new(), |path| path.to_string_lossy().to_string());
+        let transaction = self.db.begin_write()?;
+        let track_id = {
+            let mut tracks = transaction.open_table(TRACKS)?;
+            let track_id = match find_track(&tracks, title)? {
+                Some(track_id) => {
+                    delete_fingerprints(&transaction, track_id)?;
+                    track_id
+                }
+                None => {
+                    let mut counters = transaction.open_table(COUNTERS)?;
+                    let last = counters.get(LAST_TRACK_ID)?.map_or(0, |last| last.value());
+                    let track_id = last.checked_add(1).context("Out of track ids.")?;
+                    counters.insert(LAST_TRACK_ID, track_id)?;
+                    track_id
+                }
+            };
+            tracks.insert(track_id, (title, path.as_str()))?;
+
+            let mut fingerprints = transaction.open_multimap_table(FINGERPRINTS)?;
+            let mut track_fingerprints = transaction.open_multimap_table(TRACK_FINGERPRINTS)?;
+            for record in pair_records.values() {
+                fingerprints.insert(record.hash, pack(track_id, record.time_a))?;
+                track_fingerprints.insert(track_id, pack(record.hash, record.time_a))?;
+            }
+            track_id
+        };
+        transaction.commit()?;
+        Ok(track_id)
+    }
+
+    fn delete_track(&mut self, track_id: u32) -> Result<bool, anyhow::Error> {
+        let transaction = self.db.begin_write()?;
+        delete_fingerprints(&transaction, track_id)?;
+        let existed = transaction.open_table(TRACKS)?.remove(track_id)?.is_some();
+        transaction.commit()?;
+        Ok(existed)
+    }
+
+    fn lookup(&self, hashes: &[u32]) -> Result<Vec<Fingerprint>, anyhow::Error> {
+        let transaction = self.db.begin_read()?;
+        let table = transaction.open_multimap_table(FINGERPRINTS)?;
+        let mut fingerprints = vec![];
+        for &hash in hashes {
+            for value in table.get(hash)? {
+                let (track_id, track_time) = unpack(value?.value());
+                fingerprints.push(Fingerprint {
+                    hash,
+                    track_time,
+                    track_id,
+                });
+            }
+        }
+        Ok(fingerprints)
+    }
+
+    fn find_track(&self, title: &str) -> Result<Option<u32>, anyhow::Error> {
+        let transaction = self.db.begin_read()?;
+        find_track(&transaction.open_table(TRACKS)?, title)
+    }
+
+    fn track(&self, track_id: u32) -> Result<Option<Track>, anyhow::Error> {
+        let transaction = self.db.begin_read()?;
+        let track = transaction.open_table(TRACKS)?.get(track_id)?.map(|track| {
+            let (title, path) = track.value();
+            Track {
+                title: title.to_string(),
+                path: (!path.is_empty()).then(|| PathBuf::from(path)),
+            }
+        });
+        Ok(track)
+    }
+
+    fn tracks(&self) -> Result<Vec<(u32, Track)>, anyhow::Error> {
+        let transaction = self.db.begin_read()?;
+        let mut tracks = vec![];
+        for entry in transaction.open_table(TRACKS)?.iter()? {
+            let (track_id, track) = entry?;
+            let (title, path) = track.value();
+            tracks.push((
+                track_id.value(),
+                Track {
+                    title: title.to_string(),
+                    path: (!path.is_empty()).then(|| PathBuf::from(path)),
+                },
+            ));
+        }
+        Ok(tracks)
+    }
+
+    fn scan_fingerprints(
+        &self,
+        visit: &mut dyn FnMut(Fingerprint) -> Result<(), anyhow::Error>,
+    ) -> Result<(), anyhow::Error> {
+        let transaction = self.db.begin_read()?;
+        for entry in transaction.open_multimap_table(FINGERPRINTS)?.iter()? {
+            let (hash, values) = entry?;
+            for value in values {
+                let (track_id, track_time) = unpack(value?.value());
+                visit(Fingerprint {
+                    hash: hash.value(),
+                    track_time,
+                    track_id,
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    fn size_bytes(&self) -> Result<u64, anyhow::Error> {
+        Ok(fs::metadata(&self.path)?.len())
+    }
+
+    fn params(&self) -> Result<Option<AnalysisParams>, anyhow::Error> {
+        let transaction = self.db.begin_read()?;
+        let meta = transaction.open_table(META)?;
+        let Some(value) = meta.get(PARAMS_KEY)? else {
+            return Ok(None);
+        };
+        let params = serde_json::from_str(value.value())
+            .context("The recorded analysis parameters are invalid.")?;
+        Ok(Some(params))
+    }
+
+    fn set_params(&mut self, params: AnalysisParams) -> Result<(), anyhow::Error> {
+        let value = serde_json::to_string(&params)?;
+        let transaction = self.db.begin_write()?;
+        transaction
+            .open_table(META)?
+            .insert(PARAMS_KEY, value.as_str())?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn shard_position(&self) -> Result<Option<ShardPosition>, anyhow::Error> {
+        let transaction = self.db.begin_read()?;
+        let meta = transaction.open_table(META)?;
+        let Some(value) = meta.get(SHARD_POSITION_KEY)? else {
+            return Ok(None);
+        };
+        let position = serde_json::from_str(value.value())
+            .context("The recorded shard position is invalid.")?;
+        Ok(Some(position))
+    }
+
+    fn set_shard_position(&mut self, position: ShardPosition) -> Result<(), anyhow::Error> {
+        let value = serde_json::to_string(&position)?;
+        let transaction = self.db.begin_write()?;
+        transaction
+            .open_table(META)?
+            .insert(SHARD_POSITION_KEY, value.as_str())?;
+        transaction.commit()?;
+        Ok(())
+    }
+}
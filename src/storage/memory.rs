@@ -0,0 +1,161 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    mem,
+    path::Path,
+};
+
+use super::{Fingerprint, ShardPosition, Storage, Track};
+use crate::{hash::PairRecord, AnalysisParams};
+
+/// Tracks and fingerprints kept in memory only, lost when dropped
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    tracks: BTreeMap<u32, Track>,
+    fingerprints: HashMap<u32, Vec<(u32, u32)>>, // hash -> (track_time, track_id)
+    track_hashes: HashMap<u32, Vec<u32>>,        // hashes of each track, for deleting it
+    last_track_id: u32,                          // given to a track, never reused
+    params: Option<AnalysisParams>,
+    shard_position: Option<ShardPosition>,
+}
+
+impl MemoryStorage {
+    fn delete_fingerprints(&mut self, track_id: u32) {
+        for hash in self.track_hashes.remove(&track_id).unwrap_or_default() {
+            if let Some(entries) = self.fingerprints.get_mut(&hash) {
+                entries.retain(|&(_, id)| id != track_id);
+                if entries.is_empty() {
+                    self.fingerprints.remove(&hash);
+                }
+            }
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn replace_track(
+        &mut self,
+        title: &str,
+        path: Option<&Path>,
+        pair_records: &HashMap<u32, PairRecord>,
+    ) -> Result<u32, anyhow::Error> {
+        let track_id = match self.find_track(title)? {
+            Some(track_id) => {
+                self.delete_fingerprints(track_id);
+                track_id
+            }
+            None => {
+                self.last_track_id += 1;
+                self.last_track_id
+            }
+        };
+        self.tracks.insert(
+            track_id,
+            Track {
+                title: title.to_string(),
+                path: path.map(Path::to_path_buf),
+            },
+        );
+        let track_hashes = self.track_hashes.entry(track_id).or_default();
+        for record in pair_records.values() {
+            self.fingerprints
+                .entry(record.hash)
+                .or_default()
+                .push((record.time_a, track_id));
+            track_hashes.push(record.hash);
+        }
+        Ok(track_id)
+    }
+
+    fn delete_track(&mut self, track_id: u32) -> Result<bool, anyhow::Error> {
+        self.delete_fingerprints(track_id);
+        Ok(self.tracks.remove(&track_id).is_some())
+    }
+
+    fn lookup(&self, hashes: &[u32]) -> Result<Vec<Fingerprint>, anyhow::Error> {
+        let fingerprints = hashes
+            .iter()
+            .filter_map(|&hash| Some((hash, self.fingerprints.get(&hash)?)))
+            .flat_map(|(hash, entries)| {
+                entries
+                    .iter()
+                    .map(move |&(track_time, track_id)| Fingerprint {
+                        hash,
+                        track_time,
+                        track_id,
+                    })
+            })
+            .collect();
+        Ok(fingerprints)
+    }
+
+    fn find_track(&self, title: &str) -> Result<Option<u32>, anyhow::Error> {
+        Ok(self
+            .tracks
+            .iter()
+            .find(|(_, track)| track.title == title)
+            .map(|(&track_id, _)| track_id))
+    }
+
+    fn track(&self, track_id: u32) -> Result<Option<Track>, anyhow::Error> {
+        Ok(self.tracks.get(&track_id).cloned())
+    }
+
+    fn tracks(&self) -> Result<Vec<(u32, Track)>, anyhow::Error> {
+        Ok(self
+            .tracks
+            .iter()
+            .map(|(&track_id, track)| (track_id, track.clone()))
+            .collect())
+    }
+
+    fn scan_fingerprints(
+        &self,
+        visit: &mut dyn FnMut(Fingerprint) -> Result<(), anyhow::Error>,
+    ) -> Result<(), anyhow::Error> {
+        for (&hash, entries) in &self.fingerprints {
+            for &(track_time, track_id) in entries {
+                visit(Fingerprint {
+                    hash,
+                    track_time,
+                    track_id,
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// An estimate from the number of fingerprints, ignoring allocator overhead
+    fn size_bytes(&self) -> Result<u64, anyhow::Error> {
+        let fingerprints: usize = self.fingerprints.values().map(Vec::len).sum();
+        let hash_bytes = self.fingerprints.len() * mem::size_of::<(u32, Vec<(u32, u32)>)>()
+            + fingerprints * (mem::size_of::<(u32, u32)>() + mem::size_of::<u32>());
+        let track_bytes: usize = self
+            .tracks
+            .values()
+            .map(|track| {
+                mem::size_of::<Track>()
+                    + track.title.len()
+                    + track.path.as_ref().map_or(0, |path| path.as_os_str().len())
+            })
+            .sum();
+        Ok((hash_bytes + track_bytes) as u64)
+    }
+
+    fn params(&self) -> Result<Option<AnalysisParams>, anyhow::Error> {
+        Ok(self.params)
+    }
+
+    fn set_params(&mut self, params: AnalysisParams) -> Result<(), anyhow::Error> {
+        self.params = Some(params);
+        Ok(())
+    }
+
+    fn shard_position(&self) -> Result<Option<ShardPosition>, anyhow::Error> {
+        Ok(self.shard_position)
+    }
+
+    fn set_shard_position(&mut self, position: ShardPosition) -> Result<(), anyhow::Error> {
+        self.shard_position = Some(position);
+        Ok(())
+    }
+}
@@ -0,0 +1,573 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::Path,
+};
+
+use anyhow::Context;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use super::{sqlite, Fingerprint, ShardPosition, Storage, Track};
+use crate::varint::{read_varint, write_varint};
+use crate::{hash::PairRecord, AnalysisParams};
+
+/// Hashes sharing their top 18 bits are packed into the same bucket, small enough that
+/// merging a track into the buckets it falls in and looking hashes up stay cheap in
+/// large catalogues
+const BUCKET_SHIFT: u32 = 14;
+
+/// Bucket size of databases made before it was recorded
+const LEGACY_BUCKET_SHIFT: u32 = 16;
+
+/// Key of the number of low hash bits within a bucket in the meta table
+const BUCKET_SHIFT_KEY: &str = "packed_bucket_shift";
+
+const CORRUPT: &str = "Packed postings are corrupt.";
+
+/// Tracks and fingerprints in a SQLite database, with the fingerprints packed into a
+/// blob per bucket of hashes rather than a row each.
+///
+/// A bucket's postings are sorted by (hash, track_id, track_time) and stored as varints:
+/// the difference of the hash from the previous posting's, then the track id, or its
+/// difference from the previous one within the same hash, then the time, or its
+/// difference from the previous one within the same hash and track.
+///
+/// The buckets each track has postings in are recorded as the differences between
+/// them, so removing it only rewrites those.
+pub struct PackedStorage {
+    conn: Connection,
+    bucket_shift: u32, // low hash bits within a bucket
+}
+
+type Posting = (u32, u32, u32); // (hash, track_id, track_time)
+
+impl PackedStorage {
+    /// Opens the specified database, creating it and the tables if it doesn't yet exist
+    pub fn open(database: &Path) -> Result<Self, anyhow::Error> {
+        let conn = Connection::open(database)?;
+        sqlite::create_tracks_table(&conn)?;
+        sqlite::create_meta_table(&conn)?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS postings (
+                bucket INTEGER PRIMARY KEY,
+                data BLOB NOT NULL
+            )",
+            (),
+        )?;
+        // Tracks without a row were added before their buckets were recorded
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS track_buckets (
+                track_id INTEGER PRIMARY KEY,
+                buckets BLOB NOT NULL
+            )",
+            (),
+        )?;
+        let bucket_shift = Self::bucket_shift(&conn)?;
+        Ok(PackedStorage { conn, bucket_shift })
+    }
+
+    /// The recorded bucket size, recording it first in databases made before it was
+    fn bucket_shift(conn: &Connection) -> Result<u32, anyhow::Error> {
+        if let Some(shift) = sqlite::meta(conn, BUCKET_SHIFT_KEY)? {
+            return match shift.parse() {
+                Ok(shift) if (1..32).contains(&shift) => Ok(shift),
+                _ => anyhow::bail!("The recorded bucket size {} is invalid.", shift),
+            };
+        }
+        let has_postings: bool =
+            conn.query_row("SELECT EXISTS (SELECT 1 FROM postings)", [], |row| {
+                row.get(0)
+            })?;
+        let shift = if has_postings {
+            LEGACY_BUCKET_SHIFT
+        } else {
+            BUCKET_SHIFT
+        };
+        sqlite::set_meta(conn, BUCKET_SHIFT_KEY, &shift.to_string())?;
+        Ok(shift)
+    }
+
+    fn read_bucket(conn: &Connection, bucket: u32) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        let data = conn
+            .prepare_cached("SELECT data FROM postings WHERE bucket = ?1")?
+            .query_row([bucket], |row| row.get(0))
+            .optional()?;
+        Ok(data)
+    }
+
+    fn write_bucket(
+        conn: &Connection,
+        bucket_shift: u32,
+        bucket: u32,
+        postings: &[Posting],
+    ) -> Result<(), anyhow::Error> {
+        if postings.is_empty() {
+            conn.prepare_cached("DELETE FROM postings WHERE bucket = ?1")?
+                .execute([bucket])?;
+        } else {
+            conn.prepare_cached("INSERT OR REPLACE INTO postings (bucket, data) VALUES (?1, ?2)")?
+                .execute(params![bucket, encode(bucket_shift, postings)])?;
+        }
+        Ok(())
+    }
+
+    fn read_postings(
+        conn: &Connection,
+        bucket_shift: u32,
+        bucket: u32,
+    ) -> Result<Vec<Posting>, anyhow::Error> {
+        Self::read_bucket(conn, bucket)?.map_or(Ok(vec![]), |data| {
+            Postings::new(bucket, bucket_shift, &data).collect()
+        })
+    }
+
+    /// Merges the track's postings into the buckets they fall in, recording those
+    fn insert_postings(
+        conn: &Connection,
+        bucket_shift: u32,
+        track_id: u32,
+        pair_records: &HashMap<u32, PairRecord>,
+    ) -> Result<(), anyhow::Error> {
+        let mut new_postings: BTreeMap<u32, Vec<Posting>> = BTreeMap::new();
+        for record in pair_records.values() {
+            new_postings
+                .entry(record.hash >> bucket_shift)
+                .or_default()
+                .push((record.hash, track_id, record.time_a));
+        }
+        let mut buckets = vec![];
+        let mut previous_bucket = 0;
+        for (bucket, additions) in new_postings {
+            let mut postings = Self::read_postings(conn, bucket_shift, bucket)?;
+            postings.extend(additions);
+            postings.sort_unstable();
+            Self::write_bucket(conn, bucket_shift, bucket, &postings)?;
+            write_varint(&mut buckets, bucket - previous_bucket);
+            previous_bucket = bucket;
+        }
+        conn.prepare_cached(
+            "INSERT OR REPLACE INTO track_buckets (track_id, buckets) VALUES (?1, ?2)",
+        )?
+        .execute(params![track_id, buckets])?;
+        Ok(())
+    }
+
+    /// Rewrites the buckets the track has postings in, or every bucket for tracks added
+    /// before those were recorded
+    fn delete_postings(
+        conn: &Connection,
+        bucket_shift: u32,
+        track_id: u32,
+    ) -> Result<(), anyhow::Error> {
+        let recorded: Option<Vec<u8>> = conn
+            .prepare_cached("SELECT buckets FROM track_buckets WHERE track_id = ?1")?
+            .query_row([track_id], |row| row.get(0))
+            .optional()?;
+        let buckets: Vec<u32> = match recorded {
+            Some(data) => {
+                let mut buckets = vec![];
+                let (mut position, mut bucket) = (0, 0u32);
+                while position < data.len() {
+                    let delta = read_varint(&data, &mut position).context(CORRUPT)?;
+                    bucket = bucket.checked_add(delta).context(CORRUPT)?;
+                    buckets.push(bucket);
+                }
+                buckets
+            }
+            None => conn
+                .prepare("SELECT bucket FROM postings")?
+                .query_map([], |row| row.get(0))?
+                .collect::<Result<_, _>>()?,
+        };
+        for bucket in buckets {
+            let mut postings = Self::read_postings(conn, bucket_shift, bucket)?;
+            let before = postings.len();
+            postings.retain(|&(_, id, _)| id != track_id);
+            if postings.len() != before {
+                Self::write_bucket(conn, bucket_shift, bucket, &postings)?;
+            }
+        }
+        conn.prepare_cached("DELETE FROM track_buckets WHERE track_id = ?1")?
+            .execute([track_id])?;
+        Ok(())
+    }
+}
+/// Adds the postings with the wanted hashes to `fingerprints`, reading no further than
+/// the last of them
+fn find(
+    postings: Postings,
+    wanted: &HashSet<u32>,
+    fingerprints: &mut Vec<Fingerprint>,
+) -> Result<(), anyhow::Error> {
+    let last = wanted.iter().max().copied().unwrap_or_default();
+    for posting in postings {
+        let (hash, track_id, track_time) = posting?;
+        if hash > last {
+            break;
+        }
+        if wanted.contains(&hash) {
+            fingerprints.push(Fingerprint {
+                hash,
+                track_time,
+                track_id,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Encodes postings sorted by (hash, track_id, track_time), all in the same bucket
+fn encode(bucket_shift: u32, postings: &[Posting]) -> Vec<u8> {
+    let low_bits = |hash: u32| hash & ((1 << bucket_shift) - 1);
+    let mut data = vec![];
+    let mut previous: Option<Posting> = None;
+    for &(hash, track_id, track_time) in postings {
+        match previous {
+            Some((previous_hash, previous_track, previous_time)) if previous_hash == hash => {
+                write_varint(&mut data, 0);
+                write_varint(&mut data, track_id - previous_track);
+                if track_id == previous_track {
+                    write_varint(&mut data, track_time - previous_time);
+                } else {
+                    write_varint(&mut data, track_time);
+                }
+            }
+            _ => {
+                let previous_low =
+                    previous.map_or(0, |(previous_hash, _, _)| low_bits(previous_hash));
+                write_varint(&mut data, low_bits(hash) - previous_low);
+                write_varint(&mut data, track_id);
+                write_varint(&mut data, track_time);
+            }
+        }
+        previous = Some((hash, track_id, track_time));
+    }
+    data
+}
+
+/// Decodes the postings of a bucket one at a time, in order, so reading can stop early
+struct Postings<'a> {
+    data: &'a [u8],
+    position: usize,
+    bucket: u32,
+    bucket_shift: u32,
+    previous: Option<Posting>,
+}
+
+impl<'a> Postings<'a> {
+    fn new(bucket: u32, bucket_shift: u32, data: &'a [u8]) -> Self {
+        Postings {
+            data,
+            position: 0,
+            bucket,
+            bucket_shift,
+            previous: None,
+        }
+    }
+
+    fn decode_next(&mut self) -> Option<Posting> {
+        let hash_delta = read_varint(self.data, &mut self.position)?;
+        let track = read_varint(self.data, &mut self.position)?;
+        let time = read_varint(self.data, &mut self.position)?;
+        let posting = match self.previous {
+            Some((hash, previous_track, previous_time)) if hash_delta == 0 => {
+                let track_id = previous_track.checked_add(track)?;
+                let track_time = if track == 0 {
+                    previous_time.checked_add(time)?
+                } else {
+                    time
+                };
+                (hash, track_id, track_time)
+            }
+            _ => {
+                let previous_hash = self
+                    .previous
+                    .map_or(self.bucket << self.bucket_shift, |(hash, _, _)| hash);
+                let hash = previous_hash.checked_add(hash_delta)?;
+                if hash >> self.bucket_shift != self.bucket {
+                    return None;
+                }
+                (hash, track, time)
+            }
+        };
+        self.previous = Some(posting);
+        Some(posting)
+    }
+}
+
+impl Iterator for Postings<'_> {
+    type Item = Result<Posting, anyhow::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.position >= self.data.len() {
+            return None;
+        }
+        let posting = self.decode_next();
+        if posting.is_none() {
+            self.position = self.data.len(); // nothing after corrupt data can be trusted
+        }
+        Some(posting.context(CORRUPT))
+    }
+}
+
+impl Storage for PackedStorage {
+    /// Replaces the track and merges its fingerprints into the buckets they fall in, in
+    /// a single transaction
+    fn replace_track(
+        &mut self,
+        title: &str,
+        path: Option<&Path>,
+        pair_records: &HashMap<u32, PairRecord>,
+    ) -> Result<u32, anyhow::Error> {
+        let transaction = self.conn.transaction()?;
+        let (track_id, replaced) = sqlite::upsert_track(&transaction, title, path)?;
+        if replaced {
+            Self::delete_postings(&transaction, self.bucket_shift, track_id)?;
+        }
+        Self::insert_postings(&transaction, self.bucket_shift, track_id, pair_records)?;
+        transaction.commit()?;
+        Ok(track_id)
+    }
+
+    fn delete_track(&mut self, track_id: u32) -> Result<bool, anyhow::Error> {
+        let transaction = self.conn.transaction()?;
+        Self::delete_postings(&transaction, self.bucket_shift, track_id)?;
+        let deleted = transaction.execute("DELETE FROM tracks WHERE id = ?1", [track_id])?;
+        transaction.commit()?;
+        Ok(deleted > 0)
+    }
+
+    /// Reads each bucket the hashes fall in once, up to the last hash wanted from it
+    fn lookup(&self, hashes: &[u32]) -> Result<Vec<Fingerprint>, anyhow::Error> {
+        let mut wanted: BTreeMap<u32, HashSet<u32>> = BTreeMap::new();
+        for &hash in hashes {
+            wanted
+                .entry(hash >> self.bucket_shift)
+                .or_default()
+                .insert(hash);
+        }
+        let mut fingerprints = vec![];
+        for (bucket, hashes) in wanted {
+            if let Some(data) = Self::read_bucket(&self.conn, bucket)? {
+                let postings = Postings::new(bucket, self.bucket_shift, &data);
+                find(postings, &hashes, &mut fingerprints)?;
+            }
+        }
+        Ok(fingerprints)
+    }
+
+    fn find_track(&self, title: &str) -> Result<Option<u32>, anyhow::Error> {
+        sqlite::find_track(&self.conn, title)
+    }
+
+    fn track(&self, track_id: u32) -> Result<Option<Track>, anyhow::Error> {
+        sqlite::track(&self.conn, track_id)
+    }
+
+    fn tracks(&self) -> Result<Vec<(u32, Track)>, anyhow::Error> {
+        sqlite::tracks(&self.conn)
+    }
+
+    fn scan_fingerprints(
+        &self,
+        visit: &mut dyn FnMut(Fingerprint) -> Result<(), anyhow::Error>,
+    ) -> Result<(), anyhow::Error> {
+        let mut query = self
+            .conn
+            .prepare_cached("SELECT bucket, data FROM postings")?;
+        let mut rows = query.query([])?;
+        while let Some(row) = rows.next()? {
+            let bucket: u32 = row.get(0)?;
+            let data: Vec<u8> = row.get(1)?;
+            for posting in Postings::new(bucket, self.bucket_shift, &data) {
+                let (hash, track_id, track_time) = posting?;
+                visit(Fingerprint {
+                    hash,
+                    track_time,
+                    track_id,
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    fn size_bytes(&self) -> Result<u64, anyhow::Error> {
+        sqlite::size_bytes(&self.conn)
+    }
+
+    fn params(&self) -> Result<Option<AnalysisParams>, anyhow::Error> {
+        sqlite::params(&self.conn)
+    }
+
+    fn set_params(&mut self, params: AnalysisParams) -> Result<(), anyhow::Error> {
+        sqlite::set_params(&self.conn, params)
+    }
+
+    fn shard_position(&self) -> Result<Option<ShardPosition>, anyhow::Error> {
+        sqlite::shard_position(&self.conn)
+    }
+
+    fn set_shard_position(&mut self, position: ShardPosition) -> Result<(), anyhow::Error> {
+        sqlite::set_shard_position(&self.conn, position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(fingerprints: &[(u32, u32)]) -> HashMap<u32, PairRecord> {
+        fingerprints
+            .iter()
+            .map(|&(hash, time_a)| (hash, PairRecord { hash, time_a }))
+            .collect()
+    }
+
+    fn lookup(storage: &PackedStorage, hashes: &[u32]) -> Vec<Posting> {
+        let mut postings: Vec<Posting> = storage
+            .lookup(hashes)
+            .unwrap()
+            .into_iter()
+            .map(|fingerprint| {
+                (
+                    fingerprint.hash,
+                    fingerprint.track_id,
+                    fingerprint.track_time,
+                )
+            })
+            .collect();
+        postings.sort_unstable();
+        postings
+    }
+
+    fn decode(bucket: u32, data: &[u8]) -> Result<Vec<Posting>, anyhow::Error> {
+        Postings::new(bucket, BUCKET_SHIFT, data).collect()
+    }
+
+    #[test]
+    fn postings_round_trip() {
+        let bucket = 0x2_0000;
+        let start = bucket << BUCKET_SHIFT;
+        let postings = [
+            (start, 1, 0),
+            (start, 1, 7),
+            (start, 4, 3),
+            (start + 1, 2, 1_000_000),
+            (start + (1 << BUCKET_SHIFT) - 1, u32::MAX, u32::MAX),
+        ];
+        let data = encode(BUCKET_SHIFT, &postings);
+        assert_eq!(decode(bucket, &data).unwrap(), postings);
+        assert!(decode(bucket, &[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn truncated_postings_are_reported() {
+        let postings = [(5, 1, 300), (9, 2, 70_000)];
+        let data = encode(BUCKET_SHIFT, &postings);
+        // blobs are stored whole, so cutting between postings needn't be noticed
+        let first_len = encode(BUCKET_SHIFT, &postings[..1]).len();
+        assert_eq!(decode(0, &data[..first_len]).unwrap(), postings[..1]);
+        for len in (1..data.len()).filter(|&len| len != first_len) {
+            assert!(decode(0, &data[..len]).is_err(), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn postings_outside_their_bucket_are_reported() {
+        let mut data = vec![];
+        for value in [1 << BUCKET_SHIFT, 1, 1] {
+            write_varint(&mut data, value); // a hash difference past the bucket's end
+        }
+        assert!(decode(3, &data).is_err());
+        // times and track ids that overflow
+        let mut data = encode(BUCKET_SHIFT, &[(0, u32::MAX, u32::MAX)]);
+        for value in [0, 0, 1] {
+            write_varint(&mut data, value);
+        }
+        assert!(decode(0, &data).is_err());
+        let mut corrupt = encode(BUCKET_SHIFT, &[(0, u32::MAX, 0)]);
+        corrupt.extend([0, 1, 0]);
+        assert!(decode(0, &corrupt).is_err());
+    }
+
+    #[test]
+    fn lookups_stop_at_the_last_wanted_hash() {
+        // corrupt after the wanted hash, which is only noticed when read past
+        let mut data = encode(BUCKET_SHIFT, &[(1, 1, 1), (2, 1, 1)]);
+        data.push(0x80);
+        let mut fingerprints = vec![];
+        find(
+            Postings::new(0, BUCKET_SHIFT, &data),
+            &HashSet::from([1]),
+            &mut fingerprints,
+        )
+        .unwrap();
+        assert_eq!(fingerprints.len(), 1);
+        let wanted = HashSet::from([3]);
+        assert!(find(Postings::new(0, BUCKET_SHIFT, &data), &wanted, &mut vec![]).is_err());
+    }
+
+    #[test]
+    fn removing_a_track_keeps_the_others_in_its_buckets() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = PackedStorage::open(&dir.path().join("packed.db3")).unwrap();
+        let a = storage
+            .replace_track(
+                "a",
+                None,
+                &records(&[(0x1234_0001, 5), (0x1234_0002, 9), (0xffff_ffff, 1)]),
+            )
+            .unwrap();
+        let b = storage
+            .replace_track("b", None, &records(&[(0x1234_0001, 7), (3, 2)]))
+            .unwrap();
+        let hashes = [0x1234_0001, 0x1234_0002, 0xffff_ffff, 3];
+        assert_eq!(lookup(&storage, &hashes).len(), 5);
+
+        assert!(storage.delete_track(a).unwrap());
+        assert_eq!(
+            lookup(&storage, &hashes),
+            vec![(3, b, 2), (0x1234_0001, b, 7)]
+        );
+        let recorded: u32 = storage
+            .conn
+            .query_row("SELECT COUNT(*) FROM track_buckets", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(recorded, 1);
+    }
+
+    #[test]
+    fn databases_made_before_the_bucket_size_was_recorded_keep_theirs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("packed.db3");
+        {
+            let conn = Connection::open(&path).unwrap();
+            sqlite::create_tracks_table(&conn).unwrap();
+            conn.execute(
+                "CREATE TABLE postings (bucket INTEGER PRIMARY KEY, data BLOB NOT NULL)",
+                (),
+            )
+            .unwrap();
+            conn.execute("INSERT INTO tracks (title) VALUES ('old')", ())
+                .unwrap();
+            let postings = [(0xabcd_0001, 1, 10), (0xabcd_0001, 1, 12)];
+            PackedStorage::write_bucket(&conn, LEGACY_BUCKET_SHIFT, 0xabcd, &postings).unwrap();
+        }
+
+        let mut storage = PackedStorage::open(&path).unwrap();
+        assert_eq!(storage.bucket_shift, LEGACY_BUCKET_SHIFT);
+        let new = storage
+            .replace_track("new", None, &records(&[(0xabcd_0002, 3)]))
+            .unwrap();
+        let hashes = [0xabcd_0001, 0xabcd_0002];
+        assert_eq!(lookup(&storage, &hashes).len(), 3);
+
+        // its buckets weren't recorded, so all are searched
+        assert!(storage.delete_track(1).unwrap());
+        assert_eq!(lookup(&storage, &hashes), vec![(0xabcd_0002, new, 3)]);
+        drop(storage);
+        assert_eq!(
+            PackedStorage::open(&path).unwrap().bucket_shift,
+            LEGACY_BUCKET_SHIFT
+        );
+    }
+}
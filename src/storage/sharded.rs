@@ -0,0 +1,316 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+    thread,
+};
+
+use super::{open, Fingerprint, ShardPosition, Storage, StorageKind, Track};
+use crate::{
+    hash::{self, PairRecord},
+    AnalysisParams,
+};
+
+/// A catalogue split across several stores, each track kept whole in one of them.
+///
+/// Tracks are routed to a shard by the hash of their title, so the shards must always be
+/// given in the same order. Each shard records its index and the number of shards when it
+/// is first opened as one, and refuses to be opened as another.
+/// Track ids are unique across the catalogue: the id of a track in its shard times the
+/// number of shards, plus the index of the shard.
+/// Lookups are sent to every shard in parallel and the fingerprints they find gathered
+/// together, so candidates are ranked and scored exactly as in a single store.
+pub struct ShardedStorage {
+    shards: Vec<Mutex<Box<dyn Storage>>>,
+}
+
+impl ShardedStorage {
+    /// Takes the shards in the order they were made, recording it in those that don't
+    /// yet know their position
+    pub fn new(mut shards: Vec<Box<dyn Storage>>) -> Result<Self, anyhow::Error> {
+        if shards.is_empty() {
+            anyhow::bail!("A sharded catalogue needs at least one shard.");
+        }
+        let count = u16::try_from(shards.len())
+            .map_err(|_| anyhow::anyhow!("A catalogue can't have {} shards.", shards.len()))?;
+        for (index, shard) in (0..count).zip(&mut shards) {
+            let position = ShardPosition { index, count };
+            match shard.shard_position()? {
+                None => shard.set_shard_position(position)?,
+                Some(recorded) if recorded != position => anyhow::bail!(
+                    "Shard {} was made as shard {} of {}, give the shards in the order they were made.",
+                    index,
+                    recorded.index,
+                    recorded.count
+                ),
+                Some(_) => {}
+            }
+        }
+        Ok(ShardedStorage {
+            shards: shards.into_iter().map(Mutex::new).collect(),
+        })
+    }
+
+    /// Opens each shard with the given kind of storage
+    pub fn open(kind: StorageKind, paths: &[PathBuf]) -> Result<Self, anyhow::Error> {
+        Self::new(
+            paths
+                .iter()
+                .map(|path| open(kind, path))
+                .collect::<Result<_, _>>()?,
+        )
+    }
+
+    fn shard(&self, index: usize) -> Result<MutexGuard<'_, Box<dyn Storage>>, anyhow::Error> {
+        self.shards[index]
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Shard {} failed during an earlier operation.", index))
+    }
+
+    /// The shard a title is stored in
+    fn route(&self, title: &str) -> usize {
+        hash::calculate_hash(&title) as usize % self.shards.len()
+    }
+
+    fn global_id(&self, shard: usize, local_id: u32) -> Result<u32, anyhow::Error> {
+        local_id
+            .checked_mul(self.shards.len() as u32)
+            .and_then(|id| id.checked_add(shard as u32))
+            .ok_or_else(|| anyhow::anyhow!("Track id {} is too large to shard.", local_id))
+    }
+
+    /// The shard and the id within it of a track
+    fn local_id(&self, track_id: u32) -> (usize, u32) {
+        let shard_count = self.shards.len() as u32;
+        ((track_id % shard_count) as usize, track_id / shard_count)
+    }
+}
+
+impl Storage for ShardedStorage {
+    fn replace_track(
+        &mut self,
+        title: &str,
+        path: Option<&Path>,
+        pair_records: &HashMap<u32, PairRecord>,
+    ) -> Result<u32, anyhow::Error> {
+        let shard = self.route(title);
+        let local_id = self
+            .shard(shard)?
+            .replace_track(title, path, pair_records)?;
+        self.global_id(shard, local_id)
+    }
+
+    fn delete_track(&mut self, track_id: u32) -> Result<bool, anyhow::Error> {
+        let (shard, local_id) = self.local_id(track_id);
+        self.shard(shard)?.delete_track(local_id)
+    }
+
+    /// Looks the hashes up in every shard at once
+    fn lookup(&self, hashes: &[u32]) -> Result<Vec<Fingerprint>, anyhow::Error> {
+        let results = thread::scope(|scope| {
+            let lookups: Vec<_> = (0..self.shards.len())
+                .map(|shard| scope.spawn(move || self.shard(shard)?.lookup(hashes)))
+                .collect();
+            lookups
+                .into_iter()
+                .map(|lookup| {
+                    lookup
+                        .join()
+                        .unwrap_or_else(|_| Err(anyhow::anyhow!("A shard lookup panicked.")))
+                })
+                .collect::<Vec<_>>()
+        });
+
+        let mut fingerprints = vec![];
+        for (shard, result) in results.into_iter().enumerate() {
+            for fingerprint in result? {
+                fingerprints.push(Fingerprint {
+                    track_id: self.global_id(shard, fingerprint.track_id)?,
+                    ..fingerprint
+                });
+            }
+        }
+        Ok(fingerprints)
+    }
+
+    fn find_track(&self, title: &str) -> Result<Option<u32>, anyhow::Error> {
+        let shard = self.route(title);
+        self.shard(shard)?
+            .find_track(title)?
+            .map(|local_id| self.global_id(shard, local_id))
+            .transpose()
+    }
+
+    fn track(&self, track_id: u32) -> Result<Option<Track>, anyhow::Error> {
+        let (shard, local_id) = self.local_id(track_id);
+        self.shard(shard)?.track(local_id)
+    }
+
+    fn tracks(&self) -> Result<Vec<(u32, Track)>, anyhow::Error> {
+        let mut tracks = vec![];
+        for shard in 0..self.shards.len() {
+            for (local_id, track) in self.shard(shard)?.tracks()? {
+                tracks.push((self.global_id(shard, local_id)?, track));
+            }
+        }
+        tracks.sort_unstable_by_key(|&(track_id, _)| track_id);
+        Ok(tracks)
+    }
+
+    fn scan_fingerprints(
+        &self,
+        visit: &mut dyn FnMut(Fingerprint) -> Result<(), anyhow::Error>,
+    ) -> Result<(), anyhow::Error> {
+        for shard in 0..self.shards.len() {
+            self.shard(shard)?.scan_fingerprints(&mut |fingerprint| {
+                visit(Fingerprint {
+                    track_id: self.global_id(shard, fingerprint.track_id)?,
+                    ..fingerprint
+                })
+            })?;
+        }
+        Ok(())
+    }
+
+    fn size_bytes(&self) -> Result<u64, anyhow::Error> {
+        let mut bytes = 0;
+        for shard in 0..self.shards.len() {
+            bytes += self.shard(shard)?.size_bytes()?;
+        }
+        Ok(bytes)
+    }
+
+    /// The parameters recorded by the shards, which must agree
+    fn params(&self) -> Result<Option<AnalysisParams>, anyhow::Error> {
+        let mut recorded: Option<AnalysisParams> = None;
+        for shard in 0..self.shards.len() {
+            let Some(params) = self.shard(shard)?.params()? else {
+                continue;
+            };
+            match recorded {
+                Some(recorded) if recorded != params => anyhow::bail!(
+                    "Shard {} was made with {:?}, but another with {:?}.",
+                    shard,
+                    params,
+                    recorded
+                ),
+                _ => recorded = Some(params),
+            }
+        }
+        Ok(recorded)
+    }
+
+    fn set_params(&mut self, params: AnalysisParams) -> Result<(), anyhow::Error> {
+        for shard in 0..self.shards.len() {
+            self.shard(shard)?.set_params(params)?;
+        }
+        Ok(())
+    }
+
+    /// The catalogue as a whole isn't a shard of another
+    fn shard_position(&self) -> Result<Option<ShardPosition>, anyhow::Error> {
+        Ok(None)
+    }
+
+    fn set_shard_position(&mut self, _position: ShardPosition) -> Result<(), anyhow::Error> {
+        anyhow::bail!("A sharded catalogue can't itself be a shard.")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        index::Index,
+        matching::Matcher,
+        storage::{open_catalogue, InvertedIndex},
+        MatchOptions,
+    };
+
+    const TITLES: [&str; 6] = ["a", "b", "c", "d", "e", "f"];
+
+    /// Hashes of the track at `number`, each distinct to it, from `start` windows in
+    fn hashes(number: u32, start: u32) -> HashMap<u32, PairRecord> {
+        (0..50)
+            .filter(|time| time * 3 >= start)
+            .map(|time| {
+                let hash = number * 1000 + time;
+                (
+                    hash,
+                    PairRecord {
+                        hash,
+                        time_a: time * 3 - start,
+                    },
+                )
+            })
+            .collect()
+    }
+
+    fn shard_paths(dir: &Path) -> Vec<PathBuf> {
+        (0..3)
+            .map(|shard| dir.join(format!("shard{}.db3", shard)))
+            .collect()
+    }
+
+    #[test]
+    fn tracks_added_through_the_shards_are_matched_and_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths = shard_paths(dir.path());
+        let mut index = Index::new(ShardedStorage::open(StorageKind::Sqlite, &paths).unwrap());
+        for (number, title) in (0..).zip(TITLES) {
+            index.add_track(title, None, &hashes(number, 0)).unwrap();
+        }
+        drop(index);
+
+        let index = Index::new(ShardedStorage::open(StorageKind::Sqlite, &paths).unwrap());
+        let mut matcher = Matcher::new(index).unwrap();
+        let options = MatchOptions::default();
+        for (number, title) in (0..).zip(TITLES) {
+            let candidates = matcher
+                .find_candidates(&hashes(number, 30), options.binning, 1)
+                .unwrap();
+            assert_eq!(
+                (candidates[0].title.as_str(), candidates[0].offset),
+                (title, 30)
+            );
+        }
+
+        assert!(matcher.index_mut().remove_track("c").unwrap());
+        let candidates = matcher
+            .find_candidates(&hashes(2, 30), options.binning, 1)
+            .unwrap();
+        assert!(candidates.is_empty());
+        assert_eq!(matcher.index().storage().tracks().unwrap().len(), 5);
+    }
+
+    #[test]
+    fn shards_must_be_given_in_the_order_they_were_made() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut paths = shard_paths(dir.path());
+        ShardedStorage::open(StorageKind::Sqlite, &paths).unwrap();
+
+        paths.swap(0, 2);
+        assert!(ShardedStorage::open(StorageKind::Sqlite, &paths).is_err());
+        paths.swap(0, 2);
+        assert!(ShardedStorage::open(StorageKind::Sqlite, &paths[..2]).is_err());
+        assert!(open_catalogue(StorageKind::Sqlite, &paths[..1]).is_err());
+        assert!(open_catalogue(StorageKind::Sqlite, &paths).is_ok());
+    }
+
+    #[test]
+    fn indexes_of_shards_keep_their_position() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths = shard_paths(dir.path());
+        drop(ShardedStorage::open(StorageKind::Sqlite, &paths).unwrap());
+        let mut indexes = vec![];
+        for (shard, path) in paths.iter().enumerate() {
+            let index = dir.path().join(format!("shard{}.inv", shard));
+            InvertedIndex::build(&open(StorageKind::Sqlite, path).unwrap(), &index).unwrap();
+            indexes.push(index);
+        }
+
+        assert!(ShardedStorage::open(StorageKind::Inverted, &indexes).is_ok());
+        indexes.reverse();
+        assert!(ShardedStorage::open(StorageKind::Inverted, &indexes).is_err());
+    }
+}
@@ -0,0 +1,317 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use anyhow::Context;
+use rusqlite::{params, types::Value, Connection, OptionalExtension};
+
+use super::{Fingerprint, ShardPosition, Storage, Track};
+use crate::{hash::PairRecord, AnalysisParams};
+
+/// Tracks and fingerprints in a SQLite database
+pub struct SqliteStorage {
+    conn: Connection,
+}
+
+impl SqliteStorage {
+    /// Opens the specified database, creating it and the tables if it doesn't yet exist
+    pub fn open(database: &Path) -> Result<Self, anyhow::Error> {
+        Self::new(Connection::open(database)?)
+    }
+
+    /// Opens a temporary database that lives only in memory
+    pub fn in_memory() -> Result<Self, anyhow::Error> {
+        Self::new(Connection::open_in_memory()?)
+    }
+
+    fn new(conn: Connection) -> Result<Self, anyhow::Error> {
+        create_tables(&conn)?;
+        rusqlite::vtab::array::load_module(&conn)?;
+        Ok(SqliteStorage { conn })
+    }
+}
+
+impl Storage for SqliteStorage {
+    /// Replaces the track and its fingerprints in a single transaction
+    fn replace_track(
+        &mut self,
+        title: &str,
+        path: Option<&Path>,
+        pair_records: &HashMap<u32, PairRecord>,
+    ) -> Result<u32, anyhow::Error> {
+        let transaction = self.conn.transaction()?;
+        let (track_id, replaced) = upsert_track(&transaction, title, path)?;
+        if replaced {
+            transaction.execute("DELETE FROM fingerprints WHERE track_id = ?1", [track_id])?;
+        }
+        {
+            let mut insert_statement = transaction.prepare(
+                "INSERT INTO fingerprints (hash, track_time, track_id) values (?1, ?2, ?3)",
+            )?;
+            for record in pair_records.values() {
+                insert_statement
+                    .execute([record.hash, record.time_a, track_id])
+                    .context("Failed to insert.")?;
+            }
+        }
+        transaction.commit()?;
+
+        Ok(track_id)
+    }
+
+    fn delete_track(&mut self, track_id: u32) -> Result<bool, anyhow::Error> {
+        let transaction = self.conn.transaction()?;
+        transaction.execute("DELETE FROM fingerprints WHERE track_id = ?1", [track_id])?;
+        let deleted = transaction.execute("DELETE FROM tracks WHERE id = ?1", [track_id])?;
+        transaction.commit()?;
+        Ok(deleted > 0)
+    }
+
+    /// Reads every matching fingerprint in a single query
+    fn lookup(&self, hashes: &[u32]) -> Result<Vec<Fingerprint>, anyhow::Error> {
+        let hashes = Rc::new(
+            hashes
+                .iter()
+                .copied()
+                .map(Value::from)
+                .collect::<Vec<Value>>(),
+        );
+        let mut hash_query = self.conn.prepare_cached(
+            "SELECT hash, track_time, track_id FROM fingerprints WHERE hash IN rarray(?1)",
+        )?;
+        let fingerprints = hash_query
+            .query_map(params![hashes], |row| {
+                Ok(Fingerprint {
+                    hash: row.get(0)?,
+                    track_time: row.get(1)?,
+                    track_id: row.get(2)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(fingerprints)
+    }
+
+    fn find_track(&self, title: &str) -> Result<Option<u32>, anyhow::Error> {
+        find_track(&self.conn, title)
+    }
+
+    fn track(&self, track_id: u32) -> Result<Option<Track>, anyhow::Error> {
+        track(&self.conn, track_id)
+    }
+
+    fn tracks(&self) -> Result<Vec<(u32, Track)>, anyhow::Error> {
+        tracks(&self.conn)
+    }
+
+    fn scan_fingerprints(
+        &self,
+        visit: &mut dyn FnMut(Fingerprint) -> Result<(), anyhow::Error>,
+    ) -> Result<(), anyhow::Error> {
+        let mut query = self
+            .conn
+            .prepare_cached("SELECT hash, track_time, track_id FROM fingerprints")?;
+        let mut rows = query.query([])?;
+        while let Some(row) = rows.next()? {
+            visit(Fingerprint {
+                hash: row.get(0)?,
+                track_time: row.get(1)?,
+                track_id: row.get(2)?,
+            })?;
+        }
+        Ok(())
+    }
+
+    fn size_bytes(&self) -> Result<u64, anyhow::Error> {
+        size_bytes(&self.conn)
+    }
+
+    fn params(&self) -> Result<Option<AnalysisParams>, anyhow::Error> {
+        params(&self.conn)
+    }
+
+    fn set_params(&mut self, params: AnalysisParams) -> Result<(), anyhow::Error> {
+        set_params(&self.conn, params)
+    }
+
+    fn shard_position(&self) -> Result<Option<ShardPosition>, anyhow::Error> {
+        shard_position(&self.conn)
+    }
+
+    fn set_shard_position(&mut self, position: ShardPosition) -> Result<(), anyhow::Error> {
+        set_shard_position(&self.conn, position)
+    }
+}
+
+// The tracks and meta tables are shared with packed storage
+
+/// Records the track, keeping the id of any with the same title. Returns the id and
+/// whether there was one, whose fingerprints are for the caller to remove.
+pub(super) fn upsert_track(
+    conn: &Connection,
+    title: &str,
+    path: Option<&Path>,
+) -> Result<(u32, bool), anyhow::Error> {
+    let path = path.map(|path| path.to_string_lossy().to_string());
+    if let Some(track_id) = find_track(conn, title)? {
+        conn.execute(
+            "UPDATE tracks SET path = ?1 WHERE id = ?2",
+            params![path, track_id],
+        )
+        .context("Failed to update track.")?;
+        return Ok((track_id, true));
+    }
+    conn.execute(
+        "INSERT INTO tracks (title, path) VALUES (?1, ?2)",
+        params![title, path],
+    )
+    .context("Failed to insert track.")?;
+    Ok((conn.last_insert_rowid() as u32, false))
+}
+
+pub(super) fn find_track(conn: &Connection, title: &str) -> Result<Option<u32>, anyhow::Error> {
+    let id = conn
+        .prepare_cached("SELECT id FROM tracks WHERE title = ?1")?
+        .query_row([title], |row| row.get(0))
+        .optional()?;
+    Ok(id)
+}
+
+pub(super) fn track(conn: &Connection, track_id: u32) -> Result<Option<Track>, anyhow::Error> {
+    let track = conn
+        .prepare_cached("SELECT title, path FROM tracks WHERE id = ?1")?
+        .query_row([track_id], |row| {
+            Ok(Track {
+                title: row.get(0)?,
+                path: row.get::<_, Option<String>>(1)?.map(PathBuf::from),
+            })
+        })
+        .optional()?;
+    Ok(track)
+}
+
+pub(super) fn tracks(conn: &Connection) -> Result<Vec<(u32, Track)>, anyhow::Error> {
+    let mut query = conn.prepare_cached("SELECT id, title, path FROM tracks ORDER BY id")?;
+    let tracks = query
+        .query_map([], |row| {
+            Ok((
+                row.get(0)?,
+                Track {
+                    title: row.get(1)?,
+                    path: row.get::<_, Option<String>>(2)?.map(PathBuf::from),
+                },
+            ))
+        })?
+        .collect::<Result<_, _>>()?;
+    Ok(tracks)
+}
+
+/// Size of the database in bytes
+pub(super) fn size_bytes(conn: &Connection) -> Result<u64, anyhow::Error> {
+    let page_count: u64 = conn.query_row("PRAGMA page_count", [], |row| row.get(0))?;
+    let page_size: u64 = conn.query_row("PRAGMA page_size", [], |row| row.get(0))?;
+    Ok(page_count * page_size)
+}
+
+/// Creates the tracks table. Ids of removed tracks aren't reused, except in databases
+/// made before that was so, which may reuse the id of the last track.
+pub(super) fn create_tracks_table(conn: &Connection) -> Result<(), anyhow::Error> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS tracks (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            title TEXT NOT NULL,
+            path TEXT
+        )",
+        (), // empty list of parameters.
+    )?;
+    // Databases made before paths were recorded lack the column
+    if conn.prepare("SELECT path FROM tracks LIMIT 0").is_err() {
+        conn.execute("ALTER TABLE tracks ADD COLUMN path TEXT", ())?;
+    }
+    Ok(())
+}
+
+/// Key of the analysis parameters in the meta table, as JSON
+const PARAMS_KEY: &str = "analysis_params";
+
+pub(super) fn params(conn: &Connection) -> Result<Option<AnalysisParams>, anyhow::Error> {
+    meta(conn, PARAMS_KEY)?
+        .map(|value| {
+            serde_json::from_str(&value).context("The recorded analysis parameters are invalid.")
+        })
+        .transpose()
+}
+
+pub(super) fn set_params(conn: &Connection, params: AnalysisParams) -> Result<(), anyhow::Error> {
+    set_meta(conn, PARAMS_KEY, &serde_json::to_string(&params)?)
+}
+
+/// Key of the shard position in the meta table, as JSON
+const SHARD_POSITION_KEY: &str = "shard_position";
+
+pub(super) fn shard_position(conn: &Connection) -> Result<Option<ShardPosition>, anyhow::Error> {
+    meta(conn, SHARD_POSITION_KEY)?
+        .map(|value| {
+            serde_json::from_str(&value).context("The recorded shard position is invalid.")
+        })
+        .transpose()
+}
+
+pub(super) fn set_shard_position(
+    conn: &Connection,
+    position: ShardPosition,
+) -> Result<(), anyhow::Error> {
+    set_meta(conn, SHARD_POSITION_KEY, &serde_json::to_string(&position)?)
+}
+
+pub(super) fn meta(conn: &Connection, key: &str) -> Result<Option<String>, anyhow::Error> {
+    let value = conn
+        .prepare_cached("SELECT value FROM meta WHERE key = ?1")?
+        .query_row([key], |row| row.get(0))
+        .optional()?;
+    Ok(value)
+}
+
+pub(super) fn set_meta(conn: &Connection, key: &str, value: &str) -> Result<(), anyhow::Error> {
+    conn.execute(
+        "INSERT OR REPLACE INTO meta (key, value) VALUES (?1, ?2)",
+        params![key, value],
+    )?;
+    Ok(())
+}
+
+/// Creates the table of facts about the database as a whole, such as the analysis
+/// parameters it was made with
+pub(super) fn create_meta_table(conn: &Connection) -> Result<(), anyhow::Error> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS meta (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        )",
+        (),
+    )?;
+    Ok(())
+}
+
+fn create_tables(conn: &Connection) -> Result<(), anyhow::Error> {
+    create_tracks_table(conn)?;
+    create_meta_table(conn)?;
+    // Create fingerprints table
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS fingerprints (
+            id INTEGER PRIMARY KEY,
+            hash INTEGER NOT NULL,
+            track_time INTEGER NOT NULL,
+            track_id INTEGER NOT NULL
+        )",
+        (),
+    )?;
+    // Index hashes so that lookups during matching don't scan the whole table
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS fingerprints_hash ON fingerprints (hash)",
+        (),
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,444 @@
+use std::collections::HashMap;
+
+use ndarray::{s, Array2, Axis};
+
+use crate::{
+    audio_ops::{self, PcmReader},
+    hash,
+    hash::PairRecord,
+    image_ops,
+    matching::{self, Matcher, OffsetBinning},
+    report::StreamEvent,
+    storage::{Fingerprint, Storage},
+    AnalysisParams, Index,
+};
+
+/// Fingerprints audio as it arrives.
+/// Samples are turned into spectrogram windows as soon as a whole window is available,
+/// and peaks are found once the maximum filter kernel around them is complete, giving
+/// the same peaks as analysing the whole recording at once.
+/// Only the windows still needed for peak finding are kept.
+pub struct StreamFingerprinter {
+    params: AnalysisParams,
+    sample_rate: u32,
+    pending: Vec<f32>,        // samples not yet making up a whole window
+    spectrogram: Array2<f32>, // most recent windows, the first being window `first_window`
+    first_window: usize,
+    peaks_until: usize,         // windows before this have had their peaks found
+    peaks: Vec<(usize, usize)>, // (window, frequency bin), ordered by window
+}
+
+impl StreamFingerprinter {
+    pub fn new(params: AnalysisParams, sample_rate: u32) -> Result<Self, anyhow::Error> {
+        let window_size = audio_ops::window_size(sample_rate, params.window_length);
+        if window_size == 0 {
+            anyhow::bail!("Window length is shorter than one sample.");
+        }
+
+        Ok(StreamFingerprinter {
+            params,
+            sample_rate,
+            pending: vec![],
+            spectrogram: Array2::zeros((0, window_size / 2 + 1)),
+            first_window: 0,
+            peaks_until: 0,
+            peaks: vec![],
+        })
+    }
+
+    /// Total number of whole windows received
+    pub fn windows_seen(&self) -> usize {
+        self.first_window + self.spectrogram.nrows()
+    }
+
+    /// Adds mono samples, scaled like 16 bit samples, and finds any peaks that are now certain
+    pub fn push_samples(&mut self, samples: &[f32]) -> Result<(), anyhow::Error> {
+        self.pending.extend_from_slice(samples);
+        let window_size = audio_ops::window_size(self.sample_rate, self.params.window_length);
+        let whole_windows = self.pending.len() / window_size;
+        if whole_windows == 0 {
+            return Ok(());
+        }
+
+        let new_windows = audio_ops::samples_to_fft(
+            &self.pending[..whole_windows * window_size],
+            self.sample_rate,
+            self.params.window_length,
+        )?;
+        self.pending.drain(..whole_windows * window_size);
+        self.spectrogram.append(Axis(0), new_windows.view())?;
+
+        // a peak is certain once every window within half a kernel after it has arrived
+        let half_kernel = self.params.kernel_size / 2;
+        let certain_until = self.windows_seen().saturating_sub(half_kernel);
+        self.find_peaks(certain_until);
+        Ok(())
+    }
+
+    /// Finds the peaks of all remaining windows, at the end of the stream
+    pub fn finish(&mut self) {
+        self.find_peaks(self.windows_seen());
+    }
+
+    /// Drops peaks before `window`, which must not be later than windows_seen
+    pub fn forget_before(&mut self, window: usize) {
+        self.peaks.retain(|&(time, _)| time >= window);
+    }
+
+    /// Windows before this have had all their peaks found
+    pub fn peaks_found_until(&self) -> usize {
+        self.peaks_until
+    }
+
+    /// Fingerprints the peaks from `window` onwards, with times relative to `window`
+    pub fn fingerprint_since(&self, window: usize) -> HashMap<u32, PairRecord> {
+        self.fingerprint_between(window, usize::MAX)
+    }
+
+    /// Fingerprints the peaks of windows `start` to `end`, with times relative to `start`
+    pub fn fingerprint_between(&self, start: usize, end: usize) -> HashMap<u32, PairRecord> {
+        let peaks: Vec<(usize, usize)> = self
+            .peaks
+            .iter()
+            .filter(|&&(time, _)| time >= start && time < end)
+            .map(|&(time, freq)| (time - start, freq))
+            .collect();
+        hash::fingerprint(
+            &peaks,
+            self.params.window_length,
+            self.params.target_zone_delay_sec,
+            self.params.target_zone_height_hz,
+            self.params.target_zone_width_sec,
+        )
+    }
+
+    /// Finds peaks in windows from peaks_until up to `until`
+    fn find_peaks(&mut self, until: usize) {
+        if until <= self.peaks_until {
+            return;
+        }
+
+        // include half a kernel of context before the first window being searched
+        let half_kernel = self.params.kernel_size / 2;
+        let context_start = self
+            .peaks_until
+            .saturating_sub(half_kernel)
+            .max(self.first_window);
+        let region = self
+            .spectrogram
+            .slice(s![context_start - self.first_window.., ..])
+            .to_owned();
+        let filtered = image_ops::max_filter(&region, self.params.kernel_size);
+        let (search_start, magnitude_threshold) =
+            (self.peaks_until, self.params.magnitude_threshold);
+        let new_peaks = image_ops::find_equal(&region, &filtered)
+            .into_iter()
+            .map(|(time, freq)| (time + context_start, freq))
+            .filter(|&(time, freq)| {
+                time >= search_start
+                    && time < until
+                    && region[(time - context_start, freq)] > magnitude_threshold
+            });
+        self.peaks.extend(new_peaks);
+        self.peaks_until = until;
+
+        // windows before half a kernel ahead of the next search are no longer needed
+        let keep_from = self
+            .peaks_until
+            .saturating_sub(half_kernel)
+            .max(self.first_window);
+        self.spectrogram = self
+            .spectrogram
+            .slice(s![keep_from - self.first_window.., ..])
+            .to_owned();
+        self.first_window = keep_from;
+    }
+}
+
+/// The stored fingerprints of the hashes in a stream's current segment, so each
+/// decision only looks up the hashes of pairs found since the last one
+#[derive(Debug, Default)]
+pub struct LookupCache {
+    fingerprints: HashMap<u32, Vec<Fingerprint>>, // by hash, empty for hashes not stored
+}
+
+impl LookupCache {
+    /// Every stored fingerprint with one of the segment's hashes. Hashes no longer in
+    /// the segment are forgotten.
+    pub fn lookup(
+        &mut self,
+        index: &Index<impl Storage>,
+        pair_records: &HashMap<u32, PairRecord>,
+    ) -> Result<Vec<Fingerprint>, anyhow::Error> {
+        self.fingerprints
+            .retain(|hash, _| pair_records.contains_key(hash));
+        let new_hashes: Vec<u32> = pair_records
+            .keys()
+            .filter(|hash| !self.fingerprints.contains_key(hash))
+            .copied()
+            .collect();
+        for &hash in &new_hashes {
+            self.fingerprints.insert(hash, vec![]);
+        }
+        for fingerprint in index.lookup(&new_hashes)? {
+            if let Some(found) = self.fingerprints.get_mut(&fingerprint.hash) {
+                found.push(fingerprint);
+            }
+        }
+        Ok(self.fingerprints.values().flatten().copied().collect())
+    }
+}
+
+/// How often a stream is decided on and how much of it each decision hears
+#[derive(Debug, Clone)]
+pub struct StreamOptions {
+    pub segment_length: f32,    // in seconds of audio
+    pub decision_interval: f32, // in seconds of audio
+    pub binning: OffsetBinning,
+    pub thresholds: Vec<f64>, // confidences to report events at
+}
+
+/// Identifies tracks in audio as it arrives, deciding on the most recent
+/// `segment_length` seconds every `decision_interval` seconds.
+/// An event is reported each time the confidence of the identified track reaches
+/// another of the thresholds. Returns whether any track was identified.
+pub fn identify(
+    pcm_reader: &mut PcmReader,
+    params: AnalysisParams,
+    matcher: &mut Matcher<impl Storage>,
+    options: &StreamOptions,
+    mut report: impl FnMut(StreamEvent) -> Result<(), anyhow::Error>,
+) -> Result<bool, anyhow::Error> {
+    let sample_rate = pcm_reader.sample_rate();
+    let mut fingerprinter = StreamFingerprinter::new(params, sample_rate)?;
+    let mut thresholds = options.thresholds.clone();
+    thresholds.sort_by(f64::total_cmp);
+    let Some(&lowest) = thresholds.first() else {
+        anyhow::bail!("Please give at least one threshold.");
+    };
+
+    let window_length = params.window_length;
+    let segment_windows = ((options.segment_length / window_length) as usize).max(1);
+    let chunk_frames = ((options.decision_interval * sample_rate as f32) as usize).max(1);
+
+    let mut lookups = LookupCache::default();
+    // track currently identified, the window its evidence began and thresholds reached
+    let mut identified: Option<(u32, usize, usize)> = None;
+    let mut identified_any = false;
+    loop {
+        let chunk = pcm_reader.read_chunk(chunk_frames)?;
+        let finished = chunk.len() < chunk_frames;
+        fingerprinter.push_samples(&chunk)?;
+        if finished {
+            fingerprinter.finish();
+        }
+
+        let now = fingerprinter.windows_seen();
+        let segment_start = now.saturating_sub(segment_windows);
+        fingerprinter.forget_before(segment_start);
+        let pair_records = fingerprinter.fingerprint_since(segment_start);
+        let fingerprints = lookups.lookup(matcher.index(), &pair_records)?;
+        let candidates = matcher.rank_fingerprints(
+            &pair_records,
+            &fingerprints,
+            options.binning,
+            matching::MAX_CANDIDATES,
+        )?;
+
+        // the identified track is kept through weak decisions, until another one wins
+        if let Some(best) = matching::decide(&candidates, lowest) {
+            if identified.map(|(track_id, _, _)| track_id) != Some(best.track_id) {
+                // evidence begins where the track starts, if that is within the segment
+                let evidence_start = segment_start + (-best.offset).max(0) as usize;
+                identified = Some((best.track_id, evidence_start, 0));
+            }
+            if let Some((_, evidence_start, reached)) = identified.as_mut() {
+                while *reached < thresholds.len() && best.confidence >= thresholds[*reached] {
+                    report(StreamEvent {
+                        stream_time_sec: now as f32 * window_length,
+                        latency_sec: now.saturating_sub(*evidence_start) as f32 * window_length,
+                        track_id: best.track_id,
+                        title: best.title.clone(),
+                        track_offset_sec: (best.offset + (now - segment_start) as i64) as f32
+                            * window_length,
+                        confidence: best.confidence,
+                        threshold: thresholds[*reached],
+                    })?;
+                    *reached += 1;
+                    identified_any = true;
+                }
+            }
+        }
+
+        if finished {
+            break;
+        }
+    }
+
+    Ok(identified_any)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{
+        audio_ops::PcmFormat,
+        eval::StageTimings,
+        storage::MemoryStorage,
+        test_audio::{tones, SAMPLE_RATE},
+        Fingerprinter, MatchOptions,
+    };
+
+    #[test]
+    fn streamed_audio_has_the_peaks_of_the_whole_recording() {
+        let params = AnalysisParams::default();
+        let samples = tones(6, 1);
+        let whole = Fingerprinter::new(params)
+            .unwrap()
+            .analyse_samples(&samples, SAMPLE_RATE, &mut StageTimings::default())
+            .unwrap();
+
+        let mut streamed = StreamFingerprinter::new(params, SAMPLE_RATE).unwrap();
+        let mut rest = &samples[..];
+        for len in [1, 37, 800, 2999, 5].into_iter().cycle() {
+            let (chunk, after) = rest.split_at(len.min(rest.len()));
+            streamed.push_samples(chunk).unwrap();
+            // only the windows peaks are still being found in are kept
+            assert!(streamed.spectrogram.nrows() <= params.kernel_size);
+            rest = after;
+            if rest.is_empty() {
+                break;
+            }
+        }
+        streamed.finish();
+
+        assert_eq!(streamed.windows_seen(), whole.windows.nrows());
+        assert_eq!(streamed.peaks, whole.peaks);
+        let times = |hashes: HashMap<u32, PairRecord>| -> HashMap<u32, u32> {
+            hashes
+                .into_iter()
+                .map(|(hash, record)| (hash, record.time_a))
+                .collect()
+        };
+        assert_eq!(times(streamed.fingerprint_since(0)), times(whole.hashes));
+    }
+
+    #[test]
+    fn lookups_are_cached_for_the_hashes_still_in_the_segment() {
+        let records = |hashes: &[u32]| -> HashMap<u32, PairRecord> {
+            hashes
+                .iter()
+                .map(|&hash| (hash, PairRecord { hash, time_a: hash }))
+                .collect()
+        };
+        let found = |fingerprints: Vec<Fingerprint>| {
+            let mut found: Vec<(u32, u32)> = fingerprints
+                .iter()
+                .map(|fingerprint| (fingerprint.hash, fingerprint.track_id))
+                .collect();
+            found.sort_unstable();
+            found
+        };
+        let mut index = Index::new(MemoryStorage::default());
+        let a = index.add_track("a", None, &records(&[1, 2])).unwrap();
+        let mut cache = LookupCache::default();
+        assert_eq!(
+            found(cache.lookup(&index, &records(&[1, 2])).unwrap()),
+            vec![(1, a), (2, a)]
+        );
+
+        // a track added since isn't found by hashes already looked up, so they weren't
+        // looked up again
+        let b = index.add_track("b", None, &records(&[1, 2, 3])).unwrap();
+        assert_eq!(
+            found(cache.lookup(&index, &records(&[1, 2, 3])).unwrap()),
+            vec![(1, a), (2, a), (3, b)]
+        );
+
+        // hashes that left the segment are forgotten, and looked up again if they return
+        cache.lookup(&index, &records(&[3])).unwrap();
+        assert_eq!(
+            found(cache.lookup(&index, &records(&[1, 3])).unwrap()),
+            vec![(1, a), (1, b), (3, b)]
+        );
+    }
+
+    fn stream(samples: &[f32]) -> PcmReader {
+        let bytes: Vec<u8> = samples
+            .iter()
+            .flat_map(|&sample| (sample as i16).to_le_bytes())
+            .collect();
+        PcmReader::new(
+            Box::new(Cursor::new(bytes)),
+            PcmFormat::S16le,
+            SAMPLE_RATE,
+            1,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn tracks_are_identified_as_the_stream_arrives() {
+        let params = AnalysisParams::default();
+        let fingerprinter = Fingerprinter::new(params).unwrap();
+        let mut index = Index::new(MemoryStorage::default());
+        let track = tones(12, 1);
+        for (title, samples) in [("a track", &track), ("another", &tones(12, 2))] {
+            let analysis = fingerprinter
+                .analyse_samples(samples, SAMPLE_RATE, &mut StageTimings::default())
+                .unwrap();
+            index.add_track(title, None, &analysis.hashes).unwrap();
+        }
+        let mut matcher = Matcher::new(index).unwrap();
+        let options = StreamOptions {
+            segment_length: 6.,
+            decision_interval: 1.,
+            binning: MatchOptions::default().binning,
+            thresholds: vec![0.99, 0.9],
+        };
+
+        // 3s of something else, then the track from 2s in
+        let mut samples = tones(3, 9);
+        samples.extend_from_slice(&track[2 * SAMPLE_RATE as usize..10 * SAMPLE_RATE as usize]);
+        let mut events = vec![];
+        let identified = identify(
+            &mut stream(&samples),
+            params,
+            &mut matcher,
+            &options,
+            |event| {
+                events.push(event);
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        assert!(identified);
+        let thresholds: Vec<f64> = events.iter().map(|event| event.threshold).collect();
+        assert_eq!(thresholds, vec![0.9, 0.99]);
+        for event in &events {
+            assert_eq!(event.title, "a track");
+            assert!(event.confidence >= event.threshold);
+            assert!(event.latency_sec <= event.stream_time_sec);
+            let track_time = event.stream_time_sec - 3. + 2.;
+            assert!(
+                (event.track_offset_sec - track_time).abs() < 0.3,
+                "{:?}",
+                event
+            );
+        }
+
+        // a stream of neither track identifies nothing
+        let unknown = identify(
+            &mut stream(&tones(6, 9)),
+            params,
+            &mut matcher,
+            &options,
+            |_| panic!("nothing should be identified"),
+        )
+        .unwrap();
+        assert!(!unknown);
+    }
+}
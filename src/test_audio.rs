@@ -0,0 +1,26 @@
+//! Audio shared by the unit tests, as tests/common is by the integration tests
+
+use std::f32::consts::PI;
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+pub const SAMPLE_RATE: u32 = 8000;
+
+/// Chords of three random tones, changing every fifth of a second, within the range of
+/// 16 bit samples
+pub fn tones(seconds: usize, seed: u64) -> Vec<f32> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let chord_len = SAMPLE_RATE as usize / 5;
+    let mut samples = vec![];
+    for _ in 0..seconds * 5 {
+        let frequencies: Vec<f32> = (0..3).map(|_| rng.gen_range(200.0..3500.0)).collect();
+        samples.extend((0..chord_len).map(|n| {
+            let t = n as f32 / SAMPLE_RATE as f32;
+            frequencies
+                .iter()
+                .map(|frequency| 8000. * (2. * PI * frequency * t).sin())
+                .sum::<f32>()
+        }));
+    }
+    samples
+}
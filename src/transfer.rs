@@ -0,0 +1,553 @@
+//! Moving tracks and their fingerprints between databases.
+//!
+//! Exports are JSON Lines, one object per line, so they can be read a track at a time and
+//! by other tools. The first line is the header:
+//!
+//! `{"format":"atlas-fingerprints","version":1,"params":{...},"tracks":2}`
+//!
+//! where `params` are the [`AnalysisParams`] the fingerprints were made with and `tracks`
+//! the number of track lines that follow, each
+//!
+//! `{"title":"track1","path":"/music/track1.wav","content_hash":"9f1c...","fingerprints":[[hash,time],...]}`
+//!
+//! with `path` null when unknown, `content_hash` as below and each fingerprint's 32 bit
+//! hash and time in windows of `params.window_length`. Readers must reject a later
+//! version and ignore fields they don't know.
+//!
+//! Databases record the analysis parameters they were made with, and tracks are only
+//! moved between databases made with the same ones.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::{BufRead, Write},
+    path::PathBuf,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{hash::PairRecord, storage::Storage, AnalysisParams};
+
+pub const FORMAT: &str = "atlas-fingerprints";
+pub const VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Header {
+    format: String,
+    version: u32,
+    params: AnalysisParams,
+    tracks: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct TrackRecord {
+    title: String,
+    path: Option<PathBuf>,
+    content_hash: String,
+    fingerprints: Vec<(u32, u32)>, // (hash, track_time), sorted
+}
+
+/// What to do with a track whose title a track with other fingerprints already has
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TitleConflict {
+    Reject,  // fail without adding anything
+    Rename,  // add it with " (2)", " (3)" and so on after its title
+    Replace, // replace the track with the title, which keeps its id
+}
+
+/// Tracks added to a database and tracks skipped as already in it
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TransferSummary {
+    pub added: usize, // including those renamed
+    pub duplicates: usize,
+    pub renamed: usize,
+    pub replaced: usize,
+}
+
+/// Identifies a track by its fingerprints, so the same audio analysed with the same
+/// parameters has the same content hash wherever it was added. FNV-1a over the sorted
+/// (hash, time) pairs as little-endian bytes, written as 16 hex digits.
+fn content_hash(fingerprints: &[(u32, u32)]) -> String {
+    let mut state: u64 = 0xcbf29ce484222325;
+    for (hash, time) in fingerprints {
+        for byte in hash.to_le_bytes().into_iter().chain(time.to_le_bytes()) {
+            state ^= byte as u64;
+            state = state.wrapping_mul(0x100000001b3);
+        }
+    }
+    format!("{:016x}", state)
+}
+
+/// Every track of a store with its sorted fingerprints
+fn track_records(storage: &impl Storage) -> Result<Vec<TrackRecord>, anyhow::Error> {
+    let mut fingerprints: HashMap<u32, Vec<(u32, u32)>> = HashMap::new();
+    storage.scan_fingerprints(&mut |fingerprint| {
+        fingerprints
+            .entry(fingerprint.track_id)
+            .or_default()
+            .push((fingerprint.hash, fingerprint.track_time));
+        Ok(())
+    })?;
+
+    Ok(storage
+        .tracks()?
+        .into_iter()
+        .map(|(track_id, track)| {
+            let mut fingerprints = fingerprints.remove(&track_id).unwrap_or_default();
+            fingerprints.sort_unstable();
+            TrackRecord {
+                title: track.title,
+                path: track.path,
+                content_hash: content_hash(&fingerprints),
+                fingerprints,
+            }
+        })
+        .collect())
+}
+
+/// Adds tracks to a store unless a track with the same content is already there
+struct Merger<'a, S: Storage> {
+    target: &'a mut S,
+    content_hashes: HashSet<String>,
+    titles: HashMap<String, String>, // content hash of the track with each title
+    on_conflict: TitleConflict,
+    summary: TransferSummary,
+}
+
+impl<'a, S: Storage> Merger<'a, S> {
+    fn new(target: &'a mut S, on_conflict: TitleConflict) -> Result<Self, anyhow::Error> {
+        let records = track_records(target)?;
+        Ok(Merger {
+            content_hashes: records
+                .iter()
+                .map(|record| record.content_hash.clone())
+                .collect(),
+            titles: records
+                .into_iter()
+                .map(|record| (record.title, record.content_hash))
+                .collect(),
+            target,
+            on_conflict,
+            summary: TransferSummary::default(),
+        })
+    }
+
+    /// Fails if conflicting titles are rejected and any of the records, whose
+    /// fingerprints must be sorted, has one. Checked before adding, so nothing is.
+    fn check(&self, records: &[TrackRecord]) -> Result<(), anyhow::Error> {
+        if self.on_conflict != TitleConflict::Reject {
+            return Ok(());
+        }
+        let mut content_hashes = self.content_hashes.clone();
+        let mut titles: HashSet<&str> = self.titles.keys().map(String::as_str).collect();
+        let conflicts: Vec<&str> = records
+            .iter()
+            .filter(|record| {
+                content_hashes.insert(content_hash(&record.fingerprints))
+                    && !titles.insert(&record.title)
+            })
+            .map(|record| record.title.as_str())
+            .collect();
+        if !conflicts.is_empty() {
+            anyhow::bail!(
+                "Tracks with other fingerprints already have the titles {}, rename or replace them instead.",
+                conflicts.join(", ")
+            );
+        }
+        Ok(())
+    }
+
+    /// Adds a track, whose fingerprints must be sorted
+    fn add(&mut self, record: TrackRecord) -> Result<(), anyhow::Error> {
+        // recompute rather than trust the record, which may have been edited
+        let hash = content_hash(&record.fingerprints);
+        if self.content_hashes.contains(&hash) {
+            self.summary.duplicates += 1;
+            return Ok(());
+        }
+
+        let mut title = record.title;
+        match (self.titles.get(&title), self.on_conflict) {
+            (None, _) => self.summary.added += 1,
+            (Some(_), TitleConflict::Reject) => {
+                anyhow::bail!(
+                    "A track with other fingerprints is already titled {}.",
+                    title
+                )
+            }
+            (Some(_), TitleConflict::Rename) => {
+                title = (2..)
+                    .map(|number| format!("{} ({})", title, number))
+                    .find(|renamed| !self.titles.contains_key(renamed))
+                    .context("No title is free.")?;
+                self.summary.added += 1;
+                self.summary.renamed += 1;
+            }
+            (Some(replaced), TitleConflict::Replace) => {
+                self.content_hashes.remove(replaced);
+                self.summary.replaced += 1;
+            }
+        }
+
+        let pair_records: HashMap<u32, PairRecord> = record
+            .fingerprints
+            .into_iter()
+            .map(|(hash, time_a)| (hash, PairRecord { hash, time_a }))
+            .collect();
+        self.target
+            .replace_track(&title, record.path.as_deref(), &pair_records)?;
+        self.content_hashes.insert(hash.clone());
+        self.titles.insert(title, hash);
+        Ok(())
+    }
+
+    /// Checks the records, records `params` as those of the target if it doesn't yet
+    /// say, then adds the records
+    fn add_all(
+        mut self,
+        records: Vec<TrackRecord>,
+        params: AnalysisParams,
+    ) -> Result<TransferSummary, anyhow::Error> {
+        self.check(&records)?;
+        if self.target.params()?.is_none() {
+            self.target.set_params(params)?;
+        }
+        for record in records {
+            self.add(record)?;
+        }
+        Ok(self.summary)
+    }
+}
+
+/// The parameters a store was made with: those it records or, for stores made before
+/// they were recorded, `assumed`. Empty stores that don't record any take any.
+fn params_of(
+    storage: &impl Storage,
+    assumed: AnalysisParams,
+) -> Result<Option<AnalysisParams>, anyhow::Error> {
+    if let Some(params) = storage.params()? {
+        return Ok(Some(params));
+    }
+    Ok((!storage.tracks()?.is_empty()).then_some(assumed))
+}
+
+/// Fails unless fingerprints made with `theirs` can be matched alongside those of a
+/// store made with `ours`
+fn check_params(
+    ours: Option<AnalysisParams>,
+    theirs: AnalysisParams,
+    what: &str,
+) -> Result<(), anyhow::Error> {
+    match ours {
+        Some(ours) if ours != theirs => anyhow::bail!(
+            "{} was made with {:?}, which can't be matched alongside fingerprints made with {:?}.",
+            what,
+            theirs,
+            ours
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Adds the tracks of `source` to `target` under new ids, skipping those whose content
+/// is already in `target` and settling titles already taken as `on_conflict` says. Both
+/// must have been made with the same analysis parameters, which are taken to be
+/// `assumed` for stores that don't record them.
+pub fn merge(
+    target: &mut impl Storage,
+    source: &impl Storage,
+    assumed: AnalysisParams,
+    on_conflict: TitleConflict,
+) -> Result<TransferSummary, anyhow::Error> {
+    let params = params_of(source, assumed)?.unwrap_or(assumed);
+    check_params(params_of(target, assumed)?, params, "The source")?;
+    Merger::new(target, on_conflict)?.add_all(track_records(source)?, params)
+}
+
+/// Writes every track of a store, with the analysis parameters it records or, if it
+/// doesn't, `assumed`. Returns the number written.
+pub fn export(
+    storage: &impl Storage,
+    assumed: AnalysisParams,
+    mut writer: impl Write,
+) -> Result<usize, anyhow::Error> {
+    let records = track_records(storage)?;
+    let header = Header {
+        format: FORMAT.to_string(),
+        version: VERSION,
+        params: storage.params()?.unwrap_or(assumed),
+        tracks: records.len(),
+    };
+    serde_json::to_writer(&mut writer, &header)?;
+    writeln!(writer)?;
+    for record in &records {
+        serde_json::to_writer(&mut writer, record)?;
+        writeln!(writer)?;
+    }
+    writer.flush()?;
+    Ok(records.len())
+}
+
+/// Reads an export into a store, skipping tracks whose content is already there and
+/// settling titles already taken as `on_conflict` says. The store must have been made
+/// with the export's analysis parameters, taken to be `assumed` if it doesn't record them.
+pub fn import(
+    storage: &mut impl Storage,
+    assumed: AnalysisParams,
+    on_conflict: TitleConflict,
+    reader: impl BufRead,
+) -> Result<TransferSummary, anyhow::Error> {
+    let mut lines = reader.lines();
+    let header_line = lines.next().context("The export is empty.")??;
+    let header: Header =
+        serde_json::from_str(&header_line).context("The export has no valid header.")?;
+    if header.format != FORMAT {
+        anyhow::bail!("Expected an {} export, found {}.", FORMAT, header.format);
+    }
+    if header.version > VERSION {
+        anyhow::bail!(
+            "The export is version {}, only versions up to {} can be read.",
+            header.version,
+            VERSION
+        );
+    }
+    check_params(params_of(storage, assumed)?, header.params, "The export")?;
+
+    // read the whole export before adding anything, so a bad one adds nothing
+    let mut records = vec![];
+    for (number, line) in lines.enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut record: TrackRecord = serde_json::from_str(&line)
+            .with_context(|| format!("Track {} of the export is invalid.", number + 1))?;
+        record.fingerprints.sort_unstable();
+        records.push(record);
+    }
+    if records.len() != header.tracks {
+        anyhow::bail!(
+            "The export should hold {} tracks but holds {}, it may be truncated.",
+            header.tracks,
+            records.len()
+        );
+    }
+
+    Merger::new(storage, on_conflict)?.add_all(records, header.params)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    fn params(window_length: f32) -> AnalysisParams {
+        AnalysisParams {
+            window_length,
+            kernel_size: 30,
+            magnitude_threshold: 0.,
+            target_zone_delay_sec: 0.1,
+            target_zone_height_hz: 750.,
+            target_zone_width_sec: 3.,
+        }
+    }
+
+    fn store(window_length: f32, tracks: &[(&str, u32)]) -> MemoryStorage {
+        let mut storage = MemoryStorage::default();
+        storage.set_params(params(window_length)).unwrap();
+        for &(title, hash) in tracks {
+            let pair_records = HashMap::from([(hash, PairRecord { hash, time_a: 1 })]);
+            storage.replace_track(title, None, &pair_records).unwrap();
+        }
+        storage
+    }
+
+    fn titles(storage: &impl Storage) -> Vec<String> {
+        let mut titles: Vec<String> = storage
+            .tracks()
+            .unwrap()
+            .into_iter()
+            .map(|(_, track)| track.title)
+            .collect();
+        titles.sort();
+        titles
+    }
+
+    #[test]
+    fn merging_stores_made_with_other_params_fails() {
+        let mut target = store(0.1, &[("a", 1)]);
+        let source = store(0.2, &[("b", 2)]);
+        let merged = merge(&mut target, &source, params(0.1), TitleConflict::Reject);
+        assert!(merged.is_err());
+        assert_eq!(titles(&target), ["a"]);
+    }
+
+    #[test]
+    fn stores_without_params_take_the_source_params() {
+        let mut target = MemoryStorage::default();
+        let source = store(0.2, &[("b", 2)]);
+        merge(&mut target, &source, params(0.2), TitleConflict::Reject).unwrap();
+        assert_eq!(target.params().unwrap(), Some(params(0.2)));
+    }
+
+    #[test]
+    fn title_conflicts_are_rejected_before_adding_anything() {
+        let mut target = store(0.1, &[("a", 1)]);
+        let source = store(0.1, &[("a", 2), ("b", 3)]);
+        let merged = merge(&mut target, &source, params(0.1), TitleConflict::Reject);
+        assert!(merged.is_err());
+        assert_eq!(titles(&target), ["a"]);
+    }
+
+    #[test]
+    fn title_conflicts_can_be_renamed() {
+        let mut target = store(0.1, &[("a", 1), ("a (2)", 4)]);
+        let source = store(0.1, &[("a", 2), ("b", 3)]);
+        let summary = merge(&mut target, &source, params(0.1), TitleConflict::Rename).unwrap();
+        assert_eq!((summary.added, summary.renamed), (2, 1));
+        assert_eq!(titles(&target), ["a", "a (2)", "a (3)", "b"]);
+    }
+
+    #[test]
+    fn title_conflicts_can_be_replaced() {
+        let mut target = store(0.1, &[("a", 1)]);
+        let id = target.find_track("a").unwrap().unwrap();
+        let source = store(0.1, &[("a", 2)]);
+        let summary = merge(&mut target, &source, params(0.1), TitleConflict::Replace).unwrap();
+        assert_eq!((summary.added, summary.replaced), (0, 1));
+        assert_eq!(target.find_track("a").unwrap(), Some(id));
+        assert!(target.lookup(&[1]).unwrap().is_empty());
+        assert_eq!(target.lookup(&[2]).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn same_content_is_skipped_whatever_its_title() {
+        let mut target = store(0.1, &[("a", 1)]);
+        let source = store(0.1, &[("a copy", 1)]);
+        let summary = merge(&mut target, &source, params(0.1), TitleConflict::Reject).unwrap();
+        assert_eq!((summary.added, summary.duplicates), (0, 1));
+    }
+
+    fn exported(storage: &impl Storage) -> String {
+        let mut export = vec![];
+        let written = super::export(storage, params(0.1), &mut export).unwrap();
+        assert_eq!(written, storage.tracks().unwrap().len());
+        String::from_utf8(export).unwrap()
+    }
+
+    fn import_str(
+        storage: &mut impl Storage,
+        export: &str,
+    ) -> Result<TransferSummary, anyhow::Error> {
+        import(
+            storage,
+            params(0.1),
+            TitleConflict::Reject,
+            export.as_bytes(),
+        )
+    }
+
+    #[test]
+    fn exports_round_trip() {
+        let mut source = store(0.1, &[("a", 1), ("b", 2)]);
+        let pair_records = HashMap::from([
+            (3, PairRecord { hash: 3, time_a: 7 }),
+            (
+                u32::MAX,
+                PairRecord {
+                    hash: u32::MAX,
+                    time_a: 0,
+                },
+            ),
+        ]);
+        source
+            .replace_track("c", Some(Path::new("/music/c.wav")), &pair_records)
+            .unwrap();
+        let export = exported(&source);
+        assert_eq!(export.lines().count(), 4);
+
+        let mut target = MemoryStorage::default();
+        let summary = import_str(&mut target, &export).unwrap();
+        assert_eq!(summary.added, 3);
+        assert_eq!(target.params().unwrap(), Some(params(0.1)));
+        assert_eq!(titles(&target), ["a", "b", "c"]);
+        let c = target.find_track("c").unwrap().unwrap();
+        assert_eq!(
+            target.track(c).unwrap().unwrap().path,
+            Some(PathBuf::from("/music/c.wav"))
+        );
+        let mut found = target.lookup(&[3, u32::MAX]).unwrap();
+        found.sort_by_key(|fingerprint| fingerprint.hash);
+        assert_eq!(
+            found
+                .iter()
+                .map(|found| (found.hash, found.track_time))
+                .collect::<Vec<_>>(),
+            [(3, 7), (u32::MAX, 0)]
+        );
+        // the same export again adds nothing
+        assert_eq!(import_str(&mut target, &export).unwrap().duplicates, 3);
+        assert_eq!(exported(&target), export);
+    }
+
+    #[test]
+    fn truncated_exports_add_nothing() {
+        let export = exported(&store(0.1, &[("a", 1), ("b", 2)]));
+        let lines: Vec<&str> = export.lines().collect();
+        let cut_track = &lines[2][..lines[2].len() / 2];
+        for truncated in [
+            String::new(),
+            lines[0][..10].to_string(),
+            lines[..2].join("\n"),
+            format!("{}\n{}\n{}", lines[0], lines[1], cut_track),
+        ] {
+            let mut target = MemoryStorage::default();
+            assert!(
+                import_str(&mut target, &truncated).is_err(),
+                "{}",
+                truncated
+            );
+            assert!(target.tracks().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn other_formats_and_later_versions_are_rejected() {
+        let export = exported(&store(0.1, &[("a", 1)]));
+        let header = export.lines().next().unwrap();
+        let other_format = export.replacen(FORMAT, "other-fingerprints", 1);
+        let later = export.replacen(
+            &format!("\"version\":{}", VERSION),
+            &format!("\"version\":{}", VERSION + 1),
+            1,
+        );
+        assert_ne!(later, export);
+        let other_params = export.replacen("\"window_length\":0.1", "\"window_length\":0.2", 1);
+        assert_ne!(other_params, export);
+        for rejected in [
+            other_format,
+            later,
+            other_params,
+            format!("[1,2]\n{}", header),
+        ] {
+            let mut target = store(0.1, &[]);
+            assert!(import_str(&mut target, &rejected).is_err(), "{}", rejected);
+            assert!(target.tracks().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn unknown_fields_are_ignored() {
+        let export = exported(&store(0.1, &[("a", 1)]))
+            .replacen(
+                "{\"format\"",
+                "{\"comment\":\"made elsewhere\",\"format\"",
+                1,
+            )
+            .replacen("{\"title\"", "{\"rating\":5,\"title\"", 1);
+        let mut target = MemoryStorage::default();
+        assert_eq!(import_str(&mut target, &export).unwrap().added, 1);
+    }
+}
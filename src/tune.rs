@@ -0,0 +1,330 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+use itertools::iproduct;
+use rand::Rng;
+use serde::Serialize;
+
+use crate::{
+    audio_ops,
+    eval::{self, EvalOutcome, LabelledQuery, StageTimings},
+    AnalysisParams, Fingerprinter, Index, MatchOptions, Matcher,
+};
+
+/// Values of each analysis parameter to try
+#[derive(Debug, Clone)]
+pub struct SearchSpace {
+    pub window_lengths: Vec<f32>,
+    pub kernel_sizes: Vec<usize>,
+    pub magnitude_thresholds: Vec<f32>,
+    pub target_zone_delays_sec: Vec<f32>,
+    pub target_zone_heights_hz: Vec<f32>,
+    pub target_zone_widths_sec: Vec<f32>,
+}
+
+impl SearchSpace {
+    /// Every combination of the parameter values
+    pub fn grid(&self) -> Vec<AnalysisParams> {
+        iproduct!(
+            &self.window_lengths,
+            &self.kernel_sizes,
+            &self.magnitude_thresholds,
+            &self.target_zone_delays_sec,
+            &self.target_zone_heights_hz,
+            &self.target_zone_widths_sec
+        )
+        .map(
+            |(&window_length, &kernel_size, &magnitude_threshold, &delay, &height, &width)| {
+                AnalysisParams {
+                    window_length,
+                    kernel_size,
+                    magnitude_threshold,
+                    target_zone_delay_sec: delay,
+                    target_zone_height_hz: height,
+                    target_zone_width_sec: width,
+                }
+            },
+        )
+        .collect()
+    }
+
+    /// `trials` settings drawn uniformly from the range each parameter's values span
+    pub fn random(&self, trials: usize, rng: &mut impl Rng) -> Vec<AnalysisParams> {
+        (0..trials)
+            .map(|_| AnalysisParams {
+                window_length: sample(&self.window_lengths, rng),
+                kernel_size: sample_int(&self.kernel_sizes, rng),
+                magnitude_threshold: sample(&self.magnitude_thresholds, rng),
+                target_zone_delay_sec: sample(&self.target_zone_delays_sec, rng),
+                target_zone_height_hz: sample(&self.target_zone_heights_hz, rng),
+                target_zone_width_sec: sample(&self.target_zone_widths_sec, rng),
+            })
+            .collect()
+    }
+}
+
+/// A value between the smallest and largest of `values`, which must not be empty
+fn sample(values: &[f32], rng: &mut impl Rng) -> f32 {
+    let min = values.iter().copied().fold(f32::INFINITY, f32::min);
+    let max = values.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    if min < max {
+        rng.gen_range(min..=max)
+    } else {
+        min
+    }
+}
+
+fn sample_int(values: &[usize], rng: &mut impl Rng) -> usize {
+    let min = values.iter().copied().min().unwrap_or_default();
+    let max = values.iter().copied().max().unwrap_or_default();
+    rng.gen_range(min..=max)
+}
+
+/// Accuracy and cost of one setting of the analysis parameters
+#[derive(Serialize, Debug, Clone)]
+pub struct TrialResult {
+    pub window_length: f32,
+    pub kernel_size: usize,
+    pub magnitude_threshold: f32,
+    pub target_zone_delay_sec: f32,
+    pub target_zone_height_hz: f32,
+    pub target_zone_width_sec: f32,
+    pub top1_accuracy: f64,
+    pub false_positive_rate: f64, // at the minimum confidence
+    pub hashes_per_sec: f64,      // stored per second of reference audio
+    pub db_bytes: u64,
+    pub postings_per_query: f64, // stored fingerprints ranked to match a query, on average
+    pub query_ms: f64,           // mean time to match a query, reported but not ranked on
+    pub pareto_optimal: bool,
+}
+
+impl TrialResult {
+    /// Whether this is at least as good in every objective and better in one
+    fn dominates(&self, other: &TrialResult) -> bool {
+        let no_worse = self.top1_accuracy >= other.top1_accuracy
+            && self.db_bytes <= other.db_bytes
+            && self.postings_per_query <= other.postings_per_query;
+        let better = self.top1_accuracy > other.top1_accuracy
+            || self.db_bytes < other.db_bytes
+            || self.postings_per_query < other.postings_per_query;
+        no_worse && better
+    }
+}
+
+/// Marks the results that no other result beats on accuracy, database size and the
+/// postings scanned per query
+pub fn mark_pareto_optimal(results: &mut [TrialResult]) {
+    let optimal: Vec<bool> = results
+        .iter()
+        .map(|result| !results.iter().any(|other| other.dominates(result)))
+        .collect();
+    for (result, optimal) in results.iter_mut().zip(optimal) {
+        result.pareto_optimal = optimal;
+    }
+}
+
+/// The most accurate Pareto-optimal result, preferring smaller databases between equals
+pub fn suggest(results: &[TrialResult]) -> Option<&TrialResult> {
+    results
+        .iter()
+        .filter(|result| result.pareto_optimal)
+        .max_by(|a, b| {
+            a.top1_accuracy
+                .total_cmp(&b.top1_accuracy)
+                .then(b.db_bytes.cmp(&a.db_bytes))
+        })
+}
+
+/// Tries each setting of the analysis parameters, fingerprinting the reference audio
+/// into a temporary in-memory database and matching the labelled queries against it.
+/// The results are marked Pareto-optimal on accuracy, database size and the postings
+/// scanned per query. Each trial is passed to `on_trial`, numbered from 1, as it starts.
+pub fn run_trials(
+    references: &[PathBuf],
+    labels: &[LabelledQuery],
+    trials: Vec<AnalysisParams>,
+    options: &MatchOptions,
+    mut on_trial: impl FnMut(usize, &AnalysisParams),
+) -> Result<Vec<TrialResult>, anyhow::Error> {
+    let reference_sec = references
+        .iter()
+        .map(|path| {
+            audio_ops::wav_duration_sec(path).with_context(|| {
+                format!(
+                    "Could not read {}, references must be audio to analyse.",
+                    path.display()
+                )
+            })
+        })
+        .sum::<Result<f64, _>>()?;
+
+    let mut results = vec![];
+    for (i, params) in trials.into_iter().enumerate() {
+        on_trial(i + 1, &params);
+        let fingerprinter = Fingerprinter::new(params)?;
+        let mut index = Index::in_memory()?;
+        let mut hashes = 0;
+        for reference in references {
+            let analysis = fingerprinter.analyse_file(reference, &mut StageTimings::default())?;
+            let title = reference
+                .file_stem()
+                .context("Please provide a file not a directory.")?
+                .to_string_lossy();
+            index.add_track(&title, Some(reference), &analysis.hashes)?;
+            hashes += analysis.hashes.len();
+        }
+        let db_bytes = index.size_bytes()?;
+
+        let mut matcher = Matcher::new(index)?;
+        let mut timings = StageTimings::default();
+        let outcomes: Vec<EvalOutcome> = labels
+            .iter()
+            .map(|label| EvalOutcome {
+                label: label.clone(),
+                // matched without plots, which each trial would overwrite
+                report: matcher
+                    .match_file(&fingerprinter, &label.path, options, &mut timings)
+                    .map(|outcome| outcome.report),
+            })
+            .collect();
+        let summary = eval::summarize(&outcomes, options.top, &[options.min_confidence], timings);
+        let mean_timings = &summary.mean_timings;
+
+        results.push(TrialResult {
+            window_length: params.window_length,
+            kernel_size: params.kernel_size,
+            magnitude_threshold: params.magnitude_threshold,
+            target_zone_delay_sec: params.target_zone_delay_sec,
+            target_zone_height_hz: params.target_zone_height_hz,
+            target_zone_width_sec: params.target_zone_width_sec,
+            top1_accuracy: summary.top1_accuracy,
+            false_positive_rate: summary.thresholds[0].false_positive_rate,
+            hashes_per_sec: hashes as f64 / reference_sec.max(f64::EPSILON),
+            db_bytes,
+            postings_per_query: matcher.postings_scanned() as f64 / labels.len().max(1) as f64,
+            query_ms: mean_timings.decode_ms
+                + mean_timings.max_filter_ms
+                + mean_timings.peaks_ms
+                + mean_timings.fingerprint_ms
+                + mean_timings.lookup_ms,
+            pareto_optimal: false,
+        });
+    }
+
+    mark_pareto_optimal(&mut results);
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+    use crate::test_audio::{tones, SAMPLE_RATE};
+
+    fn space() -> SearchSpace {
+        SearchSpace {
+            window_lengths: vec![0.1],
+            kernel_sizes: vec![20, 5, 10],
+            magnitude_thresholds: vec![0., 0.5],
+            target_zone_delays_sec: vec![0.1],
+            target_zone_heights_hz: vec![500., 1000.],
+            target_zone_widths_sec: vec![3.],
+        }
+    }
+
+    #[test]
+    fn random_settings_are_drawn_from_the_ranges() {
+        let trials = space().random(200, &mut StdRng::seed_from_u64(7));
+        assert_eq!(trials.len(), 200);
+        for params in &trials {
+            assert_eq!(params.window_length, 0.1);
+            assert!((5..=20).contains(&params.kernel_size));
+            assert!((0. ..=0.5).contains(&params.magnitude_threshold));
+            assert!((500. ..=1000.).contains(&params.target_zone_height_hz));
+        }
+        // values between those listed are tried too
+        assert!(trials
+            .iter()
+            .any(|params| ![5, 10, 20].contains(&params.kernel_size)));
+    }
+
+    fn result(top1_accuracy: f64, db_bytes: u64, postings_per_query: f64) -> TrialResult {
+        TrialResult {
+            window_length: 0.1,
+            kernel_size: 10,
+            magnitude_threshold: 0.,
+            target_zone_delay_sec: 0.1,
+            target_zone_height_hz: 750.,
+            target_zone_width_sec: 3.,
+            top1_accuracy,
+            false_positive_rate: 0.,
+            hashes_per_sec: 0.,
+            db_bytes,
+            postings_per_query,
+            query_ms: 0.,
+            pareto_optimal: false,
+        }
+    }
+
+    #[test]
+    fn the_front_ignores_query_time() {
+        let mut results = vec![
+            result(0.9, 1000, 50.),
+            result(0.9, 1000, 80.),
+            result(0.8, 500, 80.),
+        ];
+        results[0].query_ms = 100.;
+        mark_pareto_optimal(&mut results);
+        let optimal: Vec<bool> = results.iter().map(|result| result.pareto_optimal).collect();
+        assert_eq!(optimal, [true, false, true]);
+        assert_eq!(suggest(&results).unwrap().postings_per_query, 50.);
+    }
+
+    #[test]
+    fn trials_fingerprint_the_references_and_match_the_queries() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut references = vec![];
+        let mut labels = vec![];
+        for (seed, title) in [(1, "a track"), (2, "another")] {
+            let track = tones(10, seed);
+            let reference = dir.path().join(format!("{}.wav", title));
+            audio_ops::write_wav_mono(&reference, &track, SAMPLE_RATE).unwrap();
+            references.push(reference);
+
+            let query = dir.path().join(format!("{}@2.00.wav", title));
+            let excerpt = &track[2 * SAMPLE_RATE as usize..8 * SAMPLE_RATE as usize];
+            audio_ops::write_wav_mono(&query, excerpt, SAMPLE_RATE).unwrap();
+            labels.push(LabelledQuery {
+                path: query,
+                expected_track: Some(title.to_string()),
+                expected_offset_sec: Some(2.),
+            });
+        }
+        let trials = vec![
+            AnalysisParams::default(),
+            AnalysisParams {
+                kernel_size: 10,
+                ..AnalysisParams::default()
+            },
+        ];
+
+        let results = run_trials(
+            &references,
+            &labels,
+            trials,
+            &MatchOptions::default(),
+            |_, _| {},
+        )
+        .unwrap();
+        assert_eq!(results.len(), 2);
+        for result in &results {
+            assert_eq!(result.top1_accuracy, 1.);
+            assert!(result.hashes_per_sec > 0. && result.db_bytes > 0);
+            assert!(result.postings_per_query > 0.);
+        }
+        // smaller kernels find more peaks, so store more
+        assert!(results[1].hashes_per_sec > results[0].hashes_per_sec);
+        assert!(suggest(&results).is_some());
+    }
+}
@@ -0,0 +1,77 @@
+//! Variable length encoding of integers, for compact files and postings
+
+/// Appends `value` 7 bits at a time, least significant first, with the top bit of
+/// each byte set when more follow
+pub fn write_varint(bytes: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+/// Reads a varint starting at `position`, advancing past it. Fails on truncated
+/// varints and those too big for 32 bits.
+pub fn read_varint(bytes: &[u8], position: &mut usize) -> Option<u32> {
+    let mut value = 0u32;
+    for shift in (0..35).step_by(7) {
+        let byte = *bytes.get(*position)?;
+        *position += 1;
+        let bits = (byte & 0x7f) as u32;
+        if bits.checked_shl(shift)? >> shift != bits {
+            return None; // bits past the 32nd
+        }
+        value |= bits << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_round_trip_in_as_few_bytes_as_needed() {
+        for (value, len) in [
+            (0, 1),
+            (1, 1),
+            (127, 1),
+            (128, 2),
+            (16_383, 2),
+            (16_384, 3),
+            (u32::MAX, 5),
+        ] {
+            let mut bytes = vec![0xaa]; // read from where the varint starts
+            write_varint(&mut bytes, value);
+            assert_eq!(bytes.len(), 1 + len, "{}", value);
+            let mut position = 1;
+            assert_eq!(read_varint(&bytes, &mut position), Some(value));
+            assert_eq!(position, bytes.len());
+        }
+    }
+
+    #[test]
+    fn truncated_varints_are_rejected() {
+        let mut bytes = vec![];
+        write_varint(&mut bytes, u32::MAX);
+        for len in 0..bytes.len() {
+            assert_eq!(read_varint(&bytes[..len], &mut 0), None, "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn varints_too_big_for_32_bits_are_rejected() {
+        assert_eq!(read_varint(&[0xff, 0xff, 0xff, 0xff, 0x10], &mut 0), None);
+        assert_eq!(
+            read_varint(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x00], &mut 0),
+            None
+        );
+        assert_eq!(
+            read_varint(&[0xff, 0xff, 0xff, 0xff, 0x0f], &mut 0),
+            Some(u32::MAX)
+        );
+    }
+}
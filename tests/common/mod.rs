@@ -0,0 +1,40 @@
+//! Audio shared by the integration tests
+
+use std::{f32::consts::PI, fs, path::Path};
+
+use atlas::audio_ops;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+pub const SAMPLE_RATE: u32 = 8000;
+
+/// Chords of three random tones, changing every fifth of a second, within the range of
+/// 16 bit samples
+pub fn tones(seconds: usize, seed: u64) -> Vec<f32> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let chord_len = SAMPLE_RATE as usize / 5;
+    let mut samples = vec![];
+    for _ in 0..seconds * 5 {
+        let frequencies: Vec<f32> = (0..3).map(|_| rng.gen_range(200.0..3500.0)).collect();
+        samples.extend((0..chord_len).map(|n| {
+            let t = n as f32 / SAMPLE_RATE as f32;
+            frequencies
+                .iter()
+                .map(|frequency| 8000. * (2. * PI * frequency * t).sin())
+                .sum::<f32>()
+        }));
+    }
+    samples
+}
+
+/// The samples from `start` to `end` seconds
+pub fn excerpt(samples: &[f32], start: usize, end: usize) -> &[f32] {
+    &samples[start * SAMPLE_RATE as usize..end * SAMPLE_RATE as usize]
+}
+
+/// The samples as a wav file written to `dir`
+#[allow(dead_code)] // not every test sends wav files
+pub fn wav_bytes(dir: &Path, name: &str, samples: &[f32]) -> Vec<u8> {
+    let path = dir.join(name);
+    audio_ops::write_wav_mono(&path, samples, SAMPLE_RATE).unwrap();
+    fs::read(path).unwrap()
+}
@@ -0,0 +1,86 @@
+//! Serves a catalogue on a Unix domain socket and talks to it as an integration would.
+#![cfg(unix)]
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    thread,
+};
+
+use atlas::{
+    audio_ops, daemon::Daemon, service::Service, storage::SqliteStorage, AnalysisParams,
+    Fingerprinter, Index, MatchOptions, Matcher,
+};
+use serde_json::{json, Value};
+
+mod common;
+use common::{excerpt, tones, SAMPLE_RATE};
+
+/// Sends a request line and reads the response line
+fn request(stream: &mut BufReader<UnixStream>, request: &str) -> Value {
+    writeln!(stream.get_mut(), "{}", request).unwrap();
+    let mut response = String::new();
+    stream.read_line(&mut response).unwrap();
+    serde_json::from_str(&response).unwrap()
+}
+
+#[test]
+fn serves_additions_matches_and_stats_on_a_socket() {
+    let dir = tempfile::tempdir().unwrap();
+    let database = dir.path().join("catalogue.db3");
+    let open = || Index::new(SqliteStorage::open(&database).unwrap());
+    let service = Service::new(
+        Fingerprinter::new(AnalysisParams::default()).unwrap(),
+        MatchOptions::default(),
+        100,
+        open(),
+        vec![Matcher::new(open()).unwrap(), Matcher::new(open()).unwrap()],
+    )
+    .unwrap();
+    let socket = dir.path().join("atlas.sock");
+    let daemon = Daemon::bind(&socket).unwrap();
+    let shutdown = daemon.shutdown_handle();
+
+    let track = tones(20, 1);
+    let track_path = dir.path().join("track.wav");
+    let clip_path = dir.path().join("clip.wav");
+    audio_ops::write_wav_mono(&track_path, &track, SAMPLE_RATE).unwrap();
+    audio_ops::write_wav_mono(&clip_path, excerpt(&track, 5, 12), SAMPLE_RATE).unwrap();
+
+    thread::scope(|scope| {
+        scope.spawn(|| daemon.run(&service, |err| eprintln!("{:#}", err)));
+        let mut stream = BufReader::new(UnixStream::connect(&socket).unwrap());
+
+        let added = json!({"id": 1, "command": "add_file", "path": track_path});
+        let added = request(&mut stream, &added.to_string());
+        assert_eq!(added["id"], 1, "{}", added);
+        assert_eq!(added["result"]["title"], "track");
+        let track_id = added["result"]["track_id"].clone();
+
+        let matched = json!({"id": "clip", "command": "match_file", "path": clip_path});
+        let matched = request(&mut stream, &matched.to_string());
+        assert_eq!(matched["id"], "clip", "{}", matched);
+        assert_eq!(matched["result"]["matched"], true);
+        assert_eq!(matched["result"]["results"][0]["track_id"], track_id);
+        let offset = matched["result"]["results"][0]["offset_sec"]
+            .as_f64()
+            .unwrap();
+        assert!((offset - 5.).abs() <= 0.2, "offset {}", offset);
+
+        let stats = request(&mut stream, r#"{"id":3,"command":"stats"}"#);
+        assert_eq!(stats["id"], 3, "{}", stats);
+        assert_eq!(stats["result"]["tracks"], 1);
+
+        // the id is echoed even when the command can't be read
+        let invalid = request(&mut stream, r#"{"id":4,"command":"dance"}"#);
+        assert_eq!(invalid["id"], 4, "{}", invalid);
+        assert!(invalid["error"].is_string());
+        assert!(invalid.get("result").is_none());
+        let invalid = request(&mut stream, "not json");
+        assert_eq!(invalid["id"], Value::Null);
+        assert!(invalid["error"].is_string());
+
+        drop(stream);
+        shutdown.shutdown();
+    });
+}
@@ -0,0 +1,85 @@
+//! Builds a C program against include/atlas.h and the shared library, and runs it.
+//! The compiler is `cc`, or that named by `CC`.
+#![cfg(unix)]
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use atlas::{eval::StageTimings, storage::SqliteStorage, AnalysisParams, Fingerprinter, Index};
+
+mod common;
+use common::{excerpt, tones, SAMPLE_RATE};
+
+/// Where cargo put the shared library built for this test run, the `deps` directory
+/// beside the test. The one above it is only updated by `cargo build`, and comes first
+/// in the library path cargo runs tests with, so the program is run without it.
+fn library_dir() -> PathBuf {
+    let test = env::current_exe().unwrap();
+    test.parent().unwrap().to_path_buf()
+}
+
+#[test]
+fn c_programs_fingerprint_and_match_through_the_header() {
+    let dir = tempfile::tempdir().unwrap();
+    let database = dir.path().join("catalogue.db3");
+    let fingerprinter = Fingerprinter::new(AnalysisParams::default()).unwrap();
+    let mut index = Index::new(SqliteStorage::open(&database).unwrap());
+    index.record_params(fingerprinter.params()).unwrap();
+    let track = tones(14, 1);
+    for (title, samples) in [("a track", &track), ("another", &tones(14, 2))] {
+        let analysis = fingerprinter
+            .analyse_samples(samples, SAMPLE_RATE, &mut StageTimings::default())
+            .unwrap();
+        index.add_track(title, None, &analysis.hashes).unwrap();
+    }
+    drop(index);
+    let clip = dir.path().join("clip.raw");
+    let pcm: Vec<u8> = excerpt(&track, 5, 12)
+        .iter()
+        .flat_map(|&sample| (sample as i16).to_ne_bytes())
+        .collect();
+    fs::write(&clip, pcm).unwrap();
+
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let program = dir.path().join("smoke");
+    let compiler = env::var("CC").unwrap_or_else(|_| "cc".to_string());
+    let compiled = Command::new(&compiler)
+        .args(["-std=c99", "-Wall", "-Wextra", "-Werror", "-I"])
+        .arg(root.join("include"))
+        .arg(root.join("tests/ffi/smoke.c"))
+        .arg("-L")
+        .arg(library_dir())
+        .arg(format!("-Wl,-rpath,{}", library_dir().display()))
+        .args(["-latlas", "-o"])
+        .arg(&program)
+        .output()
+        .unwrap_or_else(|err| panic!("Could not run {}: {}", compiler, err));
+    assert!(
+        compiled.status.success(),
+        "{}",
+        String::from_utf8_lossy(&compiled.stderr)
+    );
+
+    let run = Command::new(&program)
+        .env("LD_LIBRARY_PATH", library_dir())
+        .arg(&database)
+        .arg(&clip)
+        .arg(SAMPLE_RATE.to_string())
+        .output()
+        .unwrap();
+    assert!(
+        run.status.success(),
+        "{}",
+        String::from_utf8_lossy(&run.stderr)
+    );
+    let output = String::from_utf8(run.stdout).unwrap();
+    let best: Vec<&str> = output.trim_end().split('\t').collect();
+    assert_eq!(best.len(), 3, "{}", output);
+    assert_eq!(best[0], "a track");
+    let offset: f32 = best[1].parse().unwrap();
+    assert!((offset - 5.).abs() <= 0.2, "offset {}", offset);
+    assert_eq!(best[2], "1");
+}
@@ -0,0 +1,102 @@
+/*
+ * Fingerprints raw 16 bit mono audio through the C interface in include/atlas.h and
+ * matches it against a database, printing the best result as
+ * "<title>\t<offset>\t<accepted>". Built and run by tests/ffi.rs.
+ *
+ * usage: smoke <database> <audio.raw> <sample rate>
+ */
+
+#include <stdio.h>
+#include <stdlib.h>
+
+#include "atlas.h"
+
+#define CHUNK 4001 /* frames pushed at a time, not a multiple of any window */
+
+static int fail(const char *what, AtlasStatus status) {
+    const char *error = atlas_last_error();
+    fprintf(stderr, "%s failed with status %d: %s\n", what, (int)status,
+            error ? error : "(no error)");
+    return 1;
+}
+
+int main(int argc, char **argv) {
+    if (argc != 4) {
+        fprintf(stderr, "usage: smoke <database> <audio.raw> <sample rate>\n");
+        return 2;
+    }
+
+    AtlasAnalysisParams params;
+    AtlasStatus status = atlas_analysis_params_default(&params);
+    if (status != ATLAS_OK) return fail("atlas_analysis_params_default", status);
+
+    AtlasFingerprinter *fingerprinter = NULL;
+    status = atlas_fingerprinter_new(&params, (uint32_t)atoi(argv[3]), 1, &fingerprinter);
+    if (status != ATLAS_OK) return fail("atlas_fingerprinter_new", status);
+
+    FILE *audio = fopen(argv[2], "rb");
+    if (!audio) {
+        perror(argv[2]);
+        return 1;
+    }
+    int16_t samples[CHUNK];
+    size_t frames;
+    while ((frames = fread(samples, sizeof samples[0], CHUNK, audio)) > 0) {
+        status = atlas_fingerprinter_push_i16(fingerprinter, samples, frames);
+        if (status != ATLAS_OK) return fail("atlas_fingerprinter_push_i16", status);
+    }
+    fclose(audio);
+    status = atlas_fingerprinter_finish(fingerprinter);
+    if (status != ATLAS_OK) return fail("atlas_fingerprinter_finish", status);
+    if (atlas_fingerprinter_push_i16(fingerprinter, samples, 1) == ATLAS_OK) {
+        fprintf(stderr, "audio was accepted after the end\n");
+        return 1;
+    }
+
+    AtlasHash *hashes = NULL;
+    size_t len = 0;
+    status = atlas_fingerprinter_hashes(fingerprinter, &hashes, &len);
+    if (status != ATLAS_OK) return fail("atlas_fingerprinter_hashes", status);
+
+    /* misuse is reported rather than crashing */
+    AtlasMatcher *matcher = NULL;
+    status = atlas_matcher_open(NULL, ATLAS_STORAGE_SQLITE, &params, NULL, &matcher);
+    if (status != ATLAS_NULL_ARGUMENT || !atlas_last_error()) {
+        fprintf(stderr, "a null path gave status %d\n", (int)status);
+        return 1;
+    }
+    char missing[4096];
+    snprintf(missing, sizeof missing, "%s.missing", argv[1]);
+    status = atlas_matcher_open(missing, ATLAS_STORAGE_SQLITE, &params, NULL, &matcher);
+    FILE *created = fopen(missing, "rb");
+    if (status != ATLAS_INVALID_ARGUMENT || !atlas_last_error() || created) {
+        fprintf(stderr, "a missing database gave status %d\n", (int)status);
+        return 1;
+    }
+    AtlasAnalysisParams other = params;
+    other.window_length *= 2;
+    status = atlas_matcher_open(argv[1], ATLAS_STORAGE_SQLITE, &other, NULL, &matcher);
+    if (status != ATLAS_INVALID_ARGUMENT) {
+        fprintf(stderr, "other analysis parameters gave status %d\n", (int)status);
+        return 1;
+    }
+
+    status = atlas_matcher_open(argv[1], ATLAS_STORAGE_SQLITE, &params, NULL, &matcher);
+    if (status != ATLAS_OK) return fail("atlas_matcher_open", status);
+    AtlasMatchResult *results = NULL;
+    size_t count = 0;
+    status = atlas_matcher_match(matcher, hashes, len, &results, &count);
+    if (status != ATLAS_OK) return fail("atlas_matcher_match", status);
+
+    if (count > 0) {
+        printf("%s\t%.2f\t%d\n", results[0].title, results[0].offset_sec,
+               (int)results[0].accepted);
+    }
+
+    atlas_match_results_free(results, count);
+    atlas_matcher_free(matcher);
+    atlas_hashes_free(hashes, len);
+    atlas_fingerprinter_free(fingerprinter);
+    atlas_fingerprinter_free(NULL);
+    return 0;
+}
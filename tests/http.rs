@@ -0,0 +1,102 @@
+//! Serves a catalogue over HTTP on a free port and talks to it as a client would.
+
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+    thread,
+};
+
+use atlas::{
+    http::HttpServer, service::Service, storage::SqliteStorage, AnalysisParams, Fingerprinter,
+    Index, MatchOptions, Matcher,
+};
+use serde_json::Value;
+
+mod common;
+use common::{excerpt, tones, wav_bytes};
+
+/// Sends a request and returns the status and JSON body of the response
+fn request(address: SocketAddr, method: &str, target: &str, body: &[u8]) -> (u16, Value) {
+    let mut stream = TcpStream::connect(address).unwrap();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        method,
+        target,
+        body.len()
+    )
+    .unwrap();
+    stream.write_all(body).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    (status, serde_json::from_str(body).unwrap())
+}
+
+#[test]
+fn serves_matches_additions_removals_and_stats() {
+    let dir = tempfile::tempdir().unwrap();
+    let database = dir.path().join("catalogue.db3");
+    let open = || Index::new(SqliteStorage::open(&database).unwrap());
+    let service = Service::new(
+        Fingerprinter::new(AnalysisParams::default()).unwrap(),
+        MatchOptions::default(),
+        100,
+        open(),
+        vec![Matcher::new(open()).unwrap(), Matcher::new(open()).unwrap()],
+    )
+    .unwrap();
+    let server = HttpServer::bind("127.0.0.1:0", 2).unwrap();
+    let address = server.local_addr().unwrap();
+    let shutdown = server.shutdown_handle();
+
+    let track = tones(20, 1);
+    let track_wav = wav_bytes(dir.path(), "track.wav", &track);
+    let clip_wav = wav_bytes(dir.path(), "clip.wav", excerpt(&track, 5, 12));
+    let other_wav = wav_bytes(dir.path(), "other.wav", &tones(8, 2));
+
+    thread::scope(|scope| {
+        scope.spawn(|| server.run(&service, |err| eprintln!("{:#}", err)));
+
+        let (status, added) = request(address, "POST", "/tracks?title=a%20track", &track_wav);
+        assert_eq!(status, 200, "{}", added);
+        assert_eq!(added["title"], "a track");
+        let track_id = added["track_id"].clone();
+
+        let (status, report) = request(address, "POST", "/match?query=clip", &clip_wav);
+        assert_eq!(status, 200, "{}", report);
+        assert_eq!(report["query"], "clip");
+        assert_eq!(report["matched"], true);
+        assert_eq!(report["results"][0]["track_id"], track_id);
+        let offset = report["results"][0]["offset_sec"].as_f64().unwrap();
+        assert!((offset - 5.).abs() <= 0.2, "offset {}", offset);
+
+        let (status, report) = request(address, "POST", "/match", &other_wav);
+        assert_eq!(status, 200, "{}", report);
+        assert_eq!(report["matched"], false);
+
+        let (status, stats) = request(address, "GET", "/stats", b"");
+        assert_eq!(status, 200, "{}", stats);
+        assert_eq!(stats["tracks"], 1);
+
+        let (status, error) = request(address, "POST", "/match", b"not audio");
+        assert_eq!(status, 400);
+        assert!(error["error"].is_string());
+
+        let (status, removed) = request(address, "DELETE", "/tracks?title=a%20track", b"");
+        assert_eq!(status, 200, "{}", removed);
+        assert_eq!(removed["removed"], true);
+        let (status, _) = request(address, "DELETE", "/tracks?title=a%20track", b"");
+        assert_eq!(status, 404);
+
+        // measured again now the tracks have changed
+        let (_, stats) = request(address, "GET", "/stats", b"");
+        assert_eq!(stats["tracks"], 0);
+        let (_, report) = request(address, "POST", "/match?query=clip", &clip_wav);
+        assert_eq!(report["matched"], false);
+
+        shutdown.shutdown();
+    });
+}